@@ -0,0 +1,395 @@
+extern crate rusoto_core;
+extern crate rusoto_sts;
+extern crate chrono;
+extern crate serde_json;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use self::chrono::{DateTime, Duration, Utc};
+use self::rusoto_core::{default_tls_client, AutoRefreshingProvider, AwsCredentials, ChainProvider, ContainerProvider,
+                        CredentialsError, DefaultCredentialsProvider, EnvironmentProvider, InstanceMetadataProvider,
+                        ProfileProvider, ProvideAwsCredentials, Region};
+use self::rusoto_sts::{AssumeRoleRequest, Sts, StsClient};
+
+use morocco::{MoroccoError, ErrorKind};
+use files::create_private_file;
+use aws::profile::AwsConfigFile;
+
+// Temporary credentials are refreshed when they have less than this long left
+const EXPIRY_MARGIN_SECONDS: i64 = 300;
+
+// Asks for the current code of the MFA device with the given serial number
+pub type MfaCodeCallback = Arc<Fn(&str) -> Result<String, MoroccoError> + Send + Sync>;
+// Told about problems that don't stop the command, such as failing to cache the credentials
+pub type WarningCallback = Arc<Fn(&str) + Send + Sync>;
+
+// How assuming a role reaches the user. The library never uses the terminal itself,
+// so without an MFA callback, roles that require MFA can't be assumed, and without a warning callback,
+// warnings are dropped.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    pub mfa_code: Option<MfaCodeCallback>,
+    pub warning: Option<WarningCallback>
+}
+
+// How to assume a role, taken from the command line options or from the selected profile in ~/.aws/config
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoleOptions {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+    pub mfa_serial: Option<String>,
+    // where the credentials used to assume the role come from
+    pub source: RoleSource,
+    pub duration_seconds: Option<i64>
+}
+
+// The source_profile or credential_source of a profile that assumes a role
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoleSource {
+    // the usual chain of providers for a profile
+    Profile(String),
+    // another role, assumed first, when the source_profile has a role_arn of its own
+    Role(Box<RoleOptions>),
+    // credential_source = Environment, Ec2InstanceMetadata or EcsContainer
+    Environment,
+    Ec2InstanceMetadata,
+    EcsContainer
+}
+
+impl RoleOptions {
+
+    // Options given on the command line take precedence over the profile's role_arn, external_id and mfa_serial.
+    // Returns None if there is no role to assume.
+    pub fn resolve(role_arn: Option<String>,
+                   external_id: Option<String>,
+                   mfa_serial: Option<String>,
+                   profile: &str,
+                   config_file: &AwsConfigFile) -> Result<Option<RoleOptions>, MoroccoError> {
+        RoleOptions::resolve_chain(role_arn, external_id, mfa_serial, profile, config_file, &mut vec![profile.to_string()])
+    }
+
+    // `chain` holds the profiles visited so far, to catch source_profiles that refer back to each other
+    fn resolve_chain(role_arn: Option<String>,
+                     external_id: Option<String>,
+                     mfa_serial: Option<String>,
+                     profile: &str,
+                     config_file: &AwsConfigFile,
+                     chain: &mut Vec<String>) -> Result<Option<RoleOptions>, MoroccoError> {
+        let from_profile = |key: &str| config_file.get(profile, key).map(|v| v.to_string());
+        let from_command_line = role_arn.is_some();
+        let role_arn = match role_arn.or(from_profile("role_arn")) {
+            Some(arn) => arn,
+            None => return Ok(None)
+        };
+        let source = match RoleSource::resolve(profile, config_file, chain)? {
+            Some(source) => source,
+            // a role given on the command line is assumed using the profile's own credentials
+            None if from_command_line => RoleSource::Profile(profile.to_string()),
+            // but a profile that only describes a role has no credentials of its own
+            None => return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("Profile {} has a role_arn but no source_profile or credential_source in ~/.aws/config.", profile)))
+        };
+        let duration_seconds = match from_profile("duration_seconds") {
+            Some(duration) => Some(duration.parse().map_err(|_| MoroccoError::new(
+                        ErrorKind::Config,
+                        format!("Invalid duration_seconds for profile {} in ~/.aws/config: {}", profile, duration)))?),
+            None => None
+        };
+        Ok(Some(RoleOptions {
+            role_arn: role_arn,
+            external_id: external_id.or(from_profile("external_id")),
+            mfa_serial: mfa_serial.or(from_profile("mfa_serial")),
+            source: source,
+            duration_seconds: duration_seconds
+        }))
+    }
+
+    // A file name that is unique to the role and the credentials used to assume it
+    fn cache_key(&self) -> String {
+        let source = match self.source {
+            RoleSource::Profile(ref profile) => profile.clone(),
+            RoleSource::Role(ref options) => options.cache_key(),
+            RoleSource::Environment => "Environment".to_string(),
+            RoleSource::Ec2InstanceMetadata => "Ec2InstanceMetadata".to_string(),
+            RoleSource::EcsContainer => "EcsContainer".to_string()
+        };
+        let key = format!("{}_{}_{}_{}",
+                          source,
+                          self.role_arn,
+                          self.external_id.as_ref().map(|s| s.as_str()).unwrap_or(""),
+                          self.mfa_serial.as_ref().map(|s| s.as_str()).unwrap_or(""));
+        key.chars().map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' }).collect()
+    }
+
+}
+
+impl RoleSource {
+
+    // Returns None if the profile has neither a source_profile nor a credential_source
+    fn resolve(profile: &str, config_file: &AwsConfigFile, chain: &mut Vec<String>) -> Result<Option<RoleSource>, MoroccoError> {
+        let invalid = |message: String| Err(MoroccoError::new(ErrorKind::Config, message));
+        match (config_file.get(profile, "source_profile"), config_file.get(profile, "credential_source")) {
+            (Some(_), Some(_)) => invalid(format!("Profile {} has both a source_profile and a credential_source in ~/.aws/config.", profile)),
+            // a profile that is its own source uses its static credentials, as the AWS CLI does
+            (Some(source), None) if source == profile || config_file.get(source, "role_arn").is_none() =>
+                Ok(Some(RoleSource::Profile(source.to_string()))),
+            (Some(source), None) => {
+                if chain.iter().any(|p| p == source) {
+                    return invalid(format!("The source_profile of {} leads back to profile {} in ~/.aws/config.", profile, source));
+                }
+                chain.push(source.to_string());
+                let options = RoleOptions::resolve_chain(None, None, None, source, config_file, chain)?
+                    .expect("the source profile has a role_arn");
+                Ok(Some(RoleSource::Role(Box::new(options))))
+            },
+            (None, Some("Environment")) => Ok(Some(RoleSource::Environment)),
+            (None, Some("Ec2InstanceMetadata")) => Ok(Some(RoleSource::Ec2InstanceMetadata)),
+            (None, Some("EcsContainer")) => Ok(Some(RoleSource::EcsContainer)),
+            (None, Some(other)) => invalid(format!(
+                    "Unknown credential_source {} for profile {} in ~/.aws/config. It must be Environment, Ec2InstanceMetadata or EcsContainer.",
+                    other, profile)),
+            (None, None) => Ok(None)
+        }
+    }
+
+    fn credentials(&self, region: Region, callbacks: &Callbacks) -> Result<Credentials, CredentialsError> {
+        match *self {
+            RoleSource::Profile(ref profile) => chain_provider(profile).map(Credentials::Chain),
+            RoleSource::Role(ref options) =>
+                Ok(Credentials::AssumedRole(Arc::new(AssumeRoleProvider::new((**options).clone(), region, callbacks.clone())))),
+            RoleSource::Environment => Ok(Credentials::Source(Box::new(EnvironmentProvider))),
+            RoleSource::Ec2InstanceMetadata =>
+                Ok(Credentials::Source(Box::new(AutoRefreshingProvider::with_refcell(InstanceMetadataProvider)?))),
+            RoleSource::EcsContainer =>
+                Ok(Credentials::Source(Box::new(AutoRefreshingProvider::with_refcell(ContainerProvider)?)))
+        }
+    }
+
+}
+
+// The usual chain of environment variables, ~/.aws/credentials and the instance profile, for the given profile
+pub fn chain_provider(profile: &str) -> Result<DefaultCredentialsProvider, CredentialsError> {
+    let mut profile_provider = ProfileProvider::new()?;
+    profile_provider.set_profile(profile);
+    let chain_provider = ChainProvider::with_profile_provider(profile_provider);
+    AutoRefreshingProvider::with_refcell(chain_provider)
+}
+
+// The credentials used by the DynamoDB and KMS clients: either the usual provider chain for a profile,
+// or temporary credentials for an assumed role, shared between the clients so the MFA code is only asked for once.
+// Roles are themselves assumed with the credentials of their source.
+pub enum Credentials {
+    Chain(DefaultCredentialsProvider),
+    AssumedRole(Arc<AssumeRoleProvider>),
+    Source(Box<ProvideAwsCredentials + Send>)
+}
+
+impl ProvideAwsCredentials for Credentials {
+
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        match *self {
+            Credentials::Chain(ref provider) => provider.credentials(),
+            Credentials::AssumedRole(ref provider) => provider.credentials(),
+            Credentials::Source(ref provider) => provider.credentials()
+        }
+    }
+
+}
+
+// Assumes a role using STS, asking for an MFA code through the callback if needed.
+// The temporary credentials are cached in ~/.morocco/cache until shortly before they expire,
+// so that running several commands in a row does not ask for a new MFA code every time.
+pub struct AssumeRoleProvider {
+    options: RoleOptions,
+    region: Region,
+    callbacks: Callbacks,
+    // locked while the role is assumed, so that clients on other threads wait rather than ask for a second MFA code
+    cached: Mutex<Option<AwsCredentials>>
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    expiration: String
+}
+
+impl AssumeRoleProvider {
+
+    pub fn new(options: RoleOptions, region: Region, callbacks: Callbacks) -> AssumeRoleProvider {
+        AssumeRoleProvider {
+            options: options,
+            region: region,
+            callbacks: callbacks,
+            cached: Mutex::new(None)
+        }
+    }
+
+    fn assume_role(&self) -> Result<CachedCredentials, CredentialsError> {
+        let token_code = match self.options.mfa_serial {
+            Some(ref serial) => {
+                let callback = self.callbacks.mfa_code.as_ref()
+                    .ok_or(CredentialsError::new(format!("Role {} requires an MFA code, but there is no way to ask for one.", self.options.role_arn)))?;
+                let code = callback(serial)
+                    .map_err(|e| CredentialsError::new(format!("Could not read the MFA code: {}", e.message)))?;
+                Some(code.trim().to_string())
+            },
+            None => None
+        };
+
+        let client = StsClient::new(default_tls_client().map_err(|e| CredentialsError::new(format!("{}", e)))?,
+                                    self.options.source.credentials(self.region, &self.callbacks)?,
+                                    self.region);
+        let request = AssumeRoleRequest {
+            role_arn: self.options.role_arn.clone(),
+            role_session_name: format!("morocco-{}", Utc::now().timestamp()),
+            external_id: self.options.external_id.clone(),
+            serial_number: self.options.mfa_serial.clone(),
+            token_code: token_code,
+            duration_seconds: self.options.duration_seconds,
+            ..Default::default()
+        };
+        let response = client.assume_role(&request)
+            .map_err(|e| CredentialsError::new(format!("Could not assume role {}: {}", self.options.role_arn, e)))?;
+        match response.credentials {
+            Some(creds) => Ok(CachedCredentials {
+                access_key_id: creds.access_key_id,
+                secret_access_key: creds.secret_access_key,
+                session_token: creds.session_token,
+                expiration: creds.expiration
+            }),
+            None => Err(CredentialsError::new(format!("STS returned no credentials for role {}", self.options.role_arn)))
+        }
+    }
+
+    fn cache_path(&self) -> Option<PathBuf> {
+        env::home_dir().map(|home| home.join(".morocco").join("cache").join(format!("{}.json", self.options.cache_key())))
+    }
+
+    // A missing, unreadable or expired cache entry is ignored
+    fn read_cache(&self) -> Option<AwsCredentials> {
+        let mut contents = String::new();
+        File::open(self.cache_path()?).ok()?.read_to_string(&mut contents).ok()?;
+        let cached: CachedCredentials = serde_json::from_str(&contents).ok()?;
+        to_aws_credentials(cached)
+    }
+
+    // Failing to write the cache only means that the role will be assumed again next time
+    fn write_cache(&self, credentials: &CachedCredentials) {
+        if let (Some(path), Ok(json)) = (self.cache_path(), serde_json::to_string(credentials)) {
+            let written = path.parent()
+                .map_or(Ok(()), |dir| fs::create_dir_all(dir).map_err(MoroccoError::from))
+                .and_then(|_| create_private_file(&path))
+                .and_then(|mut file| file.write_all(json.as_bytes()).map_err(MoroccoError::from));
+            if let (Err(e), Some(warning)) = (written, self.callbacks.warning.as_ref()) {
+                warning(&format!("Could not cache the role credentials in {}: {}", path.display(), e));
+            }
+        }
+    }
+
+}
+
+impl ProvideAwsCredentials for AssumeRoleProvider {
+
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut cached = self.cached.lock().map_err(|_| CredentialsError::new("Another thread failed while assuming the role."))?;
+        if let Some(ref credentials) = *cached {
+            if is_fresh(credentials.expires_at()) {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = match self.read_cache() {
+            Some(credentials) => credentials,
+            None => {
+                let fresh = self.assume_role()?;
+                self.write_cache(&fresh);
+                to_aws_credentials(fresh)
+                    .ok_or(CredentialsError::new(format!("STS returned invalid credentials for role {}", self.options.role_arn)))?
+            }
+        };
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+
+}
+
+fn is_fresh(expires_at: &DateTime<Utc>) -> bool {
+    *expires_at > Utc::now() + Duration::seconds(EXPIRY_MARGIN_SECONDS)
+}
+
+// Returns None if the credentials have expired or are about to
+fn to_aws_credentials(cached: CachedCredentials) -> Option<AwsCredentials> {
+    let expires_at = DateTime::parse_from_rfc3339(&cached.expiration).ok()?.with_timezone(&Utc);
+    if !is_fresh(&expires_at) {
+        return None;
+    }
+    Some(AwsCredentials::new(cached.access_key_id, cached.secret_access_key, Some(cached.session_token), expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_options_from_profile() {
+        let config_file = AwsConfigFile::parse(
+            "[profile base]\nregion = eu-west-1\n[profile prod]\nrole_arn = arn:aws:iam::123:role/secrets\nsource_profile = base\nmfa_serial = arn:aws:iam::456:mfa/chris\n[profile broken]\nrole_arn = arn:aws:iam::123:role/secrets\n");
+
+        let prod = RoleOptions::resolve(None, Some("ext".to_string()), None, "prod", &config_file).ok().unwrap().unwrap();
+        assert_eq!("arn:aws:iam::123:role/secrets", prod.role_arn);
+        assert_eq!(RoleSource::Profile("base".to_string()), prod.source);
+        assert_eq!(Some("ext".to_string()), prod.external_id);
+        assert_eq!(Some("arn:aws:iam::456:mfa/chris".to_string()), prod.mfa_serial);
+
+        // a role given on the command line is assumed using the profile's own credentials
+        let flag = RoleOptions::resolve(Some("arn:aws:iam::789:role/x".to_string()), None, None, "base", &config_file).ok().unwrap().unwrap();
+        assert_eq!(RoleSource::Profile("base".to_string()), flag.source);
+
+        assert_eq!(None, RoleOptions::resolve(None, None, None, "base", &config_file).ok().unwrap());
+        assert_eq!(ErrorKind::Config, RoleOptions::resolve(None, None, None, "broken", &config_file).err().unwrap().kind);
+        // unless the role comes from the command line, which takes precedence over the profile's
+        let flag = RoleOptions::resolve(Some("arn:aws:iam::789:role/x".to_string()), None, None, "broken", &config_file).ok().unwrap().unwrap();
+        assert_eq!("arn:aws:iam::789:role/x", flag.role_arn);
+        assert_eq!(RoleSource::Profile("broken".to_string()), flag.source);
+    }
+
+    #[test]
+    fn chained_roles_and_credential_sources() {
+        let config_file = AwsConfigFile::parse(
+            "[profile base]\nregion = eu-west-1\n\
+             [profile hub]\nrole_arn = arn:aws:iam::1:role/hub\nsource_profile = base\nmfa_serial = arn:aws:iam::1:mfa/chris\n\
+             [profile spoke]\nrole_arn = arn:aws:iam::2:role/secrets\nsource_profile = hub\n\
+             [profile ec2]\nrole_arn = arn:aws:iam::3:role/secrets\ncredential_source = Ec2InstanceMetadata\n\
+             [profile both]\nrole_arn = arn:aws:iam::3:role/secrets\ncredential_source = Environment\nsource_profile = base\n\
+             [profile typo]\nrole_arn = arn:aws:iam::3:role/secrets\ncredential_source = Ec2\n\
+             [profile self]\nrole_arn = arn:aws:iam::4:role/secrets\nsource_profile = self\n\
+             [profile ping]\nrole_arn = arn:aws:iam::5:role/ping\nsource_profile = pong\n\
+             [profile pong]\nrole_arn = arn:aws:iam::5:role/pong\nsource_profile = ping\n");
+        let resolve = |profile: &str| RoleOptions::resolve(None, None, None, profile, &config_file);
+
+        // the hub role is assumed first, with its own MFA device, and its credentials are used to assume the spoke role
+        let spoke = resolve("spoke").ok().unwrap().unwrap();
+        let hub = RoleOptions {
+            role_arn: "arn:aws:iam::1:role/hub".to_string(),
+            external_id: None,
+            mfa_serial: Some("arn:aws:iam::1:mfa/chris".to_string()),
+            source: RoleSource::Profile("base".to_string()),
+            duration_seconds: None
+        };
+        assert_eq!(RoleSource::Role(Box::new(hub)), spoke.source);
+        assert_eq!(None, spoke.mfa_serial);
+        assert_ne!(resolve("hub").ok().unwrap().unwrap().cache_key(), spoke.cache_key());
+
+        assert_eq!(RoleSource::Ec2InstanceMetadata, resolve("ec2").ok().unwrap().unwrap().source);
+        assert_eq!(RoleSource::Profile("self".to_string()), resolve("self").ok().unwrap().unwrap().source);
+        assert_eq!(ErrorKind::Config, resolve("both").err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, resolve("typo").err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, resolve("ping").err().unwrap().kind);
+    }
+}
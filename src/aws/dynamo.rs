@@ -1,20 +1,24 @@
 extern crate rusoto_dynamodb;
 extern crate base64;
 
-use morocco::{MoroccoError, PutResult, DeletionResult};
+use std::collections::{BTreeMap, HashMap};
+
+use morocco::{MoroccoError, ErrorKind, PutResult, DeletionResult, VersionInfo, Metadata, Description};
 use aws::Item;
+use aws::errors::aws_error;
+use encryption::Cipher;
 
 use self::rusoto_dynamodb::*;
 use self::base64::{encode, decode};
 
 pub struct DynamoOps {
     table_name: String,
-    dynamo_client: Box<DynamoDb>
+    dynamo_client: Box<DynamoDb + Send>
 }
 
 impl DynamoOps {
 
-    pub fn new(table_name: String, dynamo_client: Box<DynamoDb>) -> DynamoOps {
+    pub fn new(table_name: String, dynamo_client: Box<DynamoDb + Send>) -> DynamoOps {
         DynamoOps {
             table_name: table_name,
             dynamo_client: dynamo_client
@@ -22,50 +26,220 @@ impl DynamoOps {
     }
 
     pub fn list_ids(&self) -> Result<Vec<String>, MoroccoError> {
-        let scan_input = ScanInput {
-            table_name: self.table_name.clone(),
-            ..Default::default()
+        let mut ids = Vec::new();
+        self.scan_ids(None, &mut |id| ids.push(id))?;
+        Ok(ids)
+    }
+
+    // Scan the whole table, following pagination, and pass each secret ID to the callback
+    // as soon as its page has been read. Stops early once `limit` IDs have been found.
+    pub fn scan_ids(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        let mut found = 0;
+        let mut previous_id: Option<String> = None;
+        let mut exclusive_start_key = None;
+        loop {
+            let scan_input = ScanInput {
+                table_name: self.table_name.clone(),
+                // only fetch the IDs, not the ciphertexts
+                projection_expression: Some("id".to_string()),
+                exclusive_start_key: exclusive_start_key,
+                ..Default::default()
+            };
+            let output = self.dynamo_client.scan(&scan_input).map_err(aws_error)?;
+            let ids = output.items.unwrap_or(Vec::new()).into_iter()
+                .flat_map(|item| item.get("id").and_then(|value| value.s.clone()));
+            for id in ids {
+                if limit.map_or(false, |l| found >= l) {
+                    return Ok(());
+                }
+                // all versions of a secret share a partition, so they are scanned consecutively
+                if previous_id.as_ref() != Some(&id) {
+                    found += 1;
+                    previous_id = Some(id.clone());
+                    callback(id);
+                }
+            }
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => return Ok(())
+            }
+        }
+    }
+
+    // Get the latest version of the secret with the given ID, along with its metadata
+    pub fn get_latest_item(&self, id: String) -> Result<(Item, Metadata), MoroccoError> {
+        let query_input = QueryInput {
+            scan_index_forward: Some(false),
+            limit: Some(1),
+            consistent_read: Some(true),
+            ..self.query_by_id(id)
         };
-        match self.dynamo_client.scan(&scan_input) {
+        match self.dynamo_client.query(&query_input) {
             Ok(output) => {
-                let items = output.items.unwrap_or(Vec::new());
-                let ids = items.iter()
-                    .flat_map(|item| item.get("id"))
-                    .flat_map(|value| value.s.clone())
-                    .collect();
-                Ok(ids)
+                match output.items.and_then(|items| items.into_iter().next()) {
+                    Some(attr_map) => attribute_map_to_item_and_metadata(&attr_map),
+                    None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+                }
+            }
+            Err(err) => Err(aws_error(err))
+        }
+    }
+
+    // Scan the whole table, or just the secrets whose IDs start with the prefix,
+    // and return the latest version of each secret keyed by ID
+    pub fn scan_latest_items(&self, prefix: Option<String>) -> Result<BTreeMap<String, Item>, MoroccoError> {
+        let mut latest: BTreeMap<String, (u64, Item)> = BTreeMap::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let mut scan_input = ScanInput {
+                table_name: self.table_name.clone(),
+                consistent_read: Some(true),
+                exclusive_start_key: exclusive_start_key,
+                ..Default::default()
+            };
+            if let Some(ref p) = prefix {
+                let values = [
+                    (":prefix".to_string(), AttributeValue { s: Some(p.clone()), ..Default::default() })
+                ].iter().cloned().collect::<ExpressionAttributeValueMap>();
+                scan_input.filter_expression = Some("begins_with(id, :prefix)".to_string());
+                scan_input.expression_attribute_values = Some(values);
+            }
+            let output = self.dynamo_client.scan(&scan_input).map_err(aws_error)?;
+            for attr_map in output.items.unwrap_or(Vec::new()) {
+                let id = match attr_map.get("id").and_then(|x| x.s.clone()) {
+                    Some(id) => id,
+                    None => continue
+                };
+                let version = attribute_map_to_version(&attr_map)?;
+                if latest.get(&id).map_or(true, |&(v, _)| version > v) {
+                    latest.insert(id, (version, attribute_map_to_item(&attr_map)?));
+                }
+            }
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => break
             }
-            Err(err) => Err(MoroccoError::from(err))
         }
+        Ok(latest.into_iter().map(|(id, (_, item))| (id, item)).collect())
     }
 
-    pub fn get_item(&self, id: String) -> Result<Item, MoroccoError> {
-        let key = [
-            ("id".to_string(), AttributeValue { s: Some(id), ..Default::default() })
-        ].iter().cloned().collect::<Key>();
+    pub fn get_item(&self, id: String, version: u64) -> Result<(Item, Metadata), MoroccoError> {
         let get_item_input = GetItemInput {
-            key: key,
+            key: item_key(id, version),
             table_name: self.table_name.clone(),
+            consistent_read: Some(true),
             ..Default::default()
         };
         match self.dynamo_client.get_item(&get_item_input) {
             Ok(output) => {
                 match output.item {
-                    Some(attr_map) => attribute_map_to_item(&attr_map),
-                    None => Err(MoroccoError { message: "No secret found with that ID.".to_string() })
+                    Some(attr_map) => attribute_map_to_item_and_metadata(&attr_map),
+                    None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID and version.".to_string()))
+                }
+            }
+            Err(err) => Err(aws_error(err))
+        }
+    }
+
+    // List all versions of the secret with the given ID, oldest first
+    pub fn list_versions(&self, id: String) -> Result<Vec<VersionInfo>, MoroccoError> {
+        let mut versions = Vec::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let query_input = QueryInput {
+                projection_expression: Some(VERSION_INFO_PROJECTION.to_string()),
+                expression_attribute_names: Some(version_info_attribute_names()),
+                exclusive_start_key: exclusive_start_key,
+                consistent_read: Some(true),
+                ..self.query_by_id(id.clone())
+            };
+            let output = self.dynamo_client.query(&query_input).map_err(aws_error)?;
+            for item in output.items.unwrap_or(Vec::new()) {
+                versions.push(attribute_map_to_version_info(&item)?);
+            }
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => return Ok(versions)
+            }
+        }
+    }
+
+    // The latest version of the secret with the given ID, if there is one
+    pub fn latest_version(&self, id: String) -> Result<Option<VersionInfo>, MoroccoError> {
+        let query_input = QueryInput {
+            projection_expression: Some(VERSION_INFO_PROJECTION.to_string()),
+            expression_attribute_names: Some(version_info_attribute_names()),
+            scan_index_forward: Some(false),
+            limit: Some(1),
+            consistent_read: Some(true),
+            ..self.query_by_id(id)
+        };
+        let output = self.dynamo_client.query(&query_input).map_err(aws_error)?;
+        match output.items.and_then(|items| items.into_iter().next()) {
+            Some(attr_map) => Ok(Some(attribute_map_to_version_info(&attr_map)?)),
+            None => Ok(None)
+        }
+    }
+
+    // Scan the whole table without fetching any ciphertexts, and summarise every secret
+    pub fn scan_descriptions(&self) -> Result<Vec<Description>, MoroccoError> {
+        let mut versions: BTreeMap<String, Vec<VersionInfo>> = BTreeMap::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let scan_input = ScanInput {
+                table_name: self.table_name.clone(),
+                projection_expression: Some(format!("id, {}", VERSION_INFO_PROJECTION)),
+                expression_attribute_names: Some(version_info_attribute_names()),
+                consistent_read: Some(true),
+                exclusive_start_key: exclusive_start_key,
+                ..Default::default()
+            };
+            let output = self.dynamo_client.scan(&scan_input).map_err(aws_error)?;
+            for attr_map in output.items.unwrap_or(Vec::new()) {
+                if let Some(id) = attr_map.get("id").and_then(|x| x.s.clone()) {
+                    versions.entry(id).or_insert(Vec::new()).push(attribute_map_to_version_info(&attr_map)?);
                 }
             }
-            Err(err) => Err(MoroccoError::from(err))
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => break
+            }
         }
+        Ok(versions.into_iter()
+           .flat_map(|(id, mut versions)| {
+               versions.sort_by_key(|v| v.version);
+               Description::from_versions(id, versions)
+           })
+           .collect())
+    }
+
+    // Store the given version of a secret. Versions are immutable once written,
+    // so this will not overwrite an existing item with the same ID and version.
+    // The creation time is passed in so that replicas of a version in other regions can share it.
+    pub fn put_item(&self, id: String, version: u64, item: Item, metadata: &Metadata, created_at: &str) -> Result<PutResult, MoroccoError> {
+        self.write_item(id, version, item, metadata, created_at, Some("attribute_not_exists(id)".to_string()))
+    }
+
+    // Store the given version of a secret, overwriting whatever a replica region had under the same version.
+    // Only syncing regions does this, to replace versions that differ from the main region's.
+    pub fn replace_item(&self, id: String, version: u64, item: Item, metadata: &Metadata, created_at: &str) -> Result<(), MoroccoError> {
+        self.write_item(id, version, item, metadata, created_at, None).map(|_| ())
     }
 
-    pub fn put_item(&self, id: String, item: Item, overwrite: bool) -> Result<PutResult, MoroccoError> {
+    fn write_item(&self, id: String, version: u64, item: Item, metadata: &Metadata, created_at: &str,
+                  condition_expression: Option<String>) -> Result<PutResult, MoroccoError> {
         // store as base64 string instead of binary to work around
         // https://github.com/rusoto/rusoto/issues/658
-        let attributes = [
+        let mut attributes = [
             ("id".to_string(), 
              AttributeValue { s: Some(id), ..Default::default() }),
 
+            ("version".to_string(), 
+             AttributeValue { n: Some(version.to_string()), ..Default::default() }),
+
+            ("created_at".to_string(), 
+             AttributeValue { s: Some(created_at.to_string()), ..Default::default() }),
+
             ("encrypted_data_key".to_string(), 
              AttributeValue { s: Some(encode(&item.encrypted_data_key)), .. Default::default() }),
 
@@ -73,41 +247,120 @@ impl DynamoOps {
              AttributeValue { s: Some(encode(&item.encrypted_data)), .. Default::default() }),
 
             ("iv".to_string(), 
-             AttributeValue { s: Some(encode(&item.iv)), .. Default::default() })
+             AttributeValue { s: Some(encode(&item.iv)), .. Default::default() }),
+
+            ("cipher".to_string(), 
+             AttributeValue { s: Some(item.cipher.name().to_string()), .. Default::default() }),
+
+            ("context_bound".to_string(), 
+             AttributeValue { bool: Some(item.context_bound), .. Default::default() })
         ].iter().cloned().collect::<PutItemInputAttributeMap>();
 
-        let condition_expr =
-            if overwrite { None } else { Some("attribute_not_exists(id)".to_string()) };
+        if let Some(ref hmac) = item.hmac {
+            attributes.insert("hmac".to_string(),
+                              AttributeValue { s: Some(encode(hmac)), .. Default::default() });
+        }
+        // DynamoDB rejects empty strings, so an empty description or tag is left out
+        if let Some(description) = metadata.description.as_ref().filter(|d| !d.is_empty()) {
+            attributes.insert("description".to_string(),
+                              AttributeValue { s: Some(description.clone()), .. Default::default() });
+        }
+        let tags = metadata.tags.iter()
+            .filter(|&(_, value)| !value.is_empty())
+            .map(|(key, value)| (key.clone(), AttributeValue { s: Some(value.clone()), .. Default::default() }))
+            .collect::<HashMap<String, AttributeValue>>();
+        if !tags.is_empty() {
+            attributes.insert("tags".to_string(),
+                              AttributeValue { m: Some(tags), .. Default::default() });
+        }
+        if let Some(ref author) = metadata.author {
+            attributes.insert("author".to_string(),
+                              AttributeValue { s: Some(author.clone()), .. Default::default() });
+        }
+        if let Some(ref expires_at) = metadata.expires_at {
+            attributes.insert("expires_at".to_string(),
+                              AttributeValue { s: Some(expires_at.clone()), .. Default::default() });
+        }
 
         let put_item_input = PutItemInput {
             table_name: self.table_name.clone(),
             item: attributes,
-            condition_expression: condition_expr,
+            condition_expression: condition_expression,
             ..Default::default()
         };
 
         match self.dynamo_client.put_item(&put_item_input) {
             Ok(_) => Ok(PutResult::Stored),
             Err(PutItemError::ConditionalCheckFailed(_)) => Ok(PutResult::DidNotOverwrite),
-            Err(err) => Err(MoroccoError::from(err))
+            Err(err) => Err(aws_error(err))
         }
     }
 
+    // Replace the encrypted data of an existing version, as long as its data key has not changed since it was read.
+    // All the other attributes, such as the metadata, are left as they are.
+    pub fn replace_encrypted_data(&self, id: String, version: u64, old_encrypted_data_key: &[u8], item: Item) -> Result<PutResult, MoroccoError> {
+        let names = [
+            ("#key", "encrypted_data_key"),
+            ("#data", "encrypted_data"),
+            ("#iv", "iv"),
+            ("#cipher", "cipher"),
+            ("#hmac", "hmac"),
+            ("#context_bound", "context_bound")
+        ].iter().map(|&(alias, name)| (alias.to_string(), name.to_string())).collect::<ExpressionAttributeNameMap>();
+        let mut values = [
+            (":old_key".to_string(), AttributeValue { s: Some(encode(old_encrypted_data_key)), ..Default::default() }),
+            (":key".to_string(), AttributeValue { s: Some(encode(&item.encrypted_data_key)), ..Default::default() }),
+            (":data".to_string(), AttributeValue { s: Some(encode(&item.encrypted_data)), ..Default::default() }),
+            (":iv".to_string(), AttributeValue { s: Some(encode(&item.iv)), ..Default::default() }),
+            (":cipher".to_string(), AttributeValue { s: Some(item.cipher.name().to_string()), ..Default::default() }),
+            (":context_bound".to_string(), AttributeValue { bool: Some(item.context_bound), ..Default::default() })
+        ].iter().cloned().collect::<ExpressionAttributeValueMap>();
+        let mut update_expression = "SET #key = :key, #data = :data, #iv = :iv, #cipher = :cipher, #context_bound = :context_bound".to_string();
+        match item.hmac {
+            Some(ref hmac) => {
+                values.insert(":hmac".to_string(), AttributeValue { s: Some(encode(hmac)), ..Default::default() });
+                update_expression.push_str(", #hmac = :hmac");
+            },
+            None => update_expression.push_str(" REMOVE #hmac")
+        }
+
+        let update_item_input = UpdateItemInput {
+            table_name: self.table_name.clone(),
+            key: item_key(id, version),
+            update_expression: Some(update_expression),
+            condition_expression: Some("#key = :old_key".to_string()),
+            expression_attribute_names: Some(names),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        };
+        match self.dynamo_client.update_item(&update_item_input) {
+            Ok(_) => Ok(PutResult::Stored),
+            Err(UpdateItemError::ConditionalCheckFailed(_)) => Ok(PutResult::DidNotOverwrite),
+            Err(err) => Err(aws_error(err))
+        }
+    }
+
+    // Delete all versions of the secret with the given ID
     pub fn delete_item(&self, id: String) -> Result<DeletionResult, MoroccoError> {
-        let key = [
-            ("id".to_string(), AttributeValue { s: Some(id), ..Default::default() })
-        ].iter().cloned().collect::<Key>();
+        let versions = self.list_versions(id.clone())?;
+        if versions.is_empty() {
+            return Ok(DeletionResult::NotFound);
+        }
+        for version in versions {
+            self.delete_version(id.clone(), version.version)?;
+        }
+        Ok(DeletionResult::Deleted)
+    }
+
+    // Delete a single version of the secret with the given ID
+    pub fn delete_version(&self, id: String, version: u64) -> Result<(), MoroccoError> {
         let delete_item_input = DeleteItemInput {
-            key: key,
+            key: item_key(id, version),
             table_name: self.table_name.clone(),
-            condition_expression: Some("attribute_exists(id)".to_string()),
             ..Default::default()
         };
-        match self.dynamo_client.delete_item(&delete_item_input) {
-            Ok(_) => Ok(DeletionResult::Deleted),
-            Err(DeleteItemError::ConditionalCheckFailed(_)) => Ok(DeletionResult::NotFound),
-            Err(err) => Err(MoroccoError::from(err))
-        }
+        self.dynamo_client.delete_item(&delete_item_input).map_err(aws_error)?;
+        Ok(())
     }
 
     pub fn create_table_if_does_not_exist(&self) -> Result<&str, MoroccoError> {
@@ -119,27 +372,99 @@ impl DynamoOps {
         }
     }
 
+    // Copy every secret from a table created by an older version of morocco, which kept a single version
+    // of each secret keyed by its ID alone, into this table as version 1. The items are copied as they are,
+    // so they stay encrypted under the same data keys. Secrets that already have a version 1 here are skipped,
+    // so an interrupted migration can be run again. The outcome for each secret is passed to the callback.
+    pub fn migrate_from(&self, legacy_table_name: &str, callback: &mut FnMut(&str, PutResult)) -> Result<(), MoroccoError> {
+        match self.key_schema(legacy_table_name)? {
+            Some(ref key_schema) if is_versioned(key_schema) => return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("The Dynamo table {} already supports versioned secrets, so there is nothing to migrate.", legacy_table_name))),
+            Some(_) => (),
+            None => return Err(MoroccoError::new(ErrorKind::Config, format!("The Dynamo table {} does not exist.", legacy_table_name)))
+        }
+        self.create_table_if_does_not_exist()?;
+        let mut exclusive_start_key = None;
+        loop {
+            let scan_input = ScanInput {
+                table_name: legacy_table_name.to_string(),
+                consistent_read: Some(true),
+                exclusive_start_key: exclusive_start_key,
+                ..Default::default()
+            };
+            let output = self.dynamo_client.scan(&scan_input).map_err(aws_error)?;
+            for mut attributes in output.items.unwrap_or(Vec::new()) {
+                let id = match attributes.get("id").and_then(|x| x.s.clone()) {
+                    Some(id) => id,
+                    None => continue
+                };
+                attributes.insert("version".to_string(), AttributeValue { n: Some("1".to_string()), ..Default::default() });
+                let put_item_input = PutItemInput {
+                    table_name: self.table_name.clone(),
+                    item: attributes,
+                    condition_expression: Some("attribute_not_exists(id)".to_string()),
+                    ..Default::default()
+                };
+                let result = match self.dynamo_client.put_item(&put_item_input) {
+                    Ok(_) => PutResult::Stored,
+                    Err(PutItemError::ConditionalCheckFailed(_)) => PutResult::DidNotOverwrite,
+                    Err(err) => return Err(aws_error(err))
+                };
+                callback(&id, result);
+            }
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => return Ok(())
+            }
+        }
+    }
+
     fn does_table_exist(&self) -> Result<bool, MoroccoError> {
-        let table_name = self.table_name.clone();
-        let describe_table_input = DescribeTableInput { table_name };
+        match self.key_schema(&self.table_name)? {
+            Some(ref key_schema) if is_versioned(key_schema) => Ok(true),
+            Some(_) => Err(MoroccoError::new(ErrorKind::Config, format!(
+                        "The Dynamo table {} was created by an older version of morocco and does not support versioned secrets. \
+                         Copy your secrets into a new table with `morocco aws --table <new table> migrate --from {}`.",
+                        self.table_name, self.table_name))),
+            None => Ok(false)
+        }
+    }
+
+    // The key schema of the given table, or None if it doesn't exist
+    fn key_schema(&self, table_name: &str) -> Result<Option<Vec<KeySchemaElement>>, MoroccoError> {
+        let describe_table_input = DescribeTableInput { table_name: table_name.to_string() };
         match self.dynamo_client.describe_table(&describe_table_input) {
-            Ok(output) => Ok(output.table.is_some()),
-            Err(DescribeTableError::ResourceNotFound(_)) => Ok(false),
-            Err(other) => Err(MoroccoError::from(other))
+            Ok(DescribeTableOutput { table: Some(table), .. }) => Ok(Some(table.key_schema.unwrap_or(Vec::new()))),
+            Ok(_) => Ok(None),
+            Err(DescribeTableError::ResourceNotFound(_)) => Ok(None),
+            Err(other) => Err(aws_error(other))
         }
     }
 
     fn create_table(&self) -> Result<(), MoroccoError> {
         let table_name = self.table_name.clone();
         let create_table_input = CreateTableInput { 
-            attribute_definitions: vec![ AttributeDefinition { 
-                attribute_name: "id".to_string(), 
-                attribute_type: "S".to_string() 
-            } ],
-            key_schema: vec![ KeySchemaElement { 
-                attribute_name: "id".to_string(), 
-                key_type: "HASH".to_string() 
-            } ],
+            attribute_definitions: vec![ 
+                AttributeDefinition { 
+                    attribute_name: "id".to_string(), 
+                    attribute_type: "S".to_string() 
+                },
+                AttributeDefinition { 
+                    attribute_name: "version".to_string(), 
+                    attribute_type: "N".to_string() 
+                }
+            ],
+            key_schema: vec![ 
+                KeySchemaElement { 
+                    attribute_name: "id".to_string(), 
+                    key_type: "HASH".to_string() 
+                },
+                KeySchemaElement { 
+                    attribute_name: "version".to_string(), 
+                    key_type: "RANGE".to_string() 
+                }
+            ],
             provisioned_throughput: ProvisionedThroughput { 
                 read_capacity_units: 1, 
                 write_capacity_units: 1 
@@ -147,29 +472,107 @@ impl DynamoOps {
             table_name: table_name,
             ..Default::default()
         };
-        self.dynamo_client.create_table(&create_table_input)?;
+        self.dynamo_client.create_table(&create_table_input).map_err(aws_error)?;
         Ok(())
     }
 
+    // A query for all versions of the secret with the given ID
+    fn query_by_id(&self, id: String) -> QueryInput {
+        let values = [
+            (":id".to_string(), AttributeValue { s: Some(id), ..Default::default() })
+        ].iter().cloned().collect::<ExpressionAttributeValueMap>();
+        QueryInput {
+            table_name: self.table_name.clone(),
+            key_condition_expression: Some("id = :id".to_string()),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        }
+    }
+
+}
+
+// The attributes needed to describe a version of a secret without decrypting it
+const VERSION_INFO_PROJECTION: &str = "#version, created_at, #description, #tags, #author, expires_at";
+
+// Attributes are aliased in projection expressions in case they clash with reserved words
+fn version_info_attribute_names() -> ExpressionAttributeNameMap {
+    [
+        ("#version".to_string(), "version".to_string()),
+        ("#description".to_string(), "description".to_string()),
+        ("#tags".to_string(), "tags".to_string()),
+        ("#author".to_string(), "author".to_string())
+    ].iter().cloned().collect::<ExpressionAttributeNameMap>()
+}
+
+// Tables created by older versions of morocco only have an id key
+fn is_versioned(key_schema: &[KeySchemaElement]) -> bool {
+    key_schema.iter().any(|k| k.attribute_name == "version")
+}
+
+fn item_key(id: String, version: u64) -> Key {
+    [
+        ("id".to_string(), AttributeValue { s: Some(id), ..Default::default() }),
+        ("version".to_string(), AttributeValue { n: Some(version.to_string()), ..Default::default() })
+    ].iter().cloned().collect::<Key>()
+}
+
+fn attribute_map_to_version(attribute_map: &AttributeMap) -> Result<u64, MoroccoError> {
+    match attribute_map.get("version").and_then(|x| x.n.clone()) {
+        Some(version) => Ok(version.parse::<u64>()?),
+        None => Err(MoroccoError::new(ErrorKind::Backend, "Item did not contain a version".to_string()))
+    }
+}
+
+fn attribute_map_to_version_info(attribute_map: &AttributeMap) -> Result<VersionInfo, MoroccoError> {
+    let string = |name: &str| attribute_map.get(name).and_then(|x| x.s.clone());
+    let tags = attribute_map.get("tags").and_then(|x| x.m.clone()).unwrap_or(HashMap::new())
+        .into_iter()
+        .flat_map(|(key, value)| value.s.map(|v| (key, v)))
+        .collect();
+    Ok(VersionInfo {
+        version: attribute_map_to_version(attribute_map)?,
+        created_at: string("created_at").unwrap_or("unknown".to_string()),
+        metadata: Metadata {
+            description: string("description"),
+            tags: tags,
+            author: string("author"),
+            expires_at: string("expires_at")
+        }
+    })
+}
+
+fn attribute_map_to_item_and_metadata(attribute_map: &AttributeMap) -> Result<(Item, Metadata), MoroccoError> {
+    Ok((attribute_map_to_item(attribute_map)?, attribute_map_to_version_info(attribute_map)?.metadata))
 }
 
 fn attribute_map_to_item(attribute_map: &AttributeMap) -> Result<Item, MoroccoError> {
     let encrypted_key_opt = attribute_map.get("encrypted_data_key").and_then(|x| x.s.clone());
     let encrypted_data_opt = attribute_map.get("encrypted_data").and_then(|x| x.s.clone());
     let iv_opt = attribute_map.get("iv").and_then(|x| x.s.clone());
+    // Items written before we started recording the cipher were all encrypted using CBC
+    let cipher_name = attribute_map.get("cipher").and_then(|x| x.s.clone())
+        .unwrap_or(Cipher::AesCbc.name().to_string());
 
-    match (encrypted_key_opt, encrypted_data_opt, iv_opt) {
-        (Some(encrypted_key_base64), Some(encrypted_data_base64), Some(iv_base64)) => {
+    match (encrypted_key_opt, encrypted_data_opt, iv_opt, Cipher::from_name(&cipher_name)) {
+        (Some(encrypted_key_base64), Some(encrypted_data_base64), Some(iv_base64), Some(cipher)) => {
             let encrypted_key = decode(&encrypted_key_base64)?;
             let encrypted_data = decode(&encrypted_data_base64)?;
             let iv = decode(&iv_base64)?;
+            let hmac = match attribute_map.get("hmac").and_then(|x| x.s.clone()) {
+                Some(hmac_base64) => Some(decode(&hmac_base64)?),
+                None => None
+            };
             Ok(Item {
                 encrypted_data_key: encrypted_key,
                 encrypted_data: encrypted_data,
-                iv: iv
+                iv: iv,
+                cipher: cipher,
+                hmac: hmac,
+                context_bound: attribute_map.get("context_bound").and_then(|x| x.bool).unwrap_or(false)
             })
         },
-        _ => Err(MoroccoError { message: "Item did not contain the expected fields".to_string() })
+        (_, _, _, None) => Err(MoroccoError::new(ErrorKind::Backend, format!("Item was encrypted using an unsupported cipher: {}", cipher_name))),
+        _ => Err(MoroccoError::new(ErrorKind::Backend, "Item did not contain the expected fields".to_string()))
     }
 }
 
@@ -0,0 +1,86 @@
+extern crate rusoto_core;
+extern crate hyper;
+
+use std::collections::HashMap;
+
+use self::rusoto_core::{default_tls_client, DispatchSignedRequest, HttpResponse, HttpDispatchError};
+use self::rusoto_core::signature::SignedRequest;
+use self::hyper::Client;
+use self::hyper::header::Headers;
+use self::hyper::method::Method;
+
+use morocco::{MoroccoError, ErrorKind};
+
+// Sends requests to a custom endpoint, such as DynamoDB Local or a local KMS stand-in,
+// instead of the standard endpoint for the region.
+// Requests are still signed for the standard endpoint, so this is only meant for services
+// that do not check signatures.
+pub struct EndpointDispatcher {
+    client: Client,
+    // e.g. "http://localhost:8000"
+    base_url: String,
+    // e.g. "localhost:8000"
+    host: String
+}
+
+impl EndpointDispatcher {
+
+    pub fn new(endpoint: &str) -> Result<EndpointDispatcher, MoroccoError> {
+        let endpoint = endpoint.trim_right_matches('/');
+        let (scheme, host) = match endpoint.find("://") {
+            Some(i) => (&endpoint[..i], &endpoint[i + 3..]),
+            None => ("https", endpoint)
+        };
+        if (scheme != "http" && scheme != "https") || host.is_empty() || host.contains('/') {
+            return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("Invalid endpoint {}. Expected a URL such as http://localhost:8000", endpoint)));
+        }
+        Ok(EndpointDispatcher {
+            client: default_tls_client()?,
+            base_url: format!("{}://{}", scheme, host),
+            host: host.to_string()
+        })
+    }
+
+}
+
+impl DispatchSignedRequest for EndpointDispatcher {
+
+    fn dispatch(&self, request: &SignedRequest) -> Result<HttpResponse, HttpDispatchError> {
+        let method = match request.method() {
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "HEAD" => Method::Head,
+            _ => Method::Get
+        };
+
+        let mut headers = Headers::new();
+        for (name, values) in request.headers() {
+            if name != "host" {
+                headers.set_raw(name.to_owned(), values.to_owned());
+            }
+        }
+        headers.set_raw("host", vec![self.host.clone().into_bytes()]);
+
+        let mut url = format!("{}{}", self.base_url, request.canonical_path());
+        if !request.canonical_query_string().is_empty() {
+            url = format!("{}?{}", url, request.canonical_query_string());
+        }
+
+        let payload = request.payload().unwrap_or(&[]);
+        let response = self.client.request(method, &url).headers(headers).body(payload).send()?;
+
+        let mut response_headers = HashMap::new();
+        for header in response.headers.iter() {
+            response_headers.insert(header.name().to_string(), header.value_string());
+        }
+        Ok(HttpResponse {
+            status: response.status,
+            headers: response_headers,
+            body: Box::new(response)
+        })
+    }
+
+}
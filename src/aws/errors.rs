@@ -0,0 +1,155 @@
+extern crate rusoto_core;
+extern crate rusoto_dynamodb;
+extern crate rusoto_kms;
+extern crate rusoto_sts;
+extern crate serde_json;
+
+use std::error::Error;
+
+use self::rusoto_core::{CredentialsError, TlsError};
+use self::rusoto_dynamodb::{CreateTableError, DeleteItemError, DescribeTableError, GetItemError, PutItemError, QueryError,
+                            ScanError, UpdateItemError};
+use self::rusoto_kms::{CreateAliasError, CreateKeyError, DecryptError, DescribeKeyError, GenerateDataKeyError,
+                       GenerateRandomError};
+use self::rusoto_sts::GetCallerIdentityError;
+
+use morocco::{MoroccoError, ErrorKind};
+
+// An error returned by rusoto, which can tell what kind of failure it was
+pub trait AwsError: Error + Send + Sync + 'static {
+    fn kind(&self) -> ErrorKind;
+}
+
+pub fn aws_error<E: AwsError>(err: E) -> MoroccoError {
+    MoroccoError::with_source(err.kind(), err)
+}
+
+impl From<TlsError> for MoroccoError {
+    fn from(err: TlsError) -> MoroccoError {
+        MoroccoError::with_source(ErrorKind::Backend, err)
+    }
+}
+
+impl AwsError for CredentialsError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::AccessDenied
+    }
+}
+
+// rusoto has a separate error type for every operation. They all share the HttpDispatch, Credentials,
+// Validation and Unknown variants, and then have one variant for each error that the operation is
+// documented to return. Anything that isn't listed is a backend error.
+macro_rules! aws_error_kinds {
+    ($($error:ident { $($variant:ident => $kind:ident),* })*) => { $(
+        impl AwsError for $error {
+            // the catch-all is unreachable for operations whose errors are all listed
+            #[allow(unreachable_patterns)]
+            fn kind(&self) -> ErrorKind {
+                match *self {
+                    $($error::$variant(_) => ErrorKind::$kind,)*
+                    $error::HttpDispatch(_) => ErrorKind::Unavailable,
+                    $error::Credentials(_) => ErrorKind::AccessDenied,
+                    $error::Validation(_) => ErrorKind::Config,
+                    $error::Unknown(ref response) => error_code_kind(response),
+                    _ => ErrorKind::Backend
+                }
+            }
+        }
+    )* }
+}
+
+// A missing or disabled table or key means that setup has not been run or the options are wrong
+aws_error_kinds! {
+    GetItemError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    QueryError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    ScanError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    PutItemError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    UpdateItemError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    DeleteItemError { ProvisionedThroughputExceeded => Throttled, ResourceNotFound => Config, InternalServerError => Unavailable }
+    DescribeTableError { ResourceNotFound => Config, InternalServerError => Unavailable }
+    CreateTableError { LimitExceeded => Throttled, InternalServerError => Unavailable }
+    DescribeKeyError { NotFound => Config, DependencyTimeout => Unavailable, KMSInternal => Unavailable }
+    CreateKeyError { LimitExceeded => Throttled, DependencyTimeout => Unavailable, KMSInternal => Unavailable }
+    CreateAliasError { NotFound => Config, KMSInvalidState => Config, LimitExceeded => Throttled,
+                       DependencyTimeout => Unavailable, KMSInternal => Unavailable }
+    GenerateRandomError { DependencyTimeout => Unavailable, KMSInternal => Unavailable }
+    GenerateDataKeyError { NotFound => Config, Disabled => Config, KMSInvalidState => Config,
+                           DependencyTimeout => Unavailable, KMSInternal => Unavailable, KeyUnavailable => Unavailable }
+    DecryptError { NotFound => Config, Disabled => Config, KMSInvalidState => Config, InvalidCiphertext => IntegrityFailure,
+                   DependencyTimeout => Unavailable, KMSInternal => Unavailable, KeyUnavailable => Unavailable }
+    GetCallerIdentityError { }
+}
+
+// rusoto only models the errors that are specific to an operation. Errors that any request can get,
+// such as being throttled or denied access, come back as Unknown with the raw response, so they are
+// classified by the error code in it.
+fn error_code_kind(response: &str) -> ErrorKind {
+    match error_code(response).as_ref().map(|code| code.as_str()) {
+        Some("AccessDeniedException") | Some("UnrecognizedClientException") | Some("InvalidSignatureException")
+            | Some("IncompleteSignatureException") | Some("MissingAuthenticationTokenException")
+            | Some("ExpiredTokenException") | Some("AccessDenied") | Some("InvalidClientTokenId")
+            | Some("SignatureDoesNotMatch") | Some("ExpiredToken") => ErrorKind::AccessDenied,
+        Some("ThrottlingException") | Some("Throttling") | Some("RequestLimitExceeded")
+            | Some("ProvisionedThroughputExceededException") | Some("LimitExceededException") => ErrorKind::Throttled,
+        Some("ServiceUnavailable") | Some("ServiceUnavailableException") | Some("InternalFailure")
+            | Some("InternalServerError") => ErrorKind::Unavailable,
+        Some("ValidationException") | Some("ValidationError") => ErrorKind::Config,
+        _ => ErrorKind::Backend
+    }
+}
+
+// The error code in a response from AWS. The JSON APIs (DynamoDB and KMS) give it as __type,
+// sometimes prefixed with a namespace and #, and the XML APIs (STS) give it as <Code>.
+fn error_code(response: &str) -> Option<String> {
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(response) {
+        return json.get("__type")
+            .and_then(|t| t.as_str())
+            .and_then(|t| t.rsplit('#').next())
+            .map(|code| code.to_string());
+    }
+    let start = response.find("<Code>")? + "<Code>".len();
+    let end = start + response[start..].find("</Code>")?;
+    Some(response[start..end].trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::rusoto_core::HttpDispatchError;
+
+    #[test]
+    fn modelled_errors() {
+        assert_eq!(ErrorKind::Config, GetItemError::ResourceNotFound("Requested resource not found".to_string()).kind());
+        assert_eq!(ErrorKind::Throttled, QueryError::ProvisionedThroughputExceeded("slow down".to_string()).kind());
+        assert_eq!(ErrorKind::IntegrityFailure, DecryptError::InvalidCiphertext("".to_string()).kind());
+        assert_eq!(ErrorKind::Config, GenerateDataKeyError::Disabled("arn:aws:kms:eu-west-1:1:key/1 is disabled".to_string()).kind());
+        assert_eq!(ErrorKind::Unavailable, DescribeKeyError::KMSInternal("".to_string()).kind());
+        // the message doesn't matter, only the variant
+        assert_eq!(ErrorKind::Backend, PutItemError::ConditionalCheckFailed("Credentials were fine, but the Throttling was NotFound".to_string()).kind());
+        assert_eq!(ErrorKind::Backend, CreateAliasError::AlreadyExists("alias/morocco".to_string()).kind());
+    }
+
+    #[test]
+    fn shared_errors() {
+        let dispatch = HttpDispatchError::new("connection refused".to_string());
+        assert_eq!(ErrorKind::Unavailable, ScanError::HttpDispatch(dispatch).kind());
+        assert_eq!(ErrorKind::AccessDenied, DecryptError::Credentials(CredentialsError::new("no credentials")).kind());
+        assert_eq!(ErrorKind::AccessDenied, CredentialsError::new("no credentials").kind());
+        assert_eq!(ErrorKind::Config, CreateTableError::Validation("table name too short".to_string()).kind());
+    }
+
+    #[test]
+    fn unmodelled_error_codes() {
+        let json = |code: &str| format!("{{\"__type\":\"{}\",\"message\":\"Rate of requests exceeds the allowed throughput\"}}", code);
+        assert_eq!(ErrorKind::Throttled, GetItemError::Unknown(json("com.amazonaws.dynamodb.v20120810#ThrottlingException")).kind());
+        assert_eq!(ErrorKind::AccessDenied, DecryptError::Unknown(json("AccessDeniedException")).kind());
+        assert_eq!(ErrorKind::AccessDenied, QueryError::Unknown(json("com.amazon.coral.service#UnrecognizedClientException")).kind());
+        assert_eq!(ErrorKind::Config, PutItemError::Unknown(json("com.amazon.coral.validate#ValidationException")).kind());
+        // only the code counts, not words that happen to appear in the message
+        assert_eq!(ErrorKind::Backend, PutItemError::Unknown(json("com.amazonaws.dynamodb.v20120810#TransactionConflictException")).kind());
+
+        let xml = "<ErrorResponse><Error><Type>Sender</Type><Code>ExpiredToken</Code><Message>The security token included in the request is expired</Message></Error></ErrorResponse>";
+        assert_eq!(ErrorKind::AccessDenied, error_code_kind(xml));
+        assert_eq!(ErrorKind::Backend, ScanError::Unknown("<html>Bad Gateway</html>".to_string()).kind());
+    }
+}
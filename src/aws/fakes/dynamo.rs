@@ -0,0 +1,297 @@
+extern crate rusoto_dynamodb;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use self::rusoto_dynamodb::*;
+
+use super::NOT_SUPPORTED;
+
+type Attributes = HashMap<String, AttributeValue>;
+
+#[derive(Default)]
+struct Table {
+    key_schema: Vec<KeySchemaElement>,
+    // keyed by (id, version), so that all versions of a secret are scanned together, like a partition
+    items: BTreeMap<(String, u64), Attributes>
+}
+
+#[derive(Clone)]
+pub struct FakeDynamoDb {
+    tables: Arc<Mutex<BTreeMap<String, Table>>>,
+    // the maximum number of items returned by each scan or query, to exercise pagination
+    page_size: usize,
+    // run just before the next update, to simulate another process writing first
+    before_next_update: Arc<Mutex<Option<Box<FnMut() + Send>>>>,
+    // makes deletes fail, to simulate losing the connection part of the way through a change
+    fail_deletes: Arc<Mutex<bool>>
+}
+
+impl FakeDynamoDb {
+
+    pub fn new(page_size: usize) -> FakeDynamoDb {
+        FakeDynamoDb {
+            tables: Arc::new(Mutex::new(BTreeMap::new())),
+            page_size: page_size,
+            before_next_update: Arc::new(Mutex::new(None)),
+            fail_deletes: Arc::new(Mutex::new(false))
+        }
+    }
+
+    pub fn fail_deletes(&self, fail: bool) {
+        *self.fail_deletes.lock().unwrap() = fail;
+    }
+
+    pub fn before_next_update<F: FnMut() + Send + 'static>(&self, f: F) {
+        *self.before_next_update.lock().unwrap() = Some(Box::new(f));
+    }
+
+    pub fn item(&self, table_name: &str, id: &str, version: u64) -> Option<Attributes> {
+        self.tables.lock().unwrap().get(table_name).and_then(|table| table.items.get(&(id.to_string(), version)).cloned())
+    }
+
+    pub fn set_item(&self, table_name: &str, attributes: Attributes) {
+        let key = item_key(&attributes);
+        self.tables.lock().unwrap().get_mut(table_name).expect("the table has not been created").items.insert(key, attributes);
+    }
+
+    // Run a query or scan over the items in key order, starting after the exclusive start key.
+    // Like DynamoDB, the page is cut before the filter is applied.
+    fn page<'a, I>(&self, items: I, exclusive_start_key: &Option<Attributes>, limit: Option<usize>) -> (Vec<Attributes>, Option<Attributes>)
+        where I: Iterator<Item = &'a Attributes> {
+        let start = exclusive_start_key.as_ref().map(item_key);
+        let mut remaining = items.skip_while(|item| start.as_ref().map_or(false, |s| item_key(item) != *s))
+            .skip(if start.is_some() { 1 } else { 0 })
+            .cloned()
+            .collect::<Vec<Attributes>>();
+        let page_size = limit.map_or(self.page_size, |l| l.min(self.page_size));
+        if remaining.len() > page_size {
+            remaining.truncate(page_size);
+            let last_key = remaining.last().map(|item| key_attributes(item));
+            (remaining, last_key)
+        } else {
+            (remaining, None)
+        }
+    }
+
+}
+
+impl DynamoDb for FakeDynamoDb {
+
+    fn describe_table(&self, input: &DescribeTableInput) -> Result<DescribeTableOutput, DescribeTableError> {
+        match self.tables.lock().unwrap().get(&input.table_name) {
+            Some(table) => Ok(DescribeTableOutput {
+                table: Some(TableDescription {
+                    table_name: Some(input.table_name.clone()),
+                    key_schema: Some(table.key_schema.clone()),
+                    ..Default::default()
+                })
+            }),
+            None => Err(DescribeTableError::ResourceNotFound(format!("Requested resource not found: Table: {} not found", input.table_name)))
+        }
+    }
+
+    fn create_table(&self, input: &CreateTableInput) -> Result<CreateTableOutput, CreateTableError> {
+        self.tables.lock().unwrap().insert(input.table_name.clone(), Table { key_schema: input.key_schema.clone(), ..Default::default() });
+        Ok(CreateTableOutput::default())
+    }
+
+    fn put_item(&self, input: &PutItemInput) -> Result<PutItemOutput, PutItemError> {
+        let key = item_key(&input.item);
+        let mut tables = self.tables.lock().unwrap();
+        let table = match tables.get_mut(&input.table_name) {
+            Some(table) => table,
+            None => return Err(PutItemError::ResourceNotFound("Requested resource not found".to_string()))
+        };
+        if let Some(ref condition) = input.condition_expression {
+            if !condition_holds(condition, table.items.get(&key), &input.expression_attribute_names, &input.expression_attribute_values) {
+                return Err(PutItemError::ConditionalCheckFailed("The conditional request failed".to_string()));
+            }
+        }
+        table.items.insert(key, input.item.clone());
+        Ok(PutItemOutput::default())
+    }
+
+    fn get_item(&self, input: &GetItemInput) -> Result<GetItemOutput, GetItemError> {
+        match self.tables.lock().unwrap().get(&input.table_name) {
+            Some(table) => Ok(GetItemOutput {
+                item: table.items.get(&item_key(&input.key)).map(|item| project(item, &input.projection_expression, &input.expression_attribute_names)),
+                ..Default::default()
+            }),
+            None => Err(GetItemError::ResourceNotFound("Requested resource not found".to_string()))
+        }
+    }
+
+    fn query(&self, input: &QueryInput) -> Result<QueryOutput, QueryError> {
+        let id = match input.key_condition_expression.as_ref().map(|e| e.as_str()) {
+            Some("id = :id") => value(&input.expression_attribute_values, ":id").s.clone().unwrap(),
+            other => panic!("Unsupported key condition expression: {:?}", other)
+        };
+        let tables = self.tables.lock().unwrap();
+        let table = match tables.get(&input.table_name) {
+            Some(table) => table,
+            None => return Err(QueryError::ResourceNotFound("Requested resource not found".to_string()))
+        };
+        let mut versions = table.items.iter()
+            .filter(|&(&(ref item_id, _), _)| *item_id == id)
+            .map(|(_, item)| item)
+            .collect::<Vec<&Attributes>>();
+        if input.scan_index_forward == Some(false) {
+            versions.reverse();
+        }
+        let (items, last_evaluated_key) = self.page(versions.into_iter(), &input.exclusive_start_key, input.limit.map(|l| l as usize));
+        Ok(QueryOutput {
+            items: Some(items.iter().map(|item| project(item, &input.projection_expression, &input.expression_attribute_names)).collect()),
+            last_evaluated_key: last_evaluated_key,
+            ..Default::default()
+        })
+    }
+
+    fn scan(&self, input: &ScanInput) -> Result<ScanOutput, ScanError> {
+        let tables = self.tables.lock().unwrap();
+        let table = match tables.get(&input.table_name) {
+            Some(table) => table,
+            None => return Err(ScanError::ResourceNotFound("Requested resource not found".to_string()))
+        };
+        let (items, last_evaluated_key) = self.page(table.items.values(), &input.exclusive_start_key, input.limit.map(|l| l as usize));
+        let prefix = match input.filter_expression.as_ref().map(|e| e.as_str()) {
+            Some("begins_with(id, :prefix)") => value(&input.expression_attribute_values, ":prefix").s.clone(),
+            None => None,
+            other => panic!("Unsupported filter expression: {:?}", other)
+        };
+        Ok(ScanOutput {
+            items: Some(items.iter()
+                        .filter(|item| prefix.as_ref().map_or(true, |p| item["id"].s.as_ref().unwrap().starts_with(p.as_str())))
+                        .map(|item| project(item, &input.projection_expression, &input.expression_attribute_names))
+                        .collect()),
+            last_evaluated_key: last_evaluated_key,
+            ..Default::default()
+        })
+    }
+
+    fn update_item(&self, input: &UpdateItemInput) -> Result<UpdateItemOutput, UpdateItemError> {
+        let hook = self.before_next_update.lock().unwrap().take();
+        if let Some(mut hook) = hook {
+            hook();
+        }
+        let key = item_key(&input.key);
+        let mut tables = self.tables.lock().unwrap();
+        let table = match tables.get_mut(&input.table_name) {
+            Some(table) => table,
+            None => return Err(UpdateItemError::ResourceNotFound("Requested resource not found".to_string()))
+        };
+        if let Some(ref condition) = input.condition_expression {
+            if !condition_holds(condition, table.items.get(&key), &input.expression_attribute_names, &input.expression_attribute_values) {
+                return Err(UpdateItemError::ConditionalCheckFailed("The conditional request failed".to_string()));
+            }
+        }
+        let item = table.items.entry(key).or_insert(input.key.clone());
+        // e.g. SET #a = :a, #b = :b REMOVE #c
+        let expression = input.update_expression.as_ref().expect("An update expression is required");
+        let (set, remove) = match expression.find(" REMOVE ") {
+            Some(i) => (&expression[..i], Some(&expression[i + " REMOVE ".len()..])),
+            None => (expression.as_str(), None)
+        };
+        if !set.starts_with("SET ") {
+            panic!("Unsupported update expression: {}", expression);
+        }
+        for assignment in set["SET ".len()..].split(", ") {
+            let (name, placeholder) = split_comparison(assignment).unwrap_or_else(|| panic!("Unsupported update expression: {}", expression));
+            item.insert(resolve(name, &input.expression_attribute_names), value(&input.expression_attribute_values, placeholder).clone());
+        }
+        for name in remove.into_iter().flat_map(|r| r.split(", ")) {
+            item.remove(&resolve(name, &input.expression_attribute_names));
+        }
+        Ok(UpdateItemOutput::default())
+    }
+
+    fn delete_item(&self, input: &DeleteItemInput) -> Result<DeleteItemOutput, DeleteItemError> {
+        if *self.fail_deletes.lock().unwrap() {
+            return Err(DeleteItemError::InternalServerError("Internal server error".to_string()));
+        }
+        match self.tables.lock().unwrap().get_mut(&input.table_name) {
+            Some(table) => {
+                table.items.remove(&item_key(&input.key));
+                Ok(DeleteItemOutput::default())
+            },
+            None => Err(DeleteItemError::ResourceNotFound("Requested resource not found".to_string()))
+        }
+    }
+
+    fn batch_get_item(&self, _input: &BatchGetItemInput) -> Result<BatchGetItemOutput, BatchGetItemError> { Err(BatchGetItemError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn batch_write_item(&self, _input: &BatchWriteItemInput) -> Result<BatchWriteItemOutput, BatchWriteItemError> { Err(BatchWriteItemError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn delete_table(&self, _input: &DeleteTableInput) -> Result<DeleteTableOutput, DeleteTableError> { Err(DeleteTableError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn describe_limits(&self) -> Result<DescribeLimitsOutput, DescribeLimitsError> { Err(DescribeLimitsError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn describe_time_to_live(&self, _input: &DescribeTimeToLiveInput) -> Result<DescribeTimeToLiveOutput, DescribeTimeToLiveError> { Err(DescribeTimeToLiveError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_tables(&self, _input: &ListTablesInput) -> Result<ListTablesOutput, ListTablesError> { Err(ListTablesError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_tags_of_resource(&self, _input: &ListTagsOfResourceInput) -> Result<ListTagsOfResourceOutput, ListTagsOfResourceError> { Err(ListTagsOfResourceError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn tag_resource(&self, _input: &TagResourceInput) -> Result<(), TagResourceError> { Err(TagResourceError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn untag_resource(&self, _input: &UntagResourceInput) -> Result<(), UntagResourceError> { Err(UntagResourceError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn update_table(&self, _input: &UpdateTableInput) -> Result<UpdateTableOutput, UpdateTableError> { Err(UpdateTableError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn update_time_to_live(&self, _input: &UpdateTimeToLiveInput) -> Result<UpdateTimeToLiveOutput, UpdateTimeToLiveError> { Err(UpdateTimeToLiveError::Unknown(NOT_SUPPORTED.to_string())) }
+
+}
+
+// Tables created by older versions of morocco have no version key, so their items count as version 0
+fn item_key(attributes: &Attributes) -> (String, u64) {
+    let id = attributes.get("id").and_then(|v| v.s.clone()).expect("Every item needs an id");
+    let version = match attributes.get("version") {
+        Some(version) => version.n.as_ref().and_then(|n| n.parse().ok()).expect("Versions must be numbers"),
+        None => 0
+    };
+    (id, version)
+}
+
+fn key_attributes(attributes: &Attributes) -> Attributes {
+    attributes.iter()
+        .filter(|&(name, _)| name == "id" || name == "version")
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+// Replace a #placeholder with the attribute name it stands for
+fn resolve(name: &str, names: &Option<HashMap<String, String>>) -> String {
+    if name.starts_with('#') {
+        names.as_ref().and_then(|n| n.get(name)).cloned().unwrap_or_else(|| panic!("Undefined attribute name placeholder {}", name))
+    } else {
+        name.to_string()
+    }
+}
+
+fn value<'a>(values: &'a Option<HashMap<String, AttributeValue>>, placeholder: &str) -> &'a AttributeValue {
+    values.as_ref().and_then(|v| v.get(placeholder)).unwrap_or_else(|| panic!("Undefined attribute value placeholder {}", placeholder))
+}
+
+fn split_comparison(expression: &str) -> Option<(&str, &str)> {
+    expression.find(" = ").map(|i| (expression[..i].trim(), expression[i + 3..].trim()))
+}
+
+// Supports attribute_not_exists(name) and name = :value
+fn condition_holds(condition: &str,
+                   item: Option<&Attributes>,
+                   names: &Option<HashMap<String, String>>,
+                   values: &Option<HashMap<String, AttributeValue>>) -> bool {
+    if condition.starts_with("attribute_not_exists(") && condition.ends_with(')') {
+        let name = resolve(&condition["attribute_not_exists(".len()..condition.len() - 1], names);
+        item.map_or(true, |item| !item.contains_key(&name))
+    } else if let Some((name, placeholder)) = split_comparison(condition) {
+        let expected = value(values, placeholder);
+        item.and_then(|item| item.get(&resolve(name, names)))
+            .map_or(false, |actual| actual.s == expected.s && actual.n == expected.n && actual.bool == expected.bool)
+    } else {
+        panic!("Unsupported condition expression: {}", condition)
+    }
+}
+
+fn project(item: &Attributes, projection: &Option<String>, names: &Option<HashMap<String, String>>) -> Attributes {
+    match *projection {
+        Some(ref projection) => {
+            let wanted = projection.split(',').map(|name| resolve(name.trim(), names)).collect::<Vec<String>>();
+            item.iter()
+                .filter(|&(name, _)| wanted.contains(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect()
+        },
+        None => item.clone()
+    }
+}
@@ -0,0 +1,165 @@
+extern crate rusoto_kms;
+extern crate base64;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use self::rusoto_kms::*;
+use self::serde_json::Value;
+
+use super::NOT_SUPPORTED;
+
+#[derive(Default)]
+struct KmsState {
+    region: String,
+    // key ARNs, in the order they were created
+    keys: Vec<String>,
+    // alias name (e.g. alias/morocco) to key ARN
+    aliases: HashMap<String, String>,
+    // used to make IVs and data keys that differ from each other
+    counter: u8
+}
+
+// The "ciphertext" of a data key is just the key ARN, encryption context and plaintext wrapped in JSON
+#[derive(Clone, Default)]
+pub struct FakeKms {
+    state: Arc<Mutex<KmsState>>
+}
+
+impl FakeKms {
+
+    pub fn new(region: &str) -> FakeKms {
+        let kms = FakeKms::default();
+        kms.state.lock().unwrap().region = region.to_string();
+        kms
+    }
+
+    // Create a new key and point the alias at it, as when rotating the master key by hand
+    pub fn rotate(&self, alias_name: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        let arn = format!("arn:aws:kms:{}:123456789012:key/{}", state.region, state.keys.len() + 1);
+        state.keys.push(arn.clone());
+        state.aliases.insert(alias_name.to_string(), arn.clone());
+        arn
+    }
+
+    fn key_arn(&self, key_id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        if key_id.starts_with("alias/") {
+            state.aliases.get(key_id).cloned()
+        } else {
+            state.keys.iter().find(|arn| *arn == key_id || arn.ends_with(&format!("/{}", key_id))).cloned()
+        }
+    }
+
+    fn random_bytes(&self, length: Option<i64>) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.counter = state.counter.wrapping_add(1);
+        let counter = state.counter;
+        (0..length.unwrap_or(0) as usize).map(|i| counter.wrapping_mul(31).wrapping_add(i as u8)).collect()
+    }
+
+}
+
+impl Kms for FakeKms {
+
+    fn describe_key(&self, input: &DescribeKeyRequest) -> Result<DescribeKeyResponse, DescribeKeyError> {
+        match self.key_arn(&input.key_id) {
+            Some(arn) => Ok(DescribeKeyResponse {
+                key_metadata: Some(KeyMetadata {
+                    key_id: arn.rsplit('/').next().unwrap().to_string(),
+                    arn: Some(arn),
+                    ..Default::default()
+                })
+            }),
+            None => Err(DescribeKeyError::NotFound(format!("Key '{}' does not exist", input.key_id)))
+        }
+    }
+
+    fn create_key(&self, _input: &CreateKeyRequest) -> Result<CreateKeyResponse, CreateKeyError> {
+        let mut state = self.state.lock().unwrap();
+        let key_id = (state.keys.len() + 1).to_string();
+        let arn = format!("arn:aws:kms:{}:123456789012:key/{}", state.region, key_id);
+        state.keys.push(arn.clone());
+        Ok(CreateKeyResponse {
+            key_metadata: Some(KeyMetadata { key_id: key_id, arn: Some(arn), ..Default::default() })
+        })
+    }
+
+    fn create_alias(&self, input: &CreateAliasRequest) -> Result<(), CreateAliasError> {
+        match self.key_arn(&input.target_key_id) {
+            Some(arn) => {
+                self.state.lock().unwrap().aliases.insert(input.alias_name.clone(), arn);
+                Ok(())
+            },
+            None => Err(CreateAliasError::NotFound(format!("Key '{}' does not exist", input.target_key_id)))
+        }
+    }
+
+    fn generate_random(&self, input: &GenerateRandomRequest) -> Result<GenerateRandomResponse, GenerateRandomError> {
+        Ok(GenerateRandomResponse { plaintext: Some(self.random_bytes(input.number_of_bytes)) })
+    }
+
+    fn generate_data_key(&self, input: &GenerateDataKeyRequest) -> Result<GenerateDataKeyResponse, GenerateDataKeyError> {
+        let arn = match self.key_arn(&input.key_id) {
+            Some(arn) => arn,
+            None => return Err(GenerateDataKeyError::NotFound(format!("Key '{}' does not exist", input.key_id)))
+        };
+        let plaintext = self.random_bytes(input.number_of_bytes);
+        let wrapped = json!({
+            "key_arn": arn,
+            "context": input.encryption_context,
+            "plaintext": base64::encode(&plaintext)
+        });
+        Ok(GenerateDataKeyResponse {
+            ciphertext_blob: Some(serde_json::to_vec(&wrapped).unwrap()),
+            key_id: Some(arn),
+            plaintext: Some(plaintext)
+        })
+    }
+
+    fn decrypt(&self, input: &DecryptRequest) -> Result<DecryptResponse, DecryptError> {
+        let invalid = || DecryptError::InvalidCiphertext("".to_string());
+        let wrapped: Value = serde_json::from_slice(&input.ciphertext_blob).map_err(|_| invalid())?;
+        // as in KMS, the data key can only be decrypted with exactly the same encryption context
+        if wrapped["context"] != json!(input.encryption_context) {
+            return Err(invalid());
+        }
+        Ok(DecryptResponse {
+            key_id: wrapped["key_arn"].as_str().map(|arn| arn.to_string()),
+            plaintext: Some(base64::decode(wrapped["plaintext"].as_str().ok_or(invalid())?).map_err(|_| invalid())?)
+        })
+    }
+
+    fn cancel_key_deletion(&self, _input: &CancelKeyDeletionRequest) -> Result<CancelKeyDeletionResponse, CancelKeyDeletionError> { Err(CancelKeyDeletionError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn create_grant(&self, _input: &CreateGrantRequest) -> Result<CreateGrantResponse, CreateGrantError> { Err(CreateGrantError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn delete_alias(&self, _input: &DeleteAliasRequest) -> Result<(), DeleteAliasError> { Err(DeleteAliasError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn delete_imported_key_material(&self, _input: &DeleteImportedKeyMaterialRequest) -> Result<(), DeleteImportedKeyMaterialError> { Err(DeleteImportedKeyMaterialError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn disable_key(&self, _input: &DisableKeyRequest) -> Result<(), DisableKeyError> { Err(DisableKeyError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn disable_key_rotation(&self, _input: &DisableKeyRotationRequest) -> Result<(), DisableKeyRotationError> { Err(DisableKeyRotationError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn enable_key(&self, _input: &EnableKeyRequest) -> Result<(), EnableKeyError> { Err(EnableKeyError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn enable_key_rotation(&self, _input: &EnableKeyRotationRequest) -> Result<(), EnableKeyRotationError> { Err(EnableKeyRotationError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn encrypt(&self, _input: &EncryptRequest) -> Result<EncryptResponse, EncryptError> { Err(EncryptError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn generate_data_key_without_plaintext(&self, _input: &GenerateDataKeyWithoutPlaintextRequest) -> Result<GenerateDataKeyWithoutPlaintextResponse, GenerateDataKeyWithoutPlaintextError> { Err(GenerateDataKeyWithoutPlaintextError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn get_key_policy(&self, _input: &GetKeyPolicyRequest) -> Result<GetKeyPolicyResponse, GetKeyPolicyError> { Err(GetKeyPolicyError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn get_key_rotation_status(&self, _input: &GetKeyRotationStatusRequest) -> Result<GetKeyRotationStatusResponse, GetKeyRotationStatusError> { Err(GetKeyRotationStatusError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn get_parameters_for_import(&self, _input: &GetParametersForImportRequest) -> Result<GetParametersForImportResponse, GetParametersForImportError> { Err(GetParametersForImportError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn import_key_material(&self, _input: &ImportKeyMaterialRequest) -> Result<ImportKeyMaterialResponse, ImportKeyMaterialError> { Err(ImportKeyMaterialError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_aliases(&self, _input: &ListAliasesRequest) -> Result<ListAliasesResponse, ListAliasesError> { Err(ListAliasesError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_grants(&self, _input: &ListGrantsRequest) -> Result<ListGrantsResponse, ListGrantsError> { Err(ListGrantsError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_key_policies(&self, _input: &ListKeyPoliciesRequest) -> Result<ListKeyPoliciesResponse, ListKeyPoliciesError> { Err(ListKeyPoliciesError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_keys(&self, _input: &ListKeysRequest) -> Result<ListKeysResponse, ListKeysError> { Err(ListKeysError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_resource_tags(&self, _input: &ListResourceTagsRequest) -> Result<ListResourceTagsResponse, ListResourceTagsError> { Err(ListResourceTagsError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn list_retirable_grants(&self, _input: &ListRetirableGrantsRequest) -> Result<ListGrantsResponse, ListRetirableGrantsError> { Err(ListRetirableGrantsError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn put_key_policy(&self, _input: &PutKeyPolicyRequest) -> Result<(), PutKeyPolicyError> { Err(PutKeyPolicyError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn re_encrypt(&self, _input: &ReEncryptRequest) -> Result<ReEncryptResponse, ReEncryptError> { Err(ReEncryptError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn retire_grant(&self, _input: &RetireGrantRequest) -> Result<(), RetireGrantError> { Err(RetireGrantError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn revoke_grant(&self, _input: &RevokeGrantRequest) -> Result<(), RevokeGrantError> { Err(RevokeGrantError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn schedule_key_deletion(&self, _input: &ScheduleKeyDeletionRequest) -> Result<ScheduleKeyDeletionResponse, ScheduleKeyDeletionError> { Err(ScheduleKeyDeletionError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn tag_resource(&self, _input: &TagResourceRequest) -> Result<(), TagResourceError> { Err(TagResourceError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn untag_resource(&self, _input: &UntagResourceRequest) -> Result<(), UntagResourceError> { Err(UntagResourceError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn update_alias(&self, _input: &UpdateAliasRequest) -> Result<(), UpdateAliasError> { Err(UpdateAliasError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn update_key_description(&self, _input: &UpdateKeyDescriptionRequest) -> Result<(), UpdateKeyDescriptionError> { Err(UpdateKeyDescriptionError::Unknown(NOT_SUPPORTED.to_string())) }
+
+}
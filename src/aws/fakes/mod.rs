@@ -0,0 +1,15 @@
+// In-memory fakes of DynamoDB, KMS and STS, so that DynamoOps, KmsOps and AWS can be tested offline.
+// They support just the requests that morocco makes. Any other operation returns an Unknown error,
+// and expressions they don't understand panic, so that a change in the requests fails the tests loudly.
+// The DynamoDB and KMS fakes can be cloned before they are boxed up, so that tests can look at or tamper with their state.
+
+mod dynamo;
+mod kms;
+mod sts;
+
+pub use self::dynamo::FakeDynamoDb;
+pub use self::kms::FakeKms;
+pub use self::sts::FakeSts;
+
+// the message of the error returned by operations that morocco doesn't use
+const NOT_SUPPORTED: &str = "not supported by the fake";
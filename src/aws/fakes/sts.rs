@@ -0,0 +1,27 @@
+extern crate rusoto_sts;
+
+use self::rusoto_sts::*;
+
+use super::NOT_SUPPORTED;
+
+// Reports a fixed caller identity, which is recorded as the author of new versions
+pub struct FakeSts;
+
+impl Sts for FakeSts {
+
+    fn get_caller_identity(&self, _input: &GetCallerIdentityRequest) -> Result<GetCallerIdentityResponse, GetCallerIdentityError> {
+        Ok(GetCallerIdentityResponse {
+            account: Some("123456789012".to_string()),
+            arn: Some("arn:aws:iam::123456789012:user/chris".to_string()),
+            user_id: Some("AIDAEXAMPLE".to_string())
+        })
+    }
+
+    fn assume_role(&self, _input: &AssumeRoleRequest) -> Result<AssumeRoleResponse, AssumeRoleError> { Err(AssumeRoleError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn assume_role_with_saml(&self, _input: &AssumeRoleWithSAMLRequest) -> Result<AssumeRoleWithSAMLResponse, AssumeRoleWithSAMLError> { Err(AssumeRoleWithSAMLError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn assume_role_with_web_identity(&self, _input: &AssumeRoleWithWebIdentityRequest) -> Result<AssumeRoleWithWebIdentityResponse, AssumeRoleWithWebIdentityError> { Err(AssumeRoleWithWebIdentityError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn decode_authorization_message(&self, _input: &DecodeAuthorizationMessageRequest) -> Result<DecodeAuthorizationMessageResponse, DecodeAuthorizationMessageError> { Err(DecodeAuthorizationMessageError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn get_federation_token(&self, _input: &GetFederationTokenRequest) -> Result<GetFederationTokenResponse, GetFederationTokenError> { Err(GetFederationTokenError::Unknown(NOT_SUPPORTED.to_string())) }
+    fn get_session_token(&self, _input: &GetSessionTokenRequest) -> Result<GetSessionTokenResponse, GetSessionTokenError> { Err(GetSessionTokenError::Unknown(NOT_SUPPORTED.to_string())) }
+
+}
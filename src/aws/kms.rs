@@ -1,25 +1,32 @@
 extern crate rusoto_kms;
 
-use morocco::MoroccoError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use morocco::{MoroccoError, ErrorKind, Secret};
+use aws::errors::aws_error;
 
 use self::rusoto_kms::*;
 
 pub struct KmsOps {
     key_id: String,
-    kms_client: Box<Kms>
+    kms_client: Box<Kms + Send>,
+    // the ARN of the master key that the alias points to, looked up when first needed
+    key_arn: RefCell<Option<String>>
 }
 
 pub struct DataKey {
     pub encrypted: Vec<u8>,
-    pub plaintext: Vec<u8>
+    pub plaintext: Secret
 }
 
 impl KmsOps {
 
-    pub fn new(key_alias: String, kms_client: Box<Kms>) -> KmsOps {
+    pub fn new(key_alias: String, kms_client: Box<Kms + Send>) -> KmsOps {
         KmsOps {
             key_id: format!("alias/{}", key_alias),
-            kms_client: kms_client
+            kms_client: kms_client,
+            key_arn: RefCell::new(None)
         }
     }
 
@@ -31,7 +38,26 @@ impl KmsOps {
         match self.kms_client.describe_key(&describe_key_request) {
             Ok(response) => Ok(response.key_metadata.is_some()),
             Err(DescribeKeyError::NotFound(_)) => Ok(false),
-            Err(other) => Err(MoroccoError::from(other))
+            Err(other) => Err(aws_error(other))
+        }
+    }
+
+    // The ARN of the master key that the alias currently points to
+    pub fn master_key_arn(&self) -> Result<String, MoroccoError> {
+        if let Some(ref arn) = *self.key_arn.borrow() {
+            return Ok(arn.clone());
+        }
+        let describe_key_request = DescribeKeyRequest {
+            grant_tokens: None,
+            key_id: self.key_id.clone()
+        };
+        let response = self.kms_client.describe_key(&describe_key_request).map_err(aws_error)?;
+        match response.key_metadata.and_then(|metadata| metadata.arn) {
+            Some(arn) => {
+                *self.key_arn.borrow_mut() = Some(arn.clone());
+                Ok(arn)
+            },
+            None => Err(MoroccoError::new(ErrorKind::Backend, format!("KMS did not return the ARN of {}", self.key_id)))
         }
     }
 
@@ -40,14 +66,14 @@ impl KmsOps {
             description: Some("Master key for encryption of secrets by morocco".to_string()),
             ..Default::default()
         };
-        let create_key_response = self.kms_client.create_key(&create_key_request)?;
+        let create_key_response = self.kms_client.create_key(&create_key_request).map_err(aws_error)?;
         let key_id = create_key_response.key_metadata.unwrap().key_id;
 
         let create_alias_request = CreateAliasRequest {
             alias_name: self.key_id.clone(),
             target_key_id: key_id
         };
-        let result = self.kms_client.create_alias(&create_alias_request)?;
+        let result = self.kms_client.create_alias(&create_alias_request).map_err(aws_error)?;
         Ok(result)
     }
 
@@ -60,38 +86,50 @@ impl KmsOps {
         }
     }
 
-    pub fn generate_iv(&self) -> Result<Vec<u8>, MoroccoError> {
+    pub fn generate_iv(&self, length: usize) -> Result<Vec<u8>, MoroccoError> {
         let gen_random_request = GenerateRandomRequest { 
-            number_of_bytes: Some(16)
+            number_of_bytes: Some(length as i64)
         };
         let iv = self.kms_client.generate_random(&gen_random_request)
-            .map(|response| response.plaintext.unwrap())?;
+            .map(|response| response.plaintext.unwrap())
+            .map_err(aws_error)?;
         Ok(iv)
     }
 
-    pub fn generate_data_key(&self) -> Result<DataKey, MoroccoError> {
+    pub fn generate_data_key(&self, length: usize, context: HashMap<String, String>) -> Result<DataKey, MoroccoError> {
         let gen_data_key_request = GenerateDataKeyRequest {
             key_id: self.key_id.clone(),
-            number_of_bytes: Some(32),
+            number_of_bytes: Some(length as i64),
+            encryption_context: Some(context),
             .. Default::default()
         };
         let (encrypted_key, plaintext_key) = self.kms_client.generate_data_key(&gen_data_key_request)
-            .map(|response| (response.ciphertext_blob.unwrap(), response.plaintext.unwrap()))?;
+            .map(|response| (response.ciphertext_blob.unwrap(), response.plaintext.unwrap()))
+            .map_err(aws_error)?;
         Ok(DataKey { 
             encrypted: encrypted_key,
-            plaintext: plaintext_key
+            plaintext: Secret::new(plaintext_key)
         })
     }
 
-    pub fn decrypt_data_key(&self, encrypted_key: Vec<u8>) -> Result<Vec<u8>, MoroccoError> {
+    pub fn decrypt_data_key(&self, encrypted_key: Vec<u8>, context: Option<HashMap<String, String>>) -> Result<Secret, MoroccoError> {
+        self.decrypt_data_key_with_key_arn(encrypted_key, context).map(|(plaintext_key, _)| plaintext_key)
+    }
+
+    // Decrypt a data key, also returning the ARN of the master key that it was encrypted under
+    pub fn decrypt_data_key_with_key_arn(&self, encrypted_key: Vec<u8>, context: Option<HashMap<String, String>>) -> Result<(Secret, Option<String>), MoroccoError> {
         let decrypt_request = DecryptRequest {
             ciphertext_blob: encrypted_key,
+            encryption_context: context,
             ..Default::default()
         };
         match self.kms_client.decrypt(&decrypt_request) {
-            Ok(DecryptResponse { plaintext: Some(plaintext_key), .. }) => Ok(plaintext_key),
-            Ok(_) => Err(MoroccoError { message: "Failed to decrypt the data key".to_string() }),
-            Err(err) => Err(MoroccoError::from(err))
+            Ok(DecryptResponse { plaintext: Some(plaintext_key), key_id, .. }) => Ok((Secret::new(plaintext_key), key_id)),
+            Ok(_) => Err(MoroccoError::new(ErrorKind::Backend, "Failed to decrypt the data key".to_string())),
+            Err(DecryptError::InvalidCiphertext(_)) => Err(MoroccoError::new(
+                ErrorKind::IntegrityFailure,
+                "Failed to decrypt the data key. If the secret was stored with --context, please pass the same context when retrieving it.".to_string())),
+            Err(err) => Err(aws_error(err))
         }
     }
 
@@ -1,31 +1,63 @@
 extern crate rusoto_core;
 extern crate rusoto_kms;
 extern crate rusoto_dynamodb;
+extern crate rusoto_sts;
+extern crate chrono;
 
+use std::cell::RefCell;
 use std::str::FromStr;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use self::rusoto_core::*;
-use self::rusoto_dynamodb::DynamoDbClient;
-use self::rusoto_kms::KmsClient;
+use self::rusoto_dynamodb::{DynamoDb, DynamoDbClient};
+use self::rusoto_kms::{Kms, KmsClient};
+use self::rusoto_sts::{GetCallerIdentityRequest, Sts, StsClient};
+use self::chrono::Utc;
 
 use morocco::*;
 use encryption::*;
 
 mod kms;
 mod dynamo;
+mod endpoint;
+mod profile;
+mod credentials;
+mod errors;
+#[cfg(test)]
+mod fakes;
 
 use aws::kms::KmsOps;
 use aws::dynamo::DynamoOps;
+use aws::endpoint::EndpointDispatcher;
+use aws::profile::AwsConfigFile;
+use aws::credentials::{AssumeRoleProvider, Callbacks, Credentials, RoleOptions};
+use aws::errors::aws_error;
 
 pub struct Item {
     encrypted_data_key: Vec<u8>,
     encrypted_data: Vec<u8>,
-    iv: Vec<u8>
+    iv: Vec<u8>,
+    cipher: Cipher,
+    hmac: Option<Vec<u8>>,
+    // whether the data key was bound to an encryption context when it was generated
+    context_bound: bool
 }
 
+// Send, so it can be built once and moved to a worker thread, but not Sync, as the author is cached in a RefCell.
+// Share one between threads behind a Mutex, or build one per thread.
 pub struct AWS {
+    region: String,
+    table_name: String,
+    encryption_context: HashMap<String, String>,
     dynamo_ops: DynamoOps,
-    kms_ops: KmsOps
+    kms_ops: KmsOps,
+    // None when a custom endpoint is used, as local stand-ins have no STS to ask
+    sts_client: Option<Box<Sts + Send>>,
+    // the caller's ARN, recorded as the author of new versions. Looked up on the first write.
+    author: RefCell<Option<Option<String>>>,
+    // the same table and key alias in other regions, which changes are copied to
+    replicas: Vec<AWS>
 }
 
 // TODO store values as binary when rusoto fix is released
@@ -33,83 +65,861 @@ pub struct AWS {
 impl Morocco for AWS {
 
     fn setup(&self) -> Result<String, MoroccoError> {
-        let create_table_result = self.dynamo_ops.create_table_if_does_not_exist()?;
-        let create_key_result = self.kms_ops.create_master_key_if_does_not_exist()?;
-        Ok(format!("{} {}", create_table_result, create_key_result))
+        let result = self.setup_region()?;
+        if self.replicas.is_empty() {
+            return Ok(result);
+        }
+        let mut results = vec![format!("{}: {}", self.region, result)];
+        for replica in &self.replicas {
+            results.push(format!("{}: {}", replica.region, replica.setup_region()?));
+        }
+        Ok(results.join("\n"))
     }
 
     fn list(&self) -> Result<Vec<String>, MoroccoError> {
         let mut result = self.dynamo_ops.list_ids()?;
         result.sort();
+        // there is one item per version of each secret
+        result.dedup();
         Ok(result)
     }
 
-    fn get(&self, id: String) -> Result<Vec<u8>, MoroccoError> {
-        let item = self.dynamo_ops.get_item(id)?;
-        self.decrypt_item(item)
+    fn list_streaming(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        self.dynamo_ops.scan_ids(limit, callback)
+    }
+
+    fn get(&self, id: &str) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, None).map(|(value, _)| value)
+    }
+
+    fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, Some(version)).map(|(value, _)| value)
+    }
+
+    fn get_with_metadata(&self, id: &str, version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+        let (item, metadata) = match version {
+            Some(version) => self.dynamo_ops.get_item(id.to_string(), version)?,
+            None => self.dynamo_ops.get_latest_item(id.to_string())?
+        };
+        Ok((self.decrypt_item(id, item)?, metadata))
+    }
+
+    // Reads all the items in one paginated scan instead of a query per secret.
+    // Each data key still has to be decrypted by KMS separately.
+    fn get_all(&self, prefix: Option<&str>) -> Result<BTreeMap<String, Secret>, MoroccoError> {
+        let mut secrets = BTreeMap::new();
+        for (id, item) in self.dynamo_ops.scan_latest_items(prefix.map(|p| p.to_string()))? {
+            let value = self.decrypt_item(&id, item)?;
+            secrets.insert(id, value);
+        }
+        Ok(secrets)
+    }
+
+    fn put(&self, id: &str, value: &[u8], overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError> {
+        match self.dynamo_ops.latest_version(id.to_string())? {
+            Some(_) if !overwrite => Ok(PutResult::DidNotOverwrite),
+            latest => {
+                let item = self.encrypt_value(id, value)?;
+                let previous = latest.as_ref().map(|v| v.metadata.clone()).unwrap_or_default();
+                let metadata = previous.updated(metadata, self.author());
+                let latest_version = latest.map(|v| v.version);
+                let created_at = Utc::now().to_rfc3339();
+                let result = self.put_new_version(id.to_string(), latest_version, item, &metadata, &created_at)?;
+                if result == PutResult::Stored {
+                    let version = latest_version.unwrap_or(0) + 1;
+                    self.replicate("stored", |replica| replica.store_replica(id, version, value, &metadata, &created_at, false))?;
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<VersionInfo>, MoroccoError> {
+        self.dynamo_ops.list_versions(id.to_string())
+    }
+
+    fn describe_all(&self) -> Result<Vec<Description>, MoroccoError> {
+        self.dynamo_ops.scan_descriptions()
     }
 
-    fn put(&self, id: String, value: Vec<u8>, overwrite: bool) -> Result<PutResult, MoroccoError> {
-        let item = self.encrypt_value(value)?;
-        self.dynamo_ops.put_item(id, item, overwrite)
+    fn rollback(&self, id: &str, version: u64) -> Result<RollbackResult, MoroccoError> {
+        let versions = self.dynamo_ops.list_versions(id.to_string())?;
+        if !versions.iter().any(|v| v.version == version) {
+            return Ok(RollbackResult::NotFound);
+        }
+        // The data key is bound to the secret ID, not the version, so the old item can be copied as is
+        let (item, restored) = self.dynamo_ops.get_item(id.to_string(), version)?;
+        let latest = versions.last().map(|v| v.version);
+        // the metadata describes the secret rather than the value, so keep the latest, apart from the expiry date of the value
+        let update = MetadataUpdate { expires_at: restored.expires_at, ..Default::default() };
+        let metadata = versions.last().map(|v| v.metadata.clone()).unwrap_or_default()
+            .updated(&update, self.author());
+        self.put_new_version(id.to_string(), latest, item, &metadata, &Utc::now().to_rfc3339())?;
+        // the replicas can't copy the item, as it is encrypted under this region's master key
+        self.replicate("rolled back", |replica| self.sync_secret(replica, id, false, false).map(|_| ()))?;
+        Ok(RollbackResult::RolledBack(latest.unwrap_or(0) + 1))
+    }
+
+    fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError> {
+        let result = self.dynamo_ops.delete_item(id.to_string())?;
+        // a secret that is only left in a replica, e.g. after a failed delete, is cleaned up as well
+        let mut deleted_from_replica = false;
+        self.replicate("deleted", |replica| {
+            let replica_result = replica.dynamo_ops.delete_item(id.to_string())?;
+            deleted_from_replica |= replica_result == DeletionResult::Deleted;
+            Ok(())
+        })?;
+        if deleted_from_replica {
+            Ok(DeletionResult::Deleted)
+        } else {
+            Ok(result)
+        }
     }
 
-    fn delete(&self, id: String) -> Result<DeletionResult, MoroccoError> {
-        self.dynamo_ops.delete_item(id)
+    fn reencrypt(&self, id: &str, version: u64, force: bool, dry_run: bool) -> Result<ReencryptResult, MoroccoError> {
+        let (item, _) = self.dynamo_ops.get_item(id.to_string(), version)?;
+        let context = if item.context_bound { Some(self.encryption_context_for(id)) } else { None };
+        let (plaintext_key, key_arn) = self.kms_ops.decrypt_data_key_with_key_arn(item.encrypted_data_key.clone(), context)?;
+        // KMS reports which master key a data key was encrypted under, so a re-run after a failure skips the work already done
+        if !force && key_arn == Some(self.kms_ops.master_key_arn()?) {
+            return Ok(ReencryptResult::AlreadyCurrent);
+        }
+        if dry_run {
+            return Ok(ReencryptResult::WouldReencrypt);
+        }
+        let plaintext = AWS::open_item(&item, plaintext_key.as_bytes())?;
+        let new_item = self.encrypt_value(id, plaintext.as_bytes())?;
+        match self.dynamo_ops.replace_encrypted_data(id.to_string(), version, &item.encrypted_data_key, new_item)? {
+            PutResult::Stored => Ok(ReencryptResult::Reencrypted),
+            PutResult::DidNotOverwrite => Err(MoroccoError::new(
+                    ErrorKind::Conflict,
+                    "The secret was changed while it was being re-encrypted. Please try again.".to_string()))
+        }
+    }
+
+    // Unlike the author, this has to be known, so failures are reported
+    fn caller_identity(&self) -> Result<Option<String>, MoroccoError> {
+        let sts = match self.sts_client {
+            Some(ref sts) => sts,
+            // local stand-ins don't check who is calling
+            None => return Ok(None)
+        };
+        if let Some(Some(ref arn)) = *self.author.borrow() {
+            return Ok(Some(arn.clone()));
+        }
+        match sts.get_caller_identity(&GetCallerIdentityRequest::default()).map_err(aws_error)?.arn {
+            Some(arn) => {
+                *self.author.borrow_mut() = Some(Some(arn.clone()));
+                Ok(Some(arn))
+            },
+            None => Err(MoroccoError::new(ErrorKind::Backend, "STS did not return the caller's ARN.".to_string()))
+        }
     }
 
 }
 
 impl AWS {
 
-    pub fn new(profile: Option<String>, region: String, table_name: String, key_alias: String) -> Result<AWS, MoroccoError> {
-        let reg = Region::from_str(region.as_str())?;
+    // Configure an AWS backend, e.g.
+    // AWS::builder().region("eu-west-1").table("secrets").build()
+    pub fn builder() -> AWSBuilder {
+        AWSBuilder::default()
+    }
+
+    // Bring the copies of secrets in the replica regions into line with this region, which is the source of truth.
+    // The outcome for each region and secret is passed to the callback, so one failure doesn't stop the rest.
+    // Secrets that only exist in a replica are reported, and only deleted if delete_extra is set.
+    pub fn sync_regions(&self, prefix: Option<&str>, dry_run: bool, delete_extra: bool,
+                        callback: &mut FnMut(&str, &str, Result<SyncResult, MoroccoError>)) -> Result<(), MoroccoError> {
+        if self.replicas.is_empty() {
+            return Err(MoroccoError::new(ErrorKind::Config, "Please give the regions to sync to using --replicate-to.".to_string()));
+        }
+        let ids = self.list()?;
+        for replica in &self.replicas {
+            let mut all_ids = replica.list()?;
+            all_ids.extend(ids.iter().cloned());
+            all_ids.sort();
+            all_ids.dedup();
+            for id in all_ids.into_iter().filter(|id| prefix.map_or(true, |p| id.starts_with(p))) {
+                callback(&replica.region, &id, self.sync_secret(replica, &id, dry_run, delete_extra));
+            }
+        }
+        Ok(())
+    }
+
+    // Copy the secrets from a table created by an older version of morocco into this region's table,
+    // creating the table if need be. Run sync_regions afterwards to copy them to the replica regions.
+    pub fn migrate_from(&self, legacy_table_name: &str, callback: &mut FnMut(&str, PutResult)) -> Result<(), MoroccoError> {
+        if legacy_table_name == self.table_name {
+            return Err(MoroccoError::new(ErrorKind::Config, "Please give the new table to copy the secrets into using --table.".to_string()));
+        }
+        self.dynamo_ops.migrate_from(legacy_table_name, callback)
+    }
+
+    fn setup_region(&self) -> Result<String, MoroccoError> {
+        let create_table_result = self.dynamo_ops.create_table_if_does_not_exist()?;
+        let create_key_result = self.kms_ops.create_master_key_if_does_not_exist()?;
+        Ok(format!("{} {}", create_table_result, create_key_result))
+    }
+
+    // Apply a change that has been made in this region to every replica region.
+    // A failure in one region doesn't stop the others from being updated.
+    fn replicate<F: FnMut(&AWS) -> Result<(), MoroccoError>>(&self, action: &str, mut change: F) -> Result<(), MoroccoError> {
+        let mut failures = Vec::new();
+        let mut kind = None;
+        for replica in &self.replicas {
+            if let Err(e) = change(replica) {
+                kind = kind.or(Some(e.kind));
+                failures.push(format!("{} ({})", replica.region, e.message));
+            }
+        }
+        match kind {
+            Some(kind) => Err(MoroccoError::new(kind, format!(
+                        "The secret was {} in {}, but not in {}. Run sync-regions to bring the regions back into line.",
+                        action, self.region, failures.join(", ")))),
+            None => Ok(())
+        }
+    }
+
+    // Store a version that was written in the main region, encrypted under this region's master key
+    fn store_replica(&self, id: &str, version: u64, value: &[u8], metadata: &Metadata, created_at: &str, overwrite: bool) -> Result<(), MoroccoError> {
+        let item = self.encrypt_value(id, value)?;
+        if overwrite {
+            return self.dynamo_ops.replace_item(id.to_string(), version, item, metadata, created_at);
+        }
+        match self.dynamo_ops.put_item(id.to_string(), version, item, metadata, created_at)? {
+            PutResult::Stored => Ok(()),
+            PutResult::DidNotOverwrite => Err(MoroccoError::new(
+                    ErrorKind::Conflict,
+                    format!("Version {} of the secret already exists in {}.", version, self.region)))
+        }
+    }
+
+    // Copy the versions of a secret that are missing from a replica region, re-encrypting each one there.
+    // The ciphertexts differ between regions, so versions are matched by their number and creation time.
+    fn sync_secret(&self, replica: &AWS, id: &str, dry_run: bool, delete_extra: bool) -> Result<SyncResult, MoroccoError> {
+        let versions = self.dynamo_ops.list_versions(id.to_string())?;
+        let replica_versions = replica.dynamo_ops.list_versions(id.to_string())?;
+        let same = |a: &VersionInfo, b: &VersionInfo| a.version == b.version && a.created_at == b.created_at;
+        if versions.is_empty() {
+            if replica_versions.is_empty() {
+                return Ok(SyncResult::InSync);
+            }
+            // it may have been stored in the replica region on purpose, so only delete it when asked to
+            if !delete_extra {
+                return Ok(SyncResult::OnlyInReplica);
+            }
+            if !dry_run {
+                replica.dynamo_ops.delete_item(id.to_string())?;
+            }
+            return Ok(SyncResult::Deleted);
+        }
+        // e.g. a version was stored directly in the replica region, so start again from this region's versions
+        let diverged = replica_versions.iter().any(|r| !versions.iter().any(|v| same(v, r)));
+        let missing = versions.iter()
+            .filter(|v| diverged || !replica_versions.iter().any(|r| same(v, r)))
+            .collect::<Vec<&VersionInfo>>();
+        let result = if diverged {
+            SyncResult::Replaced
+        } else if missing.is_empty() {
+            SyncResult::InSync
+        } else {
+            SyncResult::Copied(missing.len())
+        };
+        if dry_run {
+            return Ok(result);
+        }
+        // Overwrite the replica's versions before removing the ones the main region doesn't have,
+        // so that the secret never disappears from the replica if this fails part of the way through
+        for v in missing {
+            let (item, _) = self.dynamo_ops.get_item(id.to_string(), v.version)?;
+            let value = self.decrypt_item(id, item)?;
+            replica.store_replica(id, v.version, value.as_bytes(), &v.metadata, &v.created_at, diverged)?;
+        }
+        for r in replica_versions.iter().filter(|r| !versions.iter().any(|v| v.version == r.version)) {
+            replica.dynamo_ops.delete_version(id.to_string(), r.version)?;
+        }
+        Ok(result)
+    }
+
+    // The ARN of the caller, according to STS. This is only used for the metadata,
+    // so failures (e.g. when using DynamoDB Local with fake credentials) are ignored.
+    fn author(&self) -> Option<String> {
+        if let Some(ref author) = *self.author.borrow() {
+            return author.clone();
+        }
+        let author = self.sts_client.as_ref()
+            .and_then(|sts| sts.get_caller_identity(&GetCallerIdentityRequest::default()).ok())
+            .and_then(|identity| identity.arn);
+        *self.author.borrow_mut() = Some(author.clone());
+        author
+    }
+
+    fn put_new_version(&self, id: String, latest_version: Option<u64>, item: Item, metadata: &Metadata, created_at: &str) -> Result<PutResult, MoroccoError> {
+        let version = latest_version.unwrap_or(0) + 1;
+        match self.dynamo_ops.put_item(id, version, item, metadata, created_at)? {
+            PutResult::Stored => Ok(PutResult::Stored),
+            // Somebody else stored the same version between us reading the latest version and writing
+            PutResult::DidNotOverwrite if latest_version.is_some() =>
+                Err(MoroccoError::new(ErrorKind::Conflict, "Another version of the secret was stored concurrently. Please try again.".to_string())),
+            PutResult::DidNotOverwrite => Ok(PutResult::DidNotOverwrite)
+        }
+    }
+
+    // Build the KMS encryption context for the given secret.
+    // Binding the data key to the secret ID means that an encrypted data key
+    // copied from one item to another will fail to decrypt.
+    fn encryption_context_for(&self, id: &str) -> HashMap<String, String> {
+        let mut context = self.encryption_context.clone();
+        context.insert("id".to_string(), id.to_string());
+        context.insert("table".to_string(), self.table_name.clone());
+        context
+    }
+
+    fn encrypt_value(&self, id: &str, value: &[u8]) -> Result<Item, MoroccoError> {
+        let cipher = Cipher::AesGcm;
+        let iv = self.kms_ops.generate_iv(cipher.iv_length())?;
+        let data_key = self.kms_ops.generate_data_key(DATA_KEY_LENGTH, self.encryption_context_for(id))?;
+        let (ciphertext, hmac) = seal(cipher, value, data_key.plaintext.as_bytes(), iv.as_slice())?;
+        Ok(Item {
+            encrypted_data_key: data_key.encrypted,
+            encrypted_data: ciphertext,
+            iv: iv,
+            cipher: cipher,
+            hmac: Some(hmac),
+            context_bound: true
+        })
+    }
+
+    fn decrypt_item(&self, id: &str, item: Item) -> Result<Secret, MoroccoError> {
+        // Items written before we started using encryption contexts must be decrypted without one
+        let context = if item.context_bound { Some(self.encryption_context_for(id)) } else { None };
+        let plaintext_key = self.kms_ops.decrypt_data_key(item.encrypted_data_key.clone(), context)?;
+        AWS::open_item(&item, plaintext_key.as_bytes())
+    }
+
+    fn open_item(item: &Item, plaintext_key: &[u8]) -> Result<Secret, MoroccoError> {
+        let plaintext = open(item.cipher,
+                             item.encrypted_data.as_slice(),
+                             plaintext_key,
+                             item.iv.as_slice(),
+                             item.hmac.as_ref().map(|h| h.as_slice()))?;
+        Ok(plaintext)
+    }
+
+}
+
+// Builds an AWS backend. Anything that is not set is looked up the same way as the AWS CLI does,
+// apart from the table and key alias, which both default to "morocco".
+#[derive(Clone)]
+pub struct AWSBuilder {
+    profile: Option<String>,
+    region: Option<String>,
+    table_name: String,
+    key_alias: String,
+    encryption_context: HashMap<String, String>,
+    dynamodb_endpoint: Option<String>,
+    kms_endpoint: Option<String>,
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    mfa_serial: Option<String>,
+    replicate_to: Vec<String>,
+    callbacks: Callbacks
+}
+
+impl Default for AWSBuilder {
+    fn default() -> AWSBuilder {
+        AWSBuilder {
+            profile: None,
+            region: None,
+            table_name: "morocco".to_string(),
+            key_alias: "morocco".to_string(),
+            encryption_context: HashMap::new(),
+            dynamodb_endpoint: None,
+            kms_endpoint: None,
+            role_arn: None,
+            external_id: None,
+            mfa_serial: None,
+            replicate_to: Vec::new(),
+            callbacks: Callbacks::default()
+        }
+    }
+}
+
+impl AWSBuilder {
+
+    pub fn profile(mut self, profile: &str) -> AWSBuilder {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    pub fn region(mut self, region: &str) -> AWSBuilder {
+        self.region = Some(region.to_string());
+        self
+    }
+
+    pub fn table(mut self, table_name: &str) -> AWSBuilder {
+        self.table_name = table_name.to_string();
+        self
+    }
+
+    pub fn key_alias(mut self, key_alias: &str) -> AWSBuilder {
+        self.key_alias = key_alias.to_string();
+        self
+    }
+
+    // Extra key-value pairs that data keys will be bound to, in addition to the secret ID and table name
+    pub fn context(mut self, key: &str, value: &str) -> AWSBuilder {
+        self.encryption_context.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn encryption_context(mut self, encryption_context: HashMap<String, String>) -> AWSBuilder {
+        self.encryption_context = encryption_context;
+        self
+    }
+
+    // Use a stand-in such as DynamoDB Local
+    pub fn dynamodb_endpoint(mut self, endpoint: &str) -> AWSBuilder {
+        self.dynamodb_endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    pub fn kms_endpoint(mut self, endpoint: &str) -> AWSBuilder {
+        self.kms_endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    // The role can also be given by role_arn in the profile's section of ~/.aws/config
+    pub fn role_arn(mut self, role_arn: &str) -> AWSBuilder {
+        self.role_arn = Some(role_arn.to_string());
+        self
+    }
+
+    pub fn external_id(mut self, external_id: &str) -> AWSBuilder {
+        self.external_id = Some(external_id.to_string());
+        self
+    }
+
+    pub fn mfa_serial(mut self, mfa_serial: &str) -> AWSBuilder {
+        self.mfa_serial = Some(mfa_serial.to_string());
+        self
+    }
+
+    // Called with the serial number of the MFA device when a role that requires MFA is assumed,
+    // and returns the current code. Without it, such roles can't be used.
+    pub fn mfa_code_callback<F>(mut self, callback: F) -> AWSBuilder
+        where F: Fn(&str) -> Result<String, MoroccoError> + Send + Sync + 'static {
+        self.callbacks.mfa_code = Some(Arc::new(callback));
+        self
+    }
+
+    // Called with problems that don't stop the operation, such as failing to cache a role's credentials.
+    // They are ignored otherwise.
+    pub fn warning_callback<F>(mut self, callback: F) -> AWSBuilder
+        where F: Fn(&str) + Send + Sync + 'static {
+        self.callbacks.warning = Some(Arc::new(callback));
+        self
+    }
+
+    // Also set up, store and delete secrets in the same table and key alias in another region.
+    // The custom endpoints only apply to the main region.
+    pub fn replicate_to(mut self, region: &str) -> AWSBuilder {
+        self.replicate_to.push(region.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<AWS, MoroccoError> {
+        let replicas = self.replicate_to.iter()
+            .map(|replica_region| AWSBuilder {
+                region: Some(replica_region.clone()),
+                dynamodb_endpoint: None,
+                kms_endpoint: None,
+                replicate_to: Vec::new(),
+                ..self.clone()
+            }.build())
+            .collect::<Result<Vec<AWS>, MoroccoError>>()?;
+        let env = profile::aws_env();
+        let profile = profile::selected_profile(self.profile, &env);
+        let config_file = AwsConfigFile::load(&env);
+        let region = match profile::resolve_region(self.region, &profile, &config_file, &env) {
+            Some(region) => region,
+            None => return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    "Please specify a region using --region, AWS_REGION, AWS_DEFAULT_REGION or your profile in ~/.aws/config.".to_string()))
+        };
+        let reg = Region::from_str(region.as_str()).map_err(|e| MoroccoError::with_source(ErrorKind::Config, e))?;
+        if self.replicate_to.contains(&region) {
+            return Err(MoroccoError::new(ErrorKind::Config, format!("Cannot replicate to {}, as it is the main region.", region)));
+        }
+
+        let callbacks = self.callbacks;
+        let role = RoleOptions::resolve(self.role_arn, self.external_id, self.mfa_serial, &profile, &config_file)?
+            .map(|options| Arc::new(AssumeRoleProvider::new(options, reg, callbacks)));
+        let build_creds_provider = || -> Result<Credentials, MoroccoError> {
+            match role {
+                Some(ref provider) => Ok(Credentials::AssumedRole(provider.clone())),
+                None => Ok(Credentials::Chain(credentials::chain_provider(&profile).map_err(aws_error)?))
+            }
+        };
 
-        let dynamo_client = DynamoDbClient::new(default_tls_client()?, AWS::build_creds_provider(profile.clone())?, reg);
-        let dynamo_ops = DynamoOps::new(table_name.clone(), Box::new(dynamo_client));
+        // talking to the real STS would defeat testing against local tools, and can hang on the instance metadata
+        let sts_client: Option<Box<Sts + Send>> = if self.dynamodb_endpoint.is_some() || self.kms_endpoint.is_some() {
+            None
+        } else {
+            Some(Box::new(StsClient::new(default_tls_client()?, build_creds_provider()?, reg)))
+        };
 
-        let kms_client = KmsClient::new(default_tls_client()?, AWS::build_creds_provider(profile.clone())?, reg);
-        let kms_ops = KmsOps::new(key_alias.clone(), Box::new(kms_client));
+        let dynamo_creds = build_creds_provider()?;
+        let dynamo_client: Box<DynamoDb + Send> = match self.dynamodb_endpoint {
+            Some(endpoint) => Box::new(DynamoDbClient::new(EndpointDispatcher::new(&endpoint)?, dynamo_creds, reg)),
+            None => Box::new(DynamoDbClient::new(default_tls_client()?, dynamo_creds, reg))
+        };
+        let dynamo_ops = DynamoOps::new(self.table_name.clone(), dynamo_client);
+
+        let kms_creds = build_creds_provider()?;
+        let kms_client: Box<Kms + Send> = match self.kms_endpoint {
+            Some(endpoint) => Box::new(KmsClient::new(EndpointDispatcher::new(&endpoint)?, kms_creds, reg)),
+            None => Box::new(KmsClient::new(default_tls_client()?, kms_creds, reg))
+        };
+        let kms_ops = KmsOps::new(self.key_alias, kms_client);
 
         Ok(AWS {
+            region: region,
+            table_name: self.table_name,
+            encryption_context: self.encryption_context,
             dynamo_ops: dynamo_ops,
-            kms_ops: kms_ops
+            kms_ops: kms_ops,
+            sts_client: sts_client,
+            author: RefCell::new(None),
+            replicas: replicas
         })
     }
 
-    fn build_creds_provider(profile: Option<String>) -> Result<DefaultCredentialsProvider, CredentialsError> {
-        let mut profile_provider = ProfileProvider::new().unwrap();
-        if let Some(prof) = profile {
-            profile_provider.set_profile(prof);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate base64;
+
+    use super::*;
+    use super::rusoto_dynamodb::{AttributeValue, CreateTableInput, KeySchemaElement};
+    use super::rusoto_kms::GenerateDataKeyRequest;
+    use std::sync::Mutex;
+    use std::thread;
+    use aws::fakes::{FakeDynamoDb, FakeKms, FakeSts};
+
+    // Pages of two items make every scan and query paginate
+    fn fake_aws() -> (AWS, FakeDynamoDb, FakeKms) {
+        let dynamo = FakeDynamoDb::new(2);
+        let kms = FakeKms::new("eu-west-1");
+        (fake_region("eu-west-1", &dynamo, &kms), dynamo, kms)
+    }
+
+    fn fake_region(region: &str, dynamo: &FakeDynamoDb, kms: &FakeKms) -> AWS {
+        AWS {
+            region: region.to_string(),
+            table_name: "morocco".to_string(),
+            encryption_context: HashMap::new(),
+            dynamo_ops: DynamoOps::new("morocco".to_string(), Box::new(dynamo.clone())),
+            kms_ops: KmsOps::new("morocco".to_string(), Box::new(kms.clone())),
+            sts_client: Some(Box::new(FakeSts)),
+            author: RefCell::new(None),
+            replicas: Vec::new()
         }
-        let chain_provider = ChainProvider::with_profile_provider(profile_provider);
-        AutoRefreshingProvider::with_refcell(chain_provider)
     }
 
-    fn encrypt_value(&self, value: Vec<u8>) -> Result<Item, MoroccoError> {
-        let iv = self.kms_ops.generate_iv()?;
-        let data_key = self.kms_ops.generate_data_key()?;
+    #[test]
+    fn setup_put_get_delete() {
+        let (aws, _, _) = fake_aws();
+        assert_eq!(ErrorKind::Config, aws.list().err().unwrap().kind);
+        assert_eq!("Created Dynamo table. Created customer master key.", aws.setup().ok().unwrap());
+        assert_eq!("Dynamo table already existed. Customer master key already existed.", aws.setup().ok().unwrap());
+
+        assert_eq!(PutResult::Stored, aws.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(PutResult::DidNotOverwrite, aws.put("db.password", b"other", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(PutResult::Stored, aws.put("db.password", b"newer", true, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(b"newer", aws.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(b"secret", aws.get_version("db.password", 1).ok().unwrap().as_bytes());
+        assert_eq!(vec![1, 2], aws.history("db.password").ok().unwrap().iter().map(|v| v.version).collect::<Vec<u64>>());
+
+        assert_eq!(RollbackResult::RolledBack(3), aws.rollback("db.password", 1).ok().unwrap());
+        assert_eq!(b"secret", aws.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(RollbackResult::NotFound, aws.rollback("db.password", 7).ok().unwrap());
+
+        let description = aws.describe("db.password").ok().unwrap();
+        assert_eq!(3, description.version);
+        assert_eq!(Some("arn:aws:iam::123456789012:user/chris".to_string()), description.metadata.author);
+        assert_eq!(Some("arn:aws:iam::123456789012:user/chris".to_string()), aws.caller_identity().ok().unwrap());
+        let (value, metadata) = aws.get_with_metadata("db.password", None).ok().unwrap();
+        assert_eq!(b"secret", value.as_bytes());
+        assert_eq!(description.metadata, metadata);
 
-        match encrypt(value.as_slice(), 
-                       data_key.plaintext.as_slice(), 
-                       iv.as_slice()) {
-            Ok(ciphertext) => Ok(Item {
-                encrypted_data_key: data_key.encrypted,
-                encrypted_data: ciphertext,
-                iv: iv
-            }),
-            Err(_) => Err(MoroccoError { message: "Failed to encrypt secret.".to_string() })
+        assert_eq!(DeletionResult::Deleted, aws.delete("db.password").ok().unwrap());
+        assert_eq!(DeletionResult::NotFound, aws.delete("db.password").ok().unwrap());
+        assert_eq!(ErrorKind::NotFound, aws.get("db.password").err().unwrap().kind);
+        assert_eq!(ErrorKind::NotFound, aws.get_version("db.password", 1).err().unwrap().kind);
+        assert!(aws.history("db.password").ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rollback_restores_the_expiry_date() {
+        let (aws, _, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        let expiring = MetadataUpdate {
+            description: Some("Payments DB".to_string()),
+            expires_at: Some("2017-09-01T00:00:00+00:00".to_string()),
+            ..Default::default()
+        };
+        aws.put("db.password", b"one", false, &expiring).ok().unwrap();
+        aws.put("db.password", b"two", true, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(None, aws.describe("db.password").ok().unwrap().metadata.expires_at);
+
+        aws.rollback("db.password", 1).ok().unwrap();
+        let (value, metadata) = aws.get_with_metadata("db.password", None).ok().unwrap();
+        assert_eq!(b"one", value.as_bytes());
+        assert_eq!(Some("Payments DB".to_string()), metadata.description);
+        assert_eq!(Some("2017-09-01T00:00:00+00:00".to_string()), metadata.expires_at);
+    }
+
+    #[test]
+    fn empty_description_and_tags_are_left_out() {
+        let (aws, dynamo, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        let mut tags = BTreeMap::new();
+        tags.insert("owner".to_string(), "".to_string());
+        tags.insert("team".to_string(), "payments".to_string());
+        let update = MetadataUpdate { description: Some("".to_string()), tags: Some(tags), expires_at: None };
+        aws.put("db.password", b"secret", false, &update).ok().unwrap();
+
+        // DynamoDB would reject the item if it had empty strings
+        let item = dynamo.item("morocco", "db.password", 1).unwrap();
+        assert!(!item.contains_key("description"));
+        assert_eq!(vec!["team"], item["tags"].m.as_ref().unwrap().keys().collect::<Vec<&String>>());
+    }
+
+    #[test]
+    fn can_be_moved_to_another_thread() {
+        let (aws, _, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        let worker = thread::spawn(move || {
+            aws.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+            aws.get("db.password").ok().unwrap()
+        });
+        assert_eq!(b"secret", worker.join().unwrap().as_bytes());
+    }
+
+    #[test]
+    fn listing_follows_pagination() {
+        let (aws, _, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        for id in &["app.prod.a", "app.prod.b", "app.test.a", "other"] {
+            aws.put(id, id.as_bytes(), false, &MetadataUpdate::default()).ok().unwrap();
+            aws.put(id, format!("{} v2", id).as_bytes(), true, &MetadataUpdate::default()).ok().unwrap();
         }
+        assert_eq!(vec!["app.prod.a", "app.prod.b", "app.test.a", "other"], aws.list().ok().unwrap());
+
+        let mut streamed = Vec::new();
+        aws.list_streaming(Some(3), &mut |id| streamed.push(id)).ok().unwrap();
+        assert_eq!(vec!["app.prod.a", "app.prod.b", "app.test.a"], streamed);
+
+        let all = aws.get_all(Some("app.prod.")).ok().unwrap();
+        assert_eq!(vec!["app.prod.a", "app.prod.b"], all.keys().cloned().collect::<Vec<String>>());
+        assert_eq!(b"app.prod.b v2", all["app.prod.b"].as_bytes());
+        assert_eq!(4, aws.describe_all().ok().unwrap().len());
+    }
+
+    #[test]
+    fn data_key_is_bound_to_secret_id() {
+        let (aws, dynamo, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        aws.put("a", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+        aws.put("b", b"other", false, &MetadataUpdate::default()).ok().unwrap();
+
+        // copy a's encrypted data key and data onto b
+        let mut copied = dynamo.item("morocco", "a", 1).unwrap();
+        copied.insert("id".to_string(), dynamo.item("morocco", "b", 1).unwrap()["id"].clone());
+        dynamo.set_item("morocco", copied);
+        assert_eq!(ErrorKind::IntegrityFailure, aws.get("b").err().unwrap().kind);
+    }
+
+    #[test]
+    fn reencrypt_moves_versions_to_the_current_master_key() {
+        let (aws, dynamo, kms) = fake_aws();
+        aws.setup().ok().unwrap();
+        aws.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(ReencryptResult::AlreadyCurrent, aws.reencrypt("db.password", 1, false, false).ok().unwrap());
+
+        kms.rotate("alias/morocco");
+        // the ARN of the master key is cached, so start again as a new process would
+        let aws = AWS { kms_ops: KmsOps::new("morocco".to_string(), Box::new(kms.clone())), ..aws };
+        let before = dynamo.item("morocco", "db.password", 1).unwrap();
+        assert_eq!(ReencryptResult::WouldReencrypt, aws.reencrypt("db.password", 1, false, true).ok().unwrap());
+        assert_eq!(before["encrypted_data_key"].s, dynamo.item("morocco", "db.password", 1).unwrap()["encrypted_data_key"].s);
+
+        assert_eq!(ReencryptResult::Reencrypted, aws.reencrypt("db.password", 1, false, false).ok().unwrap());
+        assert_eq!(ReencryptResult::AlreadyCurrent, aws.reencrypt("db.password", 1, false, false).ok().unwrap());
+        assert_eq!(b"secret", aws.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(ErrorKind::NotFound, aws.reencrypt("other", 1, false, false).err().unwrap().kind);
     }
 
-    fn decrypt_item(&self, item: Item) -> Result<Vec<u8>, MoroccoError> {
-        let plaintext_key = self.kms_ops.decrypt_data_key(item.encrypted_data_key)?;
-        match decrypt(item.encrypted_data.as_slice(), 
-                      plaintext_key.as_slice(), 
-                      item.iv.as_slice()) {
-            Ok(plaintext_data) => Ok(plaintext_data),
-            Err(_) => Err(MoroccoError { message: "Failed to decrypt secret".to_string() })
+    #[test]
+    fn reencrypt_dry_run_and_current_versions() {
+        let (aws, dynamo, _) = fake_aws();
+        aws.setup().ok().unwrap();
+        aws.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+        let before = dynamo.item("morocco", "db.password", 1).unwrap();
+
+        // a version that is already under the current master key is skipped unless forced, and a dry run changes nothing
+        assert_eq!(ReencryptResult::AlreadyCurrent, aws.reencrypt("db.password", 1, false, true).ok().unwrap());
+        assert_eq!(ReencryptResult::WouldReencrypt, aws.reencrypt("db.password", 1, true, true).ok().unwrap());
+        assert_eq!(before, dynamo.item("morocco", "db.password", 1).unwrap());
+
+        // forcing it generates a new data key under the same master key
+        assert_eq!(ReencryptResult::Reencrypted, aws.reencrypt("db.password", 1, true, false).ok().unwrap());
+        assert!(before["encrypted_data_key"].s != dynamo.item("morocco", "db.password", 1).unwrap()["encrypted_data_key"].s);
+        assert_eq!(b"secret", aws.get("db.password").ok().unwrap().as_bytes());
+    }
+
+    #[test]
+    fn reencrypt_fails_if_the_version_changes_meanwhile() {
+        let (aws, dynamo, kms) = fake_aws();
+        aws.setup().ok().unwrap();
+        aws.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+
+        // another process re-encrypts the same version between our read and our write
+        let other = fake_region("eu-west-1", &dynamo, &kms);
+        let (other_dynamo, written_by_other) = (dynamo.clone(), Arc::new(Mutex::new(None)));
+        let written = written_by_other.clone();
+        dynamo.before_next_update(move || {
+            other.reencrypt("db.password", 1, true, false).ok().unwrap();
+            *written.lock().unwrap() = other_dynamo.item("morocco", "db.password", 1);
+        });
+        assert_eq!(ErrorKind::Conflict, aws.reencrypt("db.password", 1, true, false).err().unwrap().kind);
+
+        // the other process's write is kept, rather than replaced with a data key it doesn't know about
+        assert_eq!(*written_by_other.lock().unwrap(), dynamo.item("morocco", "db.password", 1));
+        assert_eq!(b"secret", aws.get("db.password").ok().unwrap().as_bytes());
+    }
+
+    #[test]
+    fn migrates_secrets_from_a_table_without_versions() {
+        let (aws, dynamo, kms) = fake_aws();
+        aws.setup().ok().unwrap();
+        let create_legacy_table = CreateTableInput {
+            table_name: "legacy".to_string(),
+            key_schema: vec![KeySchemaElement { attribute_name: "id".to_string(), key_type: "HASH".to_string() }],
+            ..Default::default()
+        };
+        dynamo.create_table(&create_legacy_table).ok().unwrap();
+        // as stored by older versions of morocco, without a version, cipher, HMAC or encryption context
+        for &(id, value) in &[("a", "one"), ("b", "two"), ("c", "three")] {
+            let request = GenerateDataKeyRequest { key_id: "alias/morocco".to_string(), number_of_bytes: Some(32), ..Default::default() };
+            let data_key = kms.generate_data_key(&request).ok().unwrap();
+            let iv = aws.kms_ops.generate_iv(Cipher::AesCbc.iv_length()).ok().unwrap();
+            let ciphertext = encrypt(Cipher::AesCbc, value.as_bytes(), &data_key.plaintext.unwrap(), &iv).ok().unwrap();
+            let string = |bytes: &[u8]| AttributeValue { s: Some(base64::encode(bytes)), ..Default::default() };
+            dynamo.set_item("legacy", vec![
+                ("id".to_string(), AttributeValue { s: Some(id.to_string()), ..Default::default() }),
+                ("encrypted_data_key".to_string(), string(&data_key.ciphertext_blob.unwrap())),
+                ("encrypted_data".to_string(), string(&ciphertext)),
+                ("iv".to_string(), string(&iv))
+            ].into_iter().collect());
         }
+        let legacy = AWS { dynamo_ops: DynamoOps::new("legacy".to_string(), Box::new(dynamo.clone())), ..fake_region("eu-west-1", &dynamo, &kms) };
+        assert_eq!(ErrorKind::Config, legacy.setup().err().unwrap().kind);
+
+        let migrate = |from| {
+            let mut results = Vec::new();
+            aws.migrate_from(from, &mut |id, result| results.push(format!("{} {:?}", id, result))).map(|_| results)
+        };
+        assert_eq!(vec!["a Stored", "b Stored", "c Stored"], migrate("legacy").ok().unwrap());
+        assert_eq!(b"two", aws.get("b").ok().unwrap().as_bytes());
+        assert_eq!(vec![1], aws.history("c").ok().unwrap().iter().map(|v| v.version).collect::<Vec<u64>>());
+        // so an interrupted migration can be run again
+        assert_eq!(vec!["a DidNotOverwrite", "b DidNotOverwrite", "c DidNotOverwrite"], migrate("legacy").ok().unwrap());
+
+        assert_eq!(ErrorKind::Config, migrate("morocco").err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, migrate("missing").err().unwrap().kind);
+    }
+
+    #[test]
+    fn replicates_changes_and_syncs_regions() {
+        let (dynamo, kms) = (FakeDynamoDb::new(2), FakeKms::new("eu-west-1"));
+        let (replica_dynamo, replica_kms) = (FakeDynamoDb::new(2), FakeKms::new("us-east-1"));
+        let aws = AWS { replicas: vec![fake_region("us-east-1", &replica_dynamo, &replica_kms)], ..fake_region("eu-west-1", &dynamo, &kms) };
+        // the same tables and keys, without replication
+        let (primary, replica) = (fake_region("eu-west-1", &dynamo, &kms), fake_region("us-east-1", &replica_dynamo, &replica_kms));
+
+        assert_eq!("eu-west-1: Created Dynamo table. Created customer master key.\nus-east-1: Created Dynamo table. Created customer master key.",
+                   aws.setup().ok().unwrap());
+        aws.put("a", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+        aws.put("a", b"newer", true, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(b"newer", replica.get("a").ok().unwrap().as_bytes());
+        let versions = |aws: &AWS| aws.history("a").ok().unwrap().into_iter().map(|v| (v.version, v.created_at)).collect::<Vec<(u64, String)>>();
+        assert_eq!(versions(&primary), versions(&replica));
+        // each region's copy is encrypted under its own master key
+        assert!(dynamo.item("morocco", "a", 2).unwrap()["encrypted_data_key"] != replica_dynamo.item("morocco", "a", 2).unwrap()["encrypted_data_key"]);
+
+        aws.rollback("a", 1).ok().unwrap();
+        assert_eq!(b"secret", replica.get("a").ok().unwrap().as_bytes());
+        assert_eq!(DeletionResult::Deleted, aws.delete("a").ok().unwrap());
+        assert_eq!(ErrorKind::NotFound, replica.get("a").err().unwrap().kind);
+
+        // changes made while replication was not in use
+        primary.put("missing", b"one", false, &MetadataUpdate::default()).ok().unwrap();
+        replica.put("stale", b"two", false, &MetadataUpdate::default()).ok().unwrap();
+        primary.put("diverged", b"three", false, &MetadataUpdate::default()).ok().unwrap();
+        replica.put("diverged", b"four", false, &MetadataUpdate::default()).ok().unwrap();
+        aws.put("same", b"five", false, &MetadataUpdate::default()).ok().unwrap();
+
+        let sync = |dry_run, delete_extra| {
+            let mut results = Vec::new();
+            aws.sync_regions(None, dry_run, delete_extra, &mut |region, id, result| results.push((region.to_string(), id.to_string(), result.ok().unwrap()))).ok().unwrap();
+            results.into_iter().map(|(region, id, result)| format!("{} {} {:?}", region, id, result)).collect::<Vec<String>>()
+        };
+        let expected = vec!["us-east-1 diverged Replaced", "us-east-1 missing Copied(1)", "us-east-1 same InSync", "us-east-1 stale OnlyInReplica"];
+        assert_eq!(expected, sync(true, false));
+        assert_eq!(ErrorKind::NotFound, replica.get("missing").err().unwrap().kind);
+        assert_eq!(expected, sync(false, false));
+        assert_eq!(b"one", replica.get("missing").ok().unwrap().as_bytes());
+        assert_eq!(b"three", replica.get("diverged").ok().unwrap().as_bytes());
+        assert_eq!(b"two", replica.get("stale").ok().unwrap().as_bytes());
+
+        assert_eq!(vec!["us-east-1 diverged InSync", "us-east-1 missing InSync", "us-east-1 same InSync", "us-east-1 stale Deleted"], sync(true, true));
+        assert_eq!(b"two", replica.get("stale").ok().unwrap().as_bytes());
+        sync(false, true);
+        assert_eq!(ErrorKind::NotFound, replica.get("stale").err().unwrap().kind);
+        assert!(sync(false, true).iter().all(|line| line.ends_with("InSync")));
+
+        assert_eq!(ErrorKind::Config, primary.sync_regions(None, false, false, &mut |_, _, _| ()).err().unwrap().kind);
     }
 
+    #[test]
+    fn sync_writes_the_replacement_versions_before_removing_stale_ones() {
+        let (dynamo, kms) = (FakeDynamoDb::new(2), FakeKms::new("eu-west-1"));
+        let (replica_dynamo, replica_kms) = (FakeDynamoDb::new(2), FakeKms::new("us-east-1"));
+        let aws = AWS { replicas: vec![fake_region("us-east-1", &replica_dynamo, &replica_kms)], ..fake_region("eu-west-1", &dynamo, &kms) };
+        let (primary, replica) = (fake_region("eu-west-1", &dynamo, &kms), fake_region("us-east-1", &replica_dynamo, &replica_kms));
+        aws.setup().ok().unwrap();
+        primary.put("a", b"one", false, &MetadataUpdate::default()).ok().unwrap();
+        for value in &[&b"two"[..], b"three", b"four"] {
+            replica.put("a", value, true, &MetadataUpdate::default()).ok().unwrap();
+        }
+
+        // the replica's versions 2 and 3 can't be deleted, so its version 1 must already have been replaced
+        replica_dynamo.fail_deletes(true);
+        let mut results = Vec::new();
+        aws.sync_regions(None, false, false, &mut |_, _, result| results.push(result.map_err(|e| e.kind))).ok().unwrap();
+        assert_eq!(vec![Err(ErrorKind::Unavailable)], results);
+        assert_eq!(b"one", replica.get_version("a", 1).ok().unwrap().as_bytes());
+        assert_eq!(b"four", replica.get("a").ok().unwrap().as_bytes());
+
+        replica_dynamo.fail_deletes(false);
+        let mut results = Vec::new();
+        aws.sync_regions(None, false, false, &mut |_, _, result| results.push(result.ok().unwrap())).ok().unwrap();
+        assert_eq!(vec![SyncResult::Replaced], results);
+        assert_eq!(b"one", replica.get("a").ok().unwrap().as_bytes());
+        assert_eq!(vec![1], replica.history("a").ok().unwrap().into_iter().map(|v| v.version).collect::<Vec<u64>>());
+    }
 }
@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+// The settings in the AWS CLI's config file, ~/.aws/config, by profile name
+pub struct AwsConfigFile {
+    profiles: HashMap<String, HashMap<String, String>>
+}
+
+impl AwsConfigFile {
+
+    // A missing or unreadable file is treated as empty, as the AWS CLI does
+    pub fn load(env: &BTreeMap<String, String>) -> AwsConfigFile {
+        let path = env.get("AWS_CONFIG_FILE").map(PathBuf::from)
+            .or(env::home_dir().map(|home| home.join(".aws").join("config")));
+        let mut contents = String::new();
+        let read = path.and_then(|p| File::open(p).ok())
+            .map_or(false, |mut file| file.read_to_string(&mut contents).is_ok());
+        if read {
+            AwsConfigFile::parse(&contents)
+        } else {
+            AwsConfigFile { profiles: HashMap::new() }
+        }
+    }
+
+    // The file is INI-style, with sections called [default] and [profile <name>]
+    pub fn parse(contents: &str) -> AwsConfigFile {
+        let mut profiles = HashMap::new();
+        let mut current: Option<String> = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let section = line[1..line.len() - 1].trim();
+                current = if section == "default" {
+                    Some(section.to_string())
+                } else if section.starts_with("profile ") {
+                    Some(section["profile ".len()..].trim().to_string())
+                } else {
+                    None
+                };
+            } else if let (Some(ref profile), Some(eq)) = (current.as_ref(), line.find('=')) {
+                profiles.entry(profile.to_string())
+                    .or_insert(HashMap::new())
+                    .insert(line[..eq].trim().to_string(), line[eq + 1..].trim().to_string());
+            }
+        }
+        AwsConfigFile { profiles }
+    }
+
+    pub fn get(&self, profile: &str, key: &str) -> Option<&str> {
+        self.profiles.get(profile).and_then(|settings| settings.get(key)).map(|value| value.as_str())
+    }
+
+}
+
+// The AWS_* environment variables, which are passed around rather than read where they're used
+// so that the lookups below can be tested
+pub fn aws_env() -> BTreeMap<String, String> {
+    env::vars().filter(|&(ref name, _)| name.starts_with("AWS_")).collect()
+}
+
+// The profile to use: the one given by --profile, or else AWS_PROFILE, AWS_DEFAULT_PROFILE or "default"
+pub fn selected_profile(profile: Option<String>, env: &BTreeMap<String, String>) -> String {
+    profile
+        .or(env.get("AWS_PROFILE").cloned())
+        .or(env.get("AWS_DEFAULT_PROFILE").cloned())
+        .unwrap_or("default".to_string())
+}
+
+// Find the region the same way as the AWS CLI: the --region option (or Morocco's own config),
+// then AWS_REGION, AWS_DEFAULT_REGION, and finally the region of the profile in ~/.aws/config
+pub fn resolve_region(region: Option<String>, profile: &str, config_file: &AwsConfigFile, env: &BTreeMap<String, String>) -> Option<String> {
+    region
+        .or(env.get("AWS_REGION").cloned())
+        .or(env.get("AWS_DEFAULT_REGION").cloned())
+        .or(config_file.get(profile, "region").map(|r| r.to_string()))
+        .filter(|r| !r.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profiles() {
+        let config_file = AwsConfigFile::parse("[default]\nregion = eu-west-1\n\n# comment\n[profile prod]\nregion=us-east-1\noutput = json\n[sso-session x]\nregion = ap-south-1\n");
+        assert_eq!(Some("eu-west-1"), config_file.get("default", "region"));
+        assert_eq!(Some("us-east-1"), config_file.get("prod", "region"));
+        assert_eq!(None, config_file.get("x", "region"));
+    }
+
+    fn env(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn region_precedence() {
+        let config_file = AwsConfigFile::parse("[default]\nregion = eu-west-1\n[profile prod]\nregion = us-east-1\n[profile blank]\nregion =\n");
+        let both = env(&[("AWS_REGION", "ap-south-1"), ("AWS_DEFAULT_REGION", "sa-east-1")]);
+        let region = |option: Option<&str>, profile: &str, env: &BTreeMap<String, String>|
+            resolve_region(option.map(|r| r.to_string()), profile, &config_file, env);
+        assert_eq!(Some("eu-central-1".to_string()), region(Some("eu-central-1"), "prod", &both));
+        assert_eq!(Some("ap-south-1".to_string()), region(None, "prod", &both));
+        assert_eq!(Some("sa-east-1".to_string()), region(None, "prod", &env(&[("AWS_DEFAULT_REGION", "sa-east-1")])));
+        assert_eq!(Some("us-east-1".to_string()), region(None, "prod", &env(&[])));
+        assert_eq!(Some("eu-west-1".to_string()), region(None, "default", &env(&[])));
+        assert_eq!(None, region(None, "missing", &env(&[])));
+        assert_eq!(None, region(None, "blank", &env(&[])));
+    }
+
+    #[test]
+    fn profile_precedence() {
+        let both = env(&[("AWS_PROFILE", "prod"), ("AWS_DEFAULT_PROFILE", "dev")]);
+        assert_eq!("flag", selected_profile(Some("flag".to_string()), &both));
+        assert_eq!("prod", selected_profile(None, &both));
+        assert_eq!("dev", selected_profile(None, &env(&[("AWS_DEFAULT_PROFILE", "dev")])));
+        assert_eq!("default", selected_profile(None, &env(&[])));
+    }
+}
@@ -0,0 +1,567 @@
+extern crate crypto;
+extern crate rand;
+extern crate base64;
+extern crate chrono;
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use self::crypto::digest::Digest;
+use self::crypto::sha2::Sha256;
+use self::rand::{Rng, OsRng};
+use self::base64::{encode, decode};
+use self::chrono::{DateTime, Duration, Utc};
+
+use morocco::*;
+use encryption::*;
+use files::{create_private_file, create_new_private_file};
+
+const KEY_LENGTH: usize = 32;
+
+// A cache file as stored on disk. The entries are encrypted using AES-256-GCM with a random key
+// that is kept outside the cache directory, so that a copy or backup of the directory reveals nothing.
+// Anyone who can read both files can read the secrets, though.
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    nonce: String,
+    ciphertext: String
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+    // what was asked for, e.g. "latest db.password", "version 2 db.password" or "all app.prod."
+    request: String,
+    // for getall, the prefix of the IDs that were asked for
+    prefix: Option<String>,
+    cached_at: String,
+    // the secrets that were returned, base64 encoded in the file
+    #[serde(with = "secret_values")]
+    values: BTreeMap<String, Secret>,
+    // for a single secret read along with its metadata, the metadata,
+    // so that its expiry date can be checked without asking the backend
+    #[serde(default)]
+    metadata: Option<Metadata>
+}
+
+impl Entry {
+
+    fn is_fresh(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        match DateTime::parse_from_rfc3339(&self.cached_at) {
+            Ok(cached_at) => now.signed_duration_since(cached_at.with_timezone(&Utc)) < ttl,
+            Err(_) => false
+        }
+    }
+
+    // Whether a change to the given secret could make this entry out of date
+    fn involves(&self, id: &str) -> bool {
+        self.values.contains_key(id) || self.prefix.as_ref().map_or(false, |p| id.starts_with(p.as_str()))
+    }
+
+}
+
+// A read-through cache in front of another backend, so that reading the same secrets over and over,
+// e.g. when a fleet of processes restarts, doesn't cost a KMS request every time.
+// Entries expire after the TTL. Changes made through the cache remove the entries they affect,
+// but changes made elsewhere are only seen once the entries have expired.
+pub struct CachedMorocco<M: Morocco> {
+    inner: M,
+    ttl: Duration,
+    // the cache file and the file holding its key, or None to keep the entries in memory
+    files: Option<(PathBuf, PathBuf)>,
+    entries: RefCell<Vec<Entry>>,
+    // told when the cache file can't be written, which is otherwise ignored
+    warning_callback: Option<Box<Fn(&str) + Send>>
+}
+
+impl<M: Morocco> Morocco for CachedMorocco<M> {
+
+    fn setup(&self) -> Result<String, MoroccoError> {
+        self.inner.setup()
+    }
+
+    fn list(&self) -> Result<Vec<String>, MoroccoError> {
+        self.inner.list()
+    }
+
+    fn list_streaming(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        self.inner.list_streaming(limit, callback)
+    }
+
+    fn get(&self, id: &str) -> Result<Secret, MoroccoError> {
+        let request = format!("latest {}", id);
+        if let Some((mut values, _)) = self.lookup(&request) {
+            if let Some(value) = values.remove(id) {
+                return Ok(value);
+            }
+        }
+        let value = self.inner.get(id)?;
+        self.store(request, None, Some((id, &value)).into_iter(), None);
+        Ok(value)
+    }
+
+    fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError> {
+        let request = format!("version {} {}", version, id);
+        if let Some((mut values, _)) = self.lookup(&request) {
+            if let Some(value) = values.remove(id) {
+                return Ok(value);
+            }
+        }
+        let value = self.inner.get_version(id, version)?;
+        self.store(request, None, Some((id, &value)).into_iter(), None);
+        Ok(value)
+    }
+
+    // Shares its entries with get and get_version, but only uses those that were stored with metadata
+    fn get_with_metadata(&self, id: &str, version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+        let request = match version {
+            Some(version) => format!("version {} {}", version, id),
+            None => format!("latest {}", id)
+        };
+        if let Some((mut values, Some(metadata))) = self.lookup(&request) {
+            if let Some(value) = values.remove(id) {
+                return Ok((value, metadata));
+            }
+        }
+        let (value, metadata) = self.inner.get_with_metadata(id, version)?;
+        self.store(request, None, Some((id, &value)).into_iter(), Some(&metadata));
+        Ok((value, metadata))
+    }
+
+    fn get_all(&self, prefix: Option<&str>) -> Result<BTreeMap<String, Secret>, MoroccoError> {
+        let request = format!("all {}", prefix.unwrap_or(""));
+        if let Some((values, _)) = self.lookup(&request) {
+            return Ok(values);
+        }
+        let values = self.inner.get_all(prefix)?;
+        self.store(request, Some(prefix.unwrap_or("")), values.iter().map(|(id, value)| (id.as_str(), value)), None);
+        Ok(values)
+    }
+
+    // Every change to a secret drops its entries, even if it failed, as it may have been partly made
+    fn put(&self, id: &str, value: &[u8], overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError> {
+        let result = self.inner.put(id, value, overwrite, metadata);
+        self.invalidate(id);
+        result
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<VersionInfo>, MoroccoError> {
+        self.inner.history(id)
+    }
+
+    fn describe(&self, id: &str) -> Result<Description, MoroccoError> {
+        self.inner.describe(id)
+    }
+
+    fn describe_all(&self) -> Result<Vec<Description>, MoroccoError> {
+        self.inner.describe_all()
+    }
+
+    fn rollback(&self, id: &str, version: u64) -> Result<RollbackResult, MoroccoError> {
+        let result = self.inner.rollback(id, version);
+        self.invalidate(id);
+        result
+    }
+
+    fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError> {
+        let result = self.inner.delete(id);
+        self.invalidate(id);
+        result
+    }
+
+    fn reencrypt(&self, id: &str, version: u64, force: bool, dry_run: bool) -> Result<ReencryptResult, MoroccoError> {
+        let result = self.inner.reencrypt(id, version, force, dry_run);
+        self.invalidate(id);
+        result
+    }
+
+    fn caller_identity(&self) -> Result<Option<String>, MoroccoError> {
+        self.inner.caller_identity()
+    }
+
+}
+
+impl<M: Morocco> CachedMorocco<M> {
+
+    // Keep the entries in memory, e.g. in a long-running process
+    pub fn in_memory(inner: M, ttl_seconds: u64) -> CachedMorocco<M> {
+        CachedMorocco {
+            inner: inner,
+            ttl: Duration::seconds(ttl_seconds as i64),
+            files: None,
+            entries: RefCell::new(Vec::new()),
+            warning_callback: None
+        }
+    }
+
+    // Keep the entries in an encrypted file in the given directory, so that processes can share them.
+    // The key is read from key_path, or created there if it doesn't exist, and shouldn't be in the directory.
+    // The namespace should identify the store and the settings used to read from it, e.g. the table,
+    // region and encryption context, as each namespace gets a file of its own.
+    pub fn on_disk(inner: M, ttl_seconds: u64, dir: PathBuf, key_path: PathBuf, namespace: &str) -> CachedMorocco<M> {
+        let mut hash = Sha256::new();
+        hash.input_str(namespace);
+        let path = dir.join(format!("{}.json", hash.result_str()));
+        CachedMorocco {
+            files: Some((path, key_path)),
+            ..CachedMorocco::in_memory(inner, ttl_seconds)
+        }
+    }
+
+    // Called with problems that don't stop the operation, such as failing to write the cache file
+    pub fn warning_callback<F>(mut self, callback: F) -> CachedMorocco<M> where F: Fn(&str) + Send + 'static {
+        self.warning_callback = Some(Box::new(callback));
+        self
+    }
+
+    fn lookup(&self, request: &str) -> Option<(BTreeMap<String, Secret>, Option<Metadata>)> {
+        let now = Utc::now();
+        let entry = self.load().into_iter().find(|e| e.request == request && e.is_fresh(self.ttl, now))?;
+        Some((entry.values, entry.metadata))
+    }
+
+    fn store<'a, I: Iterator<Item = (&'a str, &'a Secret)>>(&self, request: String, prefix: Option<&str>, values: I,
+                                                           metadata: Option<&Metadata>) {
+        let now = Utc::now();
+        let mut entries = self.load();
+        entries.retain(|e| e.request != request && e.is_fresh(self.ttl, now));
+        entries.push(Entry {
+            request: request,
+            prefix: prefix.map(|p| p.to_string()),
+            cached_at: now.to_rfc3339(),
+            values: values.map(|(id, value)| (id.to_string(), value.clone())).collect(),
+            metadata: metadata.cloned()
+        });
+        self.save(entries);
+    }
+
+    fn invalidate(&self, id: &str) {
+        let mut entries = self.load();
+        let before = entries.len();
+        entries.retain(|e| !e.involves(id));
+        if entries.len() != before {
+            self.save(entries);
+        }
+    }
+
+    // A missing, unreadable or corrupt cache file is treated as empty
+    fn load(&self) -> Vec<Entry> {
+        match self.files {
+            Some((ref path, ref key_path)) => read_cache_file(path, key_path).unwrap_or(Vec::new()),
+            None => self.entries.borrow().clone()
+        }
+    }
+
+    // Failing to write the cache only means that the secrets will be read from the backend again next time
+    fn save(&self, entries: Vec<Entry>) {
+        match self.files {
+            Some((ref path, ref key_path)) => {
+                if let (Err(e), Some(warning)) = (write_cache_file(path, key_path, &entries), self.warning_callback.as_ref()) {
+                    warning(&format!("Could not cache secrets in {}: {}", path.display(), e));
+                }
+            },
+            None => *self.entries.borrow_mut() = entries
+        }
+    }
+
+}
+
+fn read_cache_file(path: &PathBuf, key_path: &PathBuf) -> Option<Vec<Entry>> {
+    let mut json = String::new();
+    File::open(path).ok()?.read_to_string(&mut json).ok()?;
+    let file: CacheFile = serde_json::from_str(&json).ok()?;
+    let key = read_key(key_path)?;
+    let plaintext = decrypt(Cipher::AesGcm, &decode(&file.ciphertext).ok()?, key.as_bytes(), &decode(&file.nonce).ok()?).ok()?;
+    serde_json::from_slice(plaintext.as_bytes()).ok()
+}
+
+fn write_cache_file(path: &PathBuf, key_path: &PathBuf, entries: &[Entry]) -> Result<(), MoroccoError> {
+    let key = match read_key(key_path) {
+        Some(key) => key,
+        None => create_key(key_path)?
+    };
+    let mut plaintext = SecretWriter(Secret::new(Vec::new()));
+    serde_json::to_writer(&mut plaintext, entries)?;
+    let plaintext = plaintext.0;
+    let mut nonce = vec![0; Cipher::AesGcm.iv_length()];
+    OsRng::new()?.fill_bytes(&mut nonce);
+    let ciphertext = match encrypt(Cipher::AesGcm, plaintext.as_bytes(), key.as_bytes(), &nonce) {
+        Ok(ciphertext) => ciphertext,
+        Err(_) => return Err(MoroccoError::new(ErrorKind::Backend, "Failed to encrypt the cache.".to_string()))
+    };
+    let file = CacheFile {
+        nonce: encode(&nonce),
+        ciphertext: encode(&ciphertext)
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Other processes may be reading the cache, so write it to a temporary file and rename it into place
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(format!(".{}.tmp", OsRng::new()?.next_u32()));
+    {
+        let mut tmp_file = create_private_file(&PathBuf::from(&tmp_path))?;
+        tmp_file.write_all(serde_json::to_string(&file)?.as_bytes())?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn read_key(key_path: &PathBuf) -> Option<Secret> {
+    let key = Secret::read_from(&mut File::open(key_path).ok()?, KEY_LENGTH).ok()?;
+    if key.len() == KEY_LENGTH { Some(key) } else { None }
+}
+
+// Another process may create the key at the same time, in which case its key is used,
+// as replacing it would make the cache it has written unreadable
+fn create_key(key_path: &PathBuf) -> Result<Secret, MoroccoError> {
+    if let Some(dir) = key_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut key = Secret::new(vec![0; KEY_LENGTH]);
+    OsRng::new()?.fill_bytes(key.as_mut_bytes());
+    match create_new_private_file(key_path) {
+        Ok(mut file) => {
+            file.write_all(key.as_bytes())?;
+            Ok(key)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => read_key(key_path).ok_or_else(|| MoroccoError::new(
+                ErrorKind::Io, format!("The cache key in {} is not valid. Delete it to create a new one.", key_path.display()))),
+        Err(e) => Err(e.into())
+    }
+}
+
+// Collects the JSON for the cache file in memory that is zeroed, including the buffers it outgrows
+struct SecretWriter(Secret);
+
+impl Write for SecretWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Entries keep the secrets they hold as Secrets, and the JSON has them as base64 strings
+mod secret_values {
+    use std::collections::BTreeMap;
+    use std::fmt;
+
+    use serde::{Serializer, Deserializer};
+    use serde::ser::{self, SerializeMap};
+    use serde::de::{self, MapAccess, Visitor};
+    use super::base64::{decode, encode_config_buf, STANDARD};
+
+    use morocco::Secret;
+
+    pub fn serialize<S: Serializer>(values: &BTreeMap<String, Secret>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(values.len()))?;
+        for (id, value) in values {
+            let mut encoded = String::new();
+            encode_config_buf(value.as_bytes(), STANDARD, &mut encoded);
+            let encoded = Secret::new(encoded.into_bytes());
+            map.serialize_entry(id, encoded.as_str().map_err(ser::Error::custom)?)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Secret>, D::Error> {
+        deserializer.deserialize_map(SecretValues)
+    }
+
+    struct SecretValues;
+
+    impl<'de> Visitor<'de> for SecretValues {
+        type Value = BTreeMap<String, Secret>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of secret IDs to base64 encoded values")
+        }
+
+        // the base64 is borrowed from the decrypted file, so the only copy of a value is the Secret
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut values = BTreeMap::new();
+            while let Some((id, encoded)) = map.next_entry::<String, &str>()? {
+                values.insert(id, Secret::new(decode(encoded).map_err(de::Error::custom)?));
+            }
+            Ok(values)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+
+    // Counts how many times it has been asked for anything
+    #[derive(Default)]
+    struct Counting {
+        secrets: RefCell<BTreeMap<String, (Vec<u8>, Metadata)>>,
+        calls: Cell<usize>
+    }
+
+    impl Counting {
+        fn called(&self) {
+            self.calls.set(self.calls.get() + 1);
+        }
+    }
+
+    impl Morocco for Counting {
+        fn setup(&self) -> Result<String, MoroccoError> { Ok(String::new()) }
+        fn list(&self) -> Result<Vec<String>, MoroccoError> {
+            self.called();
+            Ok(self.secrets.borrow().keys().cloned().collect())
+        }
+        fn get(&self, id: &str) -> Result<Secret, MoroccoError> {
+            self.get_with_metadata(id, None).map(|(value, _)| value)
+        }
+        fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError> {
+            self.get_with_metadata(id, Some(version)).map(|(value, _)| value)
+        }
+        fn get_with_metadata(&self, id: &str, _version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+            self.called();
+            match self.secrets.borrow().get(id) {
+                Some(&(ref value, ref metadata)) => Ok((Secret::new(value.clone()), metadata.clone())),
+                None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+            }
+        }
+        fn put(&self, id: &str, value: &[u8], _overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError> {
+            self.secrets.borrow_mut().insert(id.to_string(), (value.to_vec(), Metadata::default().updated(metadata, None)));
+            Ok(PutResult::Stored)
+        }
+        fn history(&self, _id: &str) -> Result<Vec<VersionInfo>, MoroccoError> {
+            self.called();
+            Ok(Vec::new())
+        }
+        fn rollback(&self, _id: &str, _version: u64) -> Result<RollbackResult, MoroccoError> { Ok(RollbackResult::NotFound) }
+        fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError> {
+            self.secrets.borrow_mut().remove(id);
+            Ok(DeletionResult::Deleted)
+        }
+    }
+
+    #[test]
+    fn cached_until_expired_or_changed() {
+        let cache = CachedMorocco::in_memory(Counting::default(), 60);
+        cache.put("app.a", b"one", false, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(b"one", cache.get("app.a").ok().unwrap().as_bytes());
+        assert_eq!(b"one", cache.get("app.a").ok().unwrap().as_bytes());
+        assert_eq!(1, cache.inner.calls.get());
+
+        assert_eq!(1, cache.get_all(Some("app.")).ok().unwrap().len());
+        assert_eq!(1, cache.get_all(Some("app.")).ok().unwrap().len());
+        // listing the secrets and reading the one found
+        assert_eq!(3, cache.inner.calls.get());
+
+        // a new secret under the prefix and a new value for a cached secret
+        cache.put("app.b", b"two", false, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(2, cache.get_all(Some("app.")).ok().unwrap().len());
+        cache.put("app.a", b"three", true, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(b"three", cache.get("app.a").ok().unwrap().as_bytes());
+        cache.delete("app.a").ok().unwrap();
+        assert_eq!(ErrorKind::NotFound, cache.get("app.a").err().unwrap().kind);
+
+        let uncached = CachedMorocco::in_memory(Counting::default(), 0);
+        uncached.put("app.a", b"one", false, &MetadataUpdate::default()).ok().unwrap();
+        uncached.get("app.a").ok().unwrap();
+        uncached.get("app.a").ok().unwrap();
+        assert_eq!(2, uncached.inner.calls.get());
+    }
+
+    #[test]
+    fn cached_reads_make_no_backend_calls() {
+        let cache = CachedMorocco::in_memory(Counting::default(), 60);
+        let update = MetadataUpdate { expires_at: Some("2030-01-01T00:00:00+00:00".to_string()), ..MetadataUpdate::default() };
+        cache.put("db.password", b"hunter2", false, &update).ok().unwrap();
+        let (value, metadata) = cache.get_with_metadata("db.password", None).ok().unwrap();
+        assert_eq!(b"hunter2", value.as_bytes());
+        assert_eq!(1, cache.inner.calls.get());
+
+        // the expiry date comes from the cache too, so checking it doesn't reach the backend
+        let (value, cached) = cache.get_with_metadata("db.password", None).ok().unwrap();
+        assert_eq!(b"hunter2", value.as_bytes());
+        assert_eq!(metadata, cached);
+        assert_eq!(Some("2030-01-01T00:00:00+00:00".to_string()), cached.expires_at);
+        assert_eq!(b"hunter2", cache.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(1, cache.inner.calls.get());
+
+        // an entry stored without metadata can't answer for it
+        cache.get_version("db.password", 1).ok().unwrap();
+        cache.get_with_metadata("db.password", Some(1)).ok().unwrap();
+        cache.get_with_metadata("db.password", Some(1)).ok().unwrap();
+        assert_eq!(3, cache.inner.calls.get());
+    }
+
+    #[test]
+    fn on_disk_cache_is_encrypted_and_shared() {
+        let base = env::temp_dir().join(format!("morocco-test-cache-{}", OsRng::new().unwrap().next_u64()));
+        let (dir, key_path) = (base.join("secrets"), base.join("key"));
+        let first = CachedMorocco::on_disk(Counting::default(), 60, dir.clone(), key_path.clone(), "aws\nregion=eu-west-1");
+        first.put("db.password", b"hunter2", false, &MetadataUpdate::default()).ok().unwrap();
+        first.get("db.password").ok().unwrap();
+
+        let mut contents = String::new();
+        File::open(&first.files.as_ref().unwrap().0).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(!contents.contains("hunter2") && !contents.contains(&encode(b"hunter2")));
+        // only the cache file is in the directory, and the key is somewhere else
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        assert!(key_path.exists());
+
+        // another process, using the same settings
+        let second = CachedMorocco::on_disk(Counting::default(), 60, dir.clone(), key_path.clone(), "aws\nregion=eu-west-1");
+        assert_eq!(b"hunter2", second.get("db.password").ok().unwrap().as_bytes());
+        let other_region = CachedMorocco::on_disk(Counting::default(), 60, dir.clone(), key_path.clone(), "aws\nregion=us-east-1");
+        assert_eq!(ErrorKind::NotFound, other_region.get("db.password").err().unwrap().kind);
+
+        // without the key, the cache can't be read
+        fs::remove_file(&key_path).unwrap();
+        let no_key = CachedMorocco::on_disk(Counting::default(), 60, dir.clone(), key_path.clone(), "aws\nregion=eu-west-1");
+        assert_eq!(ErrorKind::NotFound, no_key.get("db.password").err().unwrap().kind);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn failing_to_write_the_cache_is_only_a_warning() {
+        let base = env::temp_dir().join(format!("morocco-test-cache-{}", OsRng::new().unwrap().next_u64()));
+        // a file where the cache directory should be
+        File::create(&base).unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let reported = warnings.clone();
+        let cached = CachedMorocco::on_disk(Counting::default(), 60, base.join("secrets"), base.join("key"), "aws")
+            .warning_callback(move |warning| reported.lock().unwrap().push(warning.to_string()));
+        cached.put("db.password", b"hunter2", false, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(b"hunter2", cached.get("db.password").ok().unwrap().as_bytes());
+        assert!(warnings.lock().unwrap()[0].starts_with("Could not cache secrets in "));
+
+        fs::remove_file(&base).unwrap();
+    }
+
+    #[test]
+    fn a_key_created_by_another_process_is_not_replaced() {
+        let base = env::temp_dir().join(format!("morocco-test-cache-{}", OsRng::new().unwrap().next_u64()));
+        fs::create_dir_all(&base).unwrap();
+        let key_path = base.join("key");
+        // as if another process created the key between this one failing to read it and creating it
+        File::create(&key_path).unwrap().write_all(&[7; KEY_LENGTH]).unwrap();
+        assert_eq!(&[7; KEY_LENGTH][..], create_key(&key_path).ok().unwrap().as_bytes());
+        assert_eq!(&[7; KEY_LENGTH][..], read_key(&key_path).unwrap().as_bytes());
+
+        File::create(&key_path).unwrap().write_all(b"short").unwrap();
+        assert_eq!(ErrorKind::Io, create_key(&key_path).err().unwrap().kind);
+        assert_eq!(5, fs::metadata(&key_path).unwrap().len());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,444 @@
+use clap::{Arg, ArgGroup, App, AppSettings, SubCommand};
+
+pub fn build_cli() -> App<'static, 'static> {
+    App::new("morocco")
+        .version("0.1.0")
+        .about("Secure secret management in the cloud")
+        .arg(Arg::with_name("env")
+             .long("env")
+             .takes_value(true)
+             .value_name("NAME")
+             .help("use the settings of a named environment from the config files (default: $MOROCCO_ENV)"))
+        .subcommand(SubCommand::with_name("cache")
+                    .about("Manage the cache of secrets used with --cache-ttl")
+                    .subcommand(SubCommand::with_name("clear")
+                                .about("Remove every cached secret")))
+        .subcommand(secret_subcommands(with_cache_args(
+                    SubCommand::with_name("aws")
+                    .about("Use Amazon's Key Management Service for encryption and DynamoDB for storage")
+                    .arg(Arg::with_name("profile")
+                         .long("profile")
+                         .short("p")
+                         .takes_value(true)
+                         .help("use custom IAM profile"))
+                    .arg(Arg::with_name("region")
+                         .long("region")
+                         .short("r")
+                         .takes_value(true)
+                         .help("use custom AWS region (default: $AWS_REGION, $AWS_DEFAULT_REGION or the profile's region in ~/.aws/config)"))
+                    .arg(Arg::with_name("table")
+                         .long("table")
+                         .short("t")
+                         .takes_value(true)
+                         .help("use custom DynamoDB table (default: morocco)"))
+                    .arg(Arg::with_name("key-alias")
+                         .long("key-alias")
+                         .short("k")
+                         .takes_value(true)
+                         .help("use custom KMS customer master key (default: morocco)"))
+                    .arg(Arg::with_name("dynamodb-endpoint")
+                         .long("dynamodb-endpoint")
+                         .takes_value(true)
+                         .value_name("URL")
+                         .help("use custom DynamoDB endpoint, e.g. DynamoDB Local"))
+                    .arg(Arg::with_name("kms-endpoint")
+                         .long("kms-endpoint")
+                         .takes_value(true)
+                         .value_name("URL")
+                         .help("use custom KMS endpoint, e.g. a local stand-in"))
+                    .arg(Arg::with_name("role-arn")
+                         .long("role-arn")
+                         .takes_value(true)
+                         .value_name("ARN")
+                         .help("assume an IAM role (default: role_arn of the profile in ~/.aws/config)"))
+                    .arg(Arg::with_name("external-id")
+                         .long("external-id")
+                         .takes_value(true)
+                         .value_name("ID")
+                         .help("use an external ID when assuming the role"))
+                    .arg(Arg::with_name("mfa-serial")
+                         .long("mfa-serial")
+                         .takes_value(true)
+                         .value_name("ARN")
+                         .help("prompt for an MFA code from this device when assuming the role"))
+                    .arg(Arg::with_name("replicate-to")
+                         .long("replicate-to")
+                         .takes_value(true)
+                         .value_name("REGIONS")
+                         .help("also set up, store and delete secrets in these regions, separated by commas"))
+                    .subcommand(with_context_arg(
+                                SubCommand::with_name("sync-regions")
+                                .about("Copy changes that are missing from the --replicate-to regions, and report secrets that only exist there")
+                                .arg(Arg::with_name("prefix")
+                                     .long("prefix")
+                                     .short("p")
+                                     .takes_value(true)
+                                     .help("only sync secrets whose IDs start with this prefix"))
+                                .arg(Arg::with_name("dry-run")
+                                     .long("dry-run")
+                                     .short("n")
+                                     .takes_value(false)
+                                     .help("show what would be changed without changing anything"))
+                                .arg(Arg::with_name("delete-extra")
+                                     .long("delete-extra")
+                                     .takes_value(false)
+                                     .help("delete secrets from the --replicate-to regions that don't exist in the main region")),
+                                true,
+                                "encryption context pair that the secrets were stored with"))
+                    .subcommand(SubCommand::with_name("migrate")
+                                .about("Copy the secrets from a table created by an older version of morocco into the --table table as version 1")
+                                .arg(Arg::with_name("from")
+                                     .long("from")
+                                     .takes_value(true)
+                                     .value_name("TABLE")
+                                     .required(true)
+                                     .help("the old table, which is left as it is")))),
+                    true))
+        .subcommand(secret_subcommands(with_cache_args(
+                    SubCommand::with_name("gcp")
+                    .about("Use Google's Cloud Key Management Service for encryption and Cloud Datastore for storage")
+                    .arg(Arg::with_name("project")
+                         .long("project")
+                         .short("p")
+                         .takes_value(true)
+                         .help("Google Cloud project (default: $GOOGLE_CLOUD_PROJECT)"))
+                    .arg(Arg::with_name("location")
+                         .long("location")
+                         .short("l")
+                         .takes_value(true)
+                         .help("use custom Cloud KMS location (default: global)"))
+                    .arg(Arg::with_name("key-ring")
+                         .long("key-ring")
+                         .takes_value(true)
+                         .help("use custom Cloud KMS key ring (default: morocco)"))
+                    .arg(Arg::with_name("key")
+                         .long("key")
+                         .short("k")
+                         .takes_value(true)
+                         .help("use custom Cloud KMS crypto key (default: morocco)"))
+                    .arg(Arg::with_name("collection")
+                         .long("collection")
+                         .short("c")
+                         .takes_value(true)
+                         .help("use custom Datastore kind (default: morocco)"))
+                    .arg(Arg::with_name("kms-endpoint")
+                         .long("kms-endpoint")
+                         .takes_value(true)
+                         .help("use custom Cloud KMS endpoint, e.g. a local fake"))
+                    .arg(Arg::with_name("datastore-endpoint")
+                         .long("datastore-endpoint")
+                         .takes_value(true)
+                         .help("use custom Datastore endpoint (default: $DATASTORE_EMULATOR_HOST if set)"))),
+                    false))
+        .subcommand(secret_subcommands(
+                    SubCommand::with_name("local")
+                    .about("Use a passphrase-encrypted file on local disk for storage")
+                    .arg(Arg::with_name("file")
+                         .long("file")
+                         .short("f")
+                         .takes_value(true)
+                         .help("use custom secrets file (default: ~/.morocco/secrets)"))
+                    .arg(Arg::with_name("passphrase-env")
+                         .long("passphrase-env")
+                         .takes_value(true)
+                         .help("environment variable containing the passphrase (default: MOROCCO_PASSPHRASE)")),
+                    false))
+}
+
+// Add the subcommands that every backend supports.
+// The --context option on get and put is only meaningful for backends using KMS.
+fn secret_subcommands(app: App<'static, 'static>, kms_context: bool) -> App<'static, 'static> {
+    app
+        .subcommand(SubCommand::with_name("setup"))
+        .subcommand(SubCommand::with_name("list")
+                    .arg(Arg::with_name("stream")
+                         .long("stream")
+                         .short("s")
+                         .takes_value(false)
+                         .help("print IDs as soon as they are found, unsorted"))
+                    .arg(Arg::with_name("limit")
+                         .long("limit")
+                         .short("l")
+                         .takes_value(true)
+                         .help("print at most this many IDs"))
+                    .arg(Arg::with_name("long")
+                         .long("long")
+                         .takes_value(false)
+                         .conflicts_with("stream")
+                         .help("print the latest version, when it was stored, its author, tags and description"))
+                    .arg(Arg::with_name("tag")
+                         .long("tag")
+                         .short("t")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY=VALUE")
+                         .conflicts_with("stream")
+                         .help("only list secrets with this tag")))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("get")
+                    .arg(Arg::with_name("version")
+                         .long("version")
+                         .short("v")
+                         .takes_value(true)
+                         .help("get a specific version of the secret instead of the latest"))
+                    .arg(Arg::with_name("raw")
+                         .long("raw")
+                         .takes_value(false)
+                         .help("write the value to stdout exactly as stored, without a trailing newline"))
+                    .arg(Arg::with_name("base64")
+                         .long("base64")
+                         .takes_value(false)
+                         .help("base64-encode the value"))
+                    .arg(Arg::with_name("no-newline")
+                         .long("no-newline")
+                         .short("n")
+                         .takes_value(false)
+                         .help("do not print a newline after the value"))
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .short("O")
+                         .takes_value(true)
+                         .value_name("PATH")
+                         .help("write the value to a file, readable only by you"))
+                    .arg(Arg::with_name("strict")
+                         .long("strict")
+                         .takes_value(false)
+                         .help("fail instead of warning if the secret has expired"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1)),
+                    kms_context,
+                    "encryption context pair that the secret was stored with"))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("getall")
+                    .about("Get the latest version of many secrets at once")
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .short("p")
+                         .takes_value(true)
+                         .help("only get secrets whose IDs start with this prefix"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .short("f")
+                         .takes_value(true)
+                         .possible_values(&["json", "yaml", "dotenv", "shell"])
+                         .default_value("json")
+                         .help("output format. dotenv and shell turn IDs into environment variable names"))
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .short("O")
+                         .takes_value(true)
+                         .value_name("PATH")
+                         .help("write the secrets to a file, readable only by you")),
+                    kms_context,
+                    "encryption context pair that the secrets were stored with"))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("exec")
+                    .about("Run a command with secrets set as environment variables")
+                    .setting(AppSettings::TrailingVarArg)
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .short("p")
+                         .takes_value(true)
+                         .help("only set secrets whose IDs start with this prefix, and remove it from the variable names"))
+                    .arg(Arg::with_name("keep-prefix")
+                         .long("keep-prefix")
+                         .takes_value(false)
+                         .help("do not remove the prefix from the variable names"))
+                    .arg(Arg::with_name("env-prefix")
+                         .long("env-prefix")
+                         .takes_value(true)
+                         .value_name("PREFIX")
+                         .help("add this prefix to every variable name"))
+                    .arg(Arg::with_name("map")
+                         .long("map")
+                         .short("m")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("ID=NAME")
+                         .help("use this variable name for the secret with the given ID"))
+                    .arg(Arg::with_name("COMMAND")
+                         .required(true)
+                         .multiple(true)
+                         .index(1)
+                         .help("the command to run, and its arguments")),
+                    kms_context,
+                    "encryption context pair that the secrets were stored with"))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("render")
+                    .about("Render a template, replacing {{ secret \"some.id\" }} placeholders with secrets")
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .short("O")
+                         .takes_value(true)
+                         .value_name("PATH")
+                         .help("write the rendered template to a file, readable only by you"))
+                    .arg(Arg::with_name("TEMPLATE")
+                         .required(true)
+                         .index(1)),
+                    kms_context,
+                    "encryption context pair that the secrets were stored with"))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("put")
+                    .arg(Arg::with_name("overwrite")
+                         .long("overwrite")
+                         .short("o")
+                         .takes_value(false)
+                         .help("overwrite the record if it already exists"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1))
+                    .arg(Arg::with_name("VALUE")
+                         .index(2)
+                         .help("the value to store, or - to read it from stdin"))
+                    .arg(Arg::with_name("file")
+                         .long("file")
+                         .short("f")
+                         .takes_value(true)
+                         .value_name("PATH")
+                         .help("read the value from a file"))
+                    .arg(Arg::with_name("prompt")
+                         .long("prompt")
+                         .takes_value(false)
+                         .help("prompt for the value without echoing it"))
+                    .arg(Arg::with_name("description")
+                         .long("description")
+                         .short("d")
+                         .takes_value(true)
+                         .value_name("TEXT")
+                         .help("describe the secret (default: the description of the previous version)"))
+                    .arg(Arg::with_name("tag")
+                         .long("tag")
+                         .short("t")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY=VALUE")
+                         .help("tag the secret, replacing any tags of the previous version"))
+                    .arg(Arg::with_name("expires-in")
+                         .long("expires-in")
+                         .takes_value(true)
+                         .value_name("DURATION")
+                         .conflicts_with("expires-at")
+                         .help("mark the secret as due for rotation after this long, e.g. 90d, 12w or 36h"))
+                    .arg(Arg::with_name("expires-at")
+                         .long("expires-at")
+                         .takes_value(true)
+                         .value_name("DATE")
+                         .help("mark the secret as due for rotation at this date, e.g. 2018-01-31"))
+                    .group(ArgGroup::with_name("value-source")
+                           .args(&["VALUE", "file", "prompt"])
+                           .required(true)),
+                    kms_context,
+                    "bind the secret to an extra KMS encryption context pair"))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("putall")
+                    .about("Store many secrets at once, read from a JSON, YAML or dotenv file")
+                    .arg(Arg::with_name("overwrite")
+                         .long("overwrite")
+                         .short("o")
+                         .takes_value(false)
+                         .help("store new versions of secrets that already exist, instead of skipping them"))
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .short("p")
+                         .takes_value(true)
+                         .help("add this prefix to every ID in the file"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .short("f")
+                         .takes_value(true)
+                         .possible_values(&["json", "yaml", "dotenv"])
+                         .help("format of the file (default: guessed from the file extension)"))
+                    .arg(Arg::with_name("FILE")
+                         .required(true)
+                         .index(1)
+                         .help("file containing a map of IDs to values")),
+                    kms_context,
+                    "bind the secrets to an extra KMS encryption context pair"))
+        .subcommand(SubCommand::with_name("describe")
+                    .about("Show a secret's metadata without decrypting it")
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1)))
+        .subcommand(with_context_arg(
+                    SubCommand::with_name("reencrypt")
+                    .about("Re-encrypt secrets under new data keys from the current master key")
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .short("p")
+                         .takes_value(true)
+                         .help("only re-encrypt secrets whose IDs start with this prefix"))
+                    .arg(Arg::with_name("dry-run")
+                         .long("dry-run")
+                         .short("n")
+                         .takes_value(false)
+                         .help("show which versions would be re-encrypted without changing anything"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .takes_value(false)
+                         .help("also re-encrypt versions that are already under the current master key")),
+                    kms_context,
+                    "encryption context pair that the secrets were stored with"))
+        .subcommand(SubCommand::with_name("report")
+                    .about("List secrets that have expired or will expire soon")
+                    .arg(Arg::with_name("within")
+                         .long("within")
+                         .short("w")
+                         .takes_value(true)
+                         .value_name("DAYS")
+                         .help("also list secrets that will expire within this many days (default: 30)"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .short("f")
+                         .takes_value(true)
+                         .possible_values(&["human", "json"])
+                         .default_value("human")
+                         .help("output format")))
+        .subcommand(SubCommand::with_name("history")
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("rollback")
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1))
+                    .arg(Arg::with_name("VERSION")
+                         .required(true)
+                         .index(2)))
+        .subcommand(SubCommand::with_name("delete")
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .index(1)))
+}
+
+// Add the options for caching decrypted secrets on disk, for the backends that decrypt them with a KMS
+fn with_cache_args(app: App<'static, 'static>) -> App<'static, 'static> {
+    app
+        .arg(Arg::with_name("cache-ttl")
+             .long("cache-ttl")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .help("cache the secrets that are read for this many seconds, encrypted in ~/.morocco/cache"))
+        .arg(Arg::with_name("no-cache")
+             .long("no-cache")
+             .takes_value(false)
+             .help("read secrets from the backend even if --cache-ttl is set"))
+}
+
+fn with_context_arg(app: App<'static, 'static>, enabled: bool, help: &'static str) -> App<'static, 'static> {
+    if enabled {
+        app.arg(Arg::with_name("context")
+                .long("context")
+                .short("c")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help(help))
+    } else {
+        app
+    }
+}
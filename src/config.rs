@@ -0,0 +1,276 @@
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use morocco::{MoroccoError, ErrorKind};
+
+// The options of each backend that can be set in a config file or the environment
+const AWS_SETTINGS: &[&str] = &["profile", "region", "table", "key-alias", "dynamodb-endpoint", "kms-endpoint",
+                                    "role-arn", "external-id", "mfa-serial", "replicate-to", "cache-ttl"];
+const GCP_SETTINGS: &[&str] = &["project", "location", "key-ring", "key", "collection", "kms-endpoint", "datastore-endpoint",
+                                    "cache-ttl"];
+const LOCAL_SETTINGS: &[&str] = &["file", "passphrase-env"];
+
+const PROJECT_FILE_NAME: &str = ".morocco.toml";
+
+// A config file, e.g.
+//
+//   default_env = "dev"
+//
+//   [aws]
+//   region = "eu-west-1"
+//
+//   [envs.prod.aws]
+//   profile = "prod"
+//   key_alias = "prod-secrets"
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    default_env: Option<String>,
+    #[serde(flatten)]
+    settings: Sections,
+    #[serde(default)]
+    envs: BTreeMap<String, Sections>
+}
+
+#[derive(Deserialize, Default)]
+struct Sections {
+    #[serde(default)]
+    aws: BTreeMap<String, String>,
+    #[serde(default)]
+    gcp: BTreeMap<String, String>,
+    #[serde(default)]
+    local: BTreeMap<String, String>
+}
+
+impl Sections {
+
+    fn section(&self, backend: &str) -> &BTreeMap<String, String> {
+        match backend {
+            "aws" => &self.aws,
+            "gcp" => &self.gcp,
+            _ => &self.local
+        }
+    }
+
+}
+
+// Settings for the backends, gathered from the user's config file, the project's config file
+// and the environment. Each setting is taken from the first of these places that has it:
+//
+// 1. the command line option
+// 2. the MOROCCO_<SETTING> environment variable, e.g. MOROCCO_KEY_ALIAS, or MOROCCO_<BACKEND>_<SETTING>
+//    for settings that more than one backend has, e.g. MOROCCO_AWS_KMS_ENDPOINT
+// 3. the selected environment in the project file, then in the user file
+// 4. the top-level sections of the project file, then of the user file
+//
+// Options that none of these set fall back to their built-in defaults.
+pub struct Config {
+    // the project file first, as it takes precedence
+    files: Vec<(PathBuf, ConfigFile)>,
+    environment: Option<String>,
+    // the MOROCCO_* environment variables
+    env: BTreeMap<String, String>
+}
+
+impl Config {
+
+    // Load ~/.config/morocco/config.toml and the nearest .morocco.toml in the current directory
+    // or its parents. The environment is chosen by --env, then MOROCCO_ENV, then default_env.
+    pub fn load(environment: Option<&str>) -> Result<Config, MoroccoError> {
+        let mut paths = Vec::new();
+        if let Some(path) = env::current_dir().ok().and_then(|dir| find_project_file(&dir)) {
+            paths.push(path);
+        }
+        if let Some(path) = user_config_path().filter(|p| p.is_file()) {
+            paths.push(path);
+        }
+
+        let mut files = Vec::new();
+        for path in paths {
+            let file = read_config_file(&path)?;
+            files.push((path, file));
+        }
+        let environment = environment.map(|e| e.to_string()).or(env::var("MOROCCO_ENV").ok());
+        let env = env::vars().filter(|&(ref name, _)| name.starts_with("MOROCCO_")).collect();
+        Config::new(files, environment, env)
+    }
+
+    fn new(files: Vec<(PathBuf, ConfigFile)>, environment: Option<String>, env: BTreeMap<String, String>) -> Result<Config, MoroccoError> {
+        for &(ref path, ref file) in &files {
+            let all_sections = Some(&file.settings).into_iter().chain(file.envs.values());
+            for sections in all_sections {
+                check_settings(path, "aws", &sections.aws, AWS_SETTINGS)?;
+                check_settings(path, "gcp", &sections.gcp, GCP_SETTINGS)?;
+                check_settings(path, "local", &sections.local, LOCAL_SETTINGS)?;
+            }
+        }
+
+        let environment = environment.or(files.iter().filter_map(|&(_, ref f)| f.default_env.clone()).next());
+        if let Some(ref name) = environment {
+            if !files.iter().any(|&(_, ref f)| f.envs.contains_key(name)) {
+                return Err(MoroccoError::new(ErrorKind::Config, format!("There is no environment called {} in any config file.", name)));
+            }
+        }
+        Ok(Config { files, environment, env })
+    }
+
+    // Look up a setting for a backend, given the value of the command line option (if any)
+    pub fn value_of(&self, backend: &str, name: &str, option: Option<&str>) -> Option<String> {
+        if let Some(value) = option {
+            return Some(value.to_string());
+        }
+        if let Some(value) = self.env.get(&env_var_name(backend, name)) {
+            return Some(value.clone());
+        }
+        let key = name.replace('-', "_");
+        let in_environment = self.environment.as_ref().and_then(|environment| {
+            self.files.iter()
+                .filter_map(|&(_, ref f)| f.envs.get(environment).and_then(|s| s.section(backend).get(&key)))
+                .next()
+        });
+        let at_top_level = || self.files.iter()
+            .filter_map(|&(_, ref f)| f.settings.section(backend).get(&key))
+            .next();
+        in_environment.or_else(at_top_level).cloned()
+    }
+
+}
+
+// The settings that can be given for a backend
+pub fn settings(backend: &str) -> &'static [&'static str] {
+    match backend {
+        "aws" => AWS_SETTINGS,
+        "gcp" => GCP_SETTINGS,
+        _ => LOCAL_SETTINGS
+    }
+}
+
+// The environment variable for a setting. Settings that more than one backend has are
+// prefixed with the backend, so that e.g. a KMS endpoint for AWS isn't used for Google Cloud.
+fn env_var_name(backend: &str, name: &str) -> String {
+    let shared = ["aws", "gcp", "local"].iter().filter(|b| settings(b).contains(&name)).count() > 1;
+    let name = if shared { format!("{}_{}", backend, name) } else { name.to_string() };
+    format!("MOROCCO_{}", name.replace('-', "_").to_uppercase())
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::home_dir().map(|home| home.join(".config"))
+    }.map(|dir| dir.join("morocco").join("config.toml"))
+}
+
+fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    let path = dir.join(PROJECT_FILE_NAME);
+    if path.is_file() {
+        Some(path)
+    } else {
+        dir.parent().and_then(find_project_file)
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile, MoroccoError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    parse_config_file(path, &contents)
+}
+
+fn parse_config_file(path: &Path, contents: &str) -> Result<ConfigFile, MoroccoError> {
+    let invalid = |e: toml::de::Error| MoroccoError::new(ErrorKind::Config, format!("Invalid config file {}: {}", path.display(), e));
+    let value = contents.parse::<toml::Value>().map_err(&invalid)?;
+    check_keys(path, &value)?;
+    value.try_into().map_err(&invalid)
+}
+
+// Serde ignores fields that it doesn't know, and the backend sections are flattened into the top level,
+// so misspelt sections and environments are caught here
+fn check_keys(path: &Path, value: &toml::Value) -> Result<(), MoroccoError> {
+    let unknown = |key: &str, place: &str| MoroccoError::new(
+        ErrorKind::Config,
+        format!("Unknown key {} {} of {}", key, place, path.display()));
+    let empty = BTreeMap::new();
+    let top_level = value.as_table().unwrap_or(&empty);
+    for key in top_level.keys() {
+        if !["default_env", "aws", "gcp", "local", "envs"].contains(&key.as_str()) {
+            return Err(unknown(key, "at the top level"));
+        }
+    }
+    let envs = top_level.get("envs").and_then(|e| e.as_table()).unwrap_or(&empty);
+    for (name, environment) in envs {
+        for key in environment.as_table().unwrap_or(&empty).keys() {
+            if !["aws", "gcp", "local"].contains(&key.as_str()) {
+                return Err(unknown(key, &format!("in the environment {}", name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Catch typos, which would otherwise be silently ignored
+fn check_settings(path: &Path, backend: &str, section: &BTreeMap<String, String>, allowed: &[&str]) -> Result<(), MoroccoError> {
+    for key in section.keys() {
+        if !allowed.iter().any(|name| name.replace('-', "_") == *key) {
+            return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("Unknown setting {} in the [{}] section of {}", key, backend, path.display())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(files: &[&str], environment: Option<&str>) -> Result<Config, MoroccoError> {
+        config_with_env(files, environment, &[])
+    }
+
+    fn config_with_env(files: &[&str], environment: Option<&str>, env: &[(&str, &str)]) -> Result<Config, MoroccoError> {
+        let path = PathBuf::from("test.toml");
+        let mut parsed = Vec::new();
+        for contents in files {
+            parsed.push((path.clone(), parse_config_file(&path, contents)?));
+        }
+        let env = env.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::new(parsed, environment.map(|e| e.to_string()), env)
+    }
+
+    #[test]
+    fn precedence() {
+        let project = "[aws]\ntable = \"project-table\"\n[envs.prod.aws]\nkey_alias = \"project-prod-key\"\n";
+        let user = "default_env = \"prod\"\n[aws]\ntable = \"user-table\"\nkey_alias = \"user-key\"\n[envs.prod.aws]\ntable = \"user-prod-table\"\nkey_alias = \"user-prod-key\"\n";
+        let config = config(&[project, user], None).ok().unwrap();
+        assert_eq!(Some("flag".to_string()), config.value_of("aws", "table", Some("flag")));
+        assert_eq!(Some("project-prod-key".to_string()), config.value_of("aws", "key-alias", None));
+        // a setting in the selected environment beats one at the top level, even of the project file
+        assert_eq!(Some("user-prod-table".to_string()), config.value_of("aws", "table", None));
+        assert_eq!(None, config.value_of("gcp", "collection", None));
+    }
+
+    #[test]
+    fn environment() {
+        let env = [("MOROCCO_TABLE", "env-table"), ("MOROCCO_AWS_KMS_ENDPOINT", "http://localhost:4599"), ("MOROCCO_CACHE_TTL", "60")];
+        let config = config_with_env(&["[aws]\ntable = \"file-table\"\n[gcp]\ncache_ttl = \"300\"\n"], None, &env).ok().unwrap();
+        assert_eq!(Some("flag".to_string()), config.value_of("aws", "table", Some("flag")));
+        assert_eq!(Some("env-table".to_string()), config.value_of("aws", "table", None));
+        // settings that both AWS and Google Cloud have need the backend in the variable's name
+        assert_eq!(Some("http://localhost:4599".to_string()), config.value_of("aws", "kms-endpoint", None));
+        assert_eq!(None, config.value_of("gcp", "kms-endpoint", None));
+        assert_eq!(Some("300".to_string()), config.value_of("gcp", "cache-ttl", None));
+        assert_eq!(None, config.value_of("aws", "cache-ttl", None));
+    }
+
+    #[test]
+    fn unknown_environment_or_setting() {
+        assert_eq!(ErrorKind::Config, config(&["[aws]\nregion = \"eu-west-1\"\n"], Some("prod")).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, config(&["[aws]\nkeyalias = \"oops\"\n"], None).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, config(&["[asw]\nregion = \"eu-west-1\"\n"], None).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, config(&["defaul_env = \"prod\"\n"], None).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, config(&["[envs.prod.asw]\nregion = \"eu-west-1\"\n"], None).err().unwrap().kind);
+    }
+}
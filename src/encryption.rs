@@ -2,12 +2,201 @@ extern crate crypto;
 
 use self::crypto::{ symmetriccipher, buffer, aes, blockmodes };
 use self::crypto::buffer::{ ReadBuffer, WriteBuffer, BufferResult };
+use self::crypto::aes_gcm::AesGcm;
+use self::crypto::aead::{ AeadEncryptor, AeadDecryptor };
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::{ Mac, MacResult };
+use self::crypto::sha2::Sha256;
+
+use morocco::{MoroccoError, ErrorKind};
+use secret::{Secret, wipe};
+
+const GCM_TAG_LENGTH: usize = 16;
+
+// Length of the data keys used for envelope encryption:
+// 32 bytes of AES key followed by 32 bytes of HMAC key
+pub const DATA_KEY_LENGTH: usize = 64;
+
+// The cipher used to encrypt a secret. This is recorded alongside each item
+// so that we can still decrypt items written by older versions of morocco.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    // AES-256/CBC/Pkcs. Unauthenticated, only used for reading legacy items.
+    AesCbc,
+    // AES-256/GCM. The authentication tag is appended to the ciphertext.
+    AesGcm
+}
+
+impl Cipher {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Cipher::AesCbc => "AES-256-CBC",
+            Cipher::AesGcm => "AES-256-GCM"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Cipher> {
+        match name {
+            "AES-256-CBC" => Some(Cipher::AesCbc),
+            "AES-256-GCM" => Some(Cipher::AesGcm),
+            _ => None
+        }
+    }
+
+    // The number of bytes of IV (or nonce, in the case of GCM) that the cipher expects
+    pub fn iv_length(&self) -> usize {
+        match *self {
+            Cipher::AesCbc => 16,
+            Cipher::AesGcm => 12
+        }
+    }
+
+}
+
+// InvalidLength and InvalidPadding only come from encrypting, where they mean that we passed
+// a bad key or IV. Stored data that can't be decrypted is always an IntegrityCheckFailed.
+#[derive(Debug, PartialEq)]
+pub enum EncryptionError {
+    InvalidLength,
+    InvalidPadding,
+    AuthenticationFailed,
+    // the reason the integrity check failed
+    IntegrityCheckFailed(&'static str)
+}
+
+impl From<EncryptionError> for MoroccoError {
+    fn from(err: EncryptionError) -> MoroccoError {
+        match err {
+            EncryptionError::AuthenticationFailed =>
+                MoroccoError::new(ErrorKind::IntegrityFailure, integrity_failure_message("the ciphertext failed authentication")),
+            EncryptionError::IntegrityCheckFailed(reason) =>
+                MoroccoError::new(ErrorKind::IntegrityFailure, integrity_failure_message(reason)),
+            _ => MoroccoError::new(ErrorKind::Backend, "Failed to encrypt secret.".to_string())
+        }
+    }
+}
+
+fn integrity_failure_message(reason: &str) -> String {
+    format!("Integrity check failed: {}. The stored secret may have been tampered with or corrupted.", reason)
+}
+
+impl From<symmetriccipher::SymmetricCipherError> for EncryptionError {
+    fn from(err: symmetriccipher::SymmetricCipherError) -> EncryptionError {
+        match err {
+            symmetriccipher::SymmetricCipherError::InvalidLength => EncryptionError::InvalidLength,
+            symmetriccipher::SymmetricCipherError::InvalidPadding => EncryptionError::InvalidPadding
+        }
+    }
+}
+
+// Encrypt a buffer with the given key and iv using the given cipher.
+pub fn encrypt(cipher: Cipher, data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if key.len() != 32 || iv.len() != cipher.iv_length() {
+        return Err(EncryptionError::InvalidLength);
+    }
+    match cipher {
+        Cipher::AesCbc => Ok(cbc_encrypt(data, key, iv)?),
+        Cipher::AesGcm => Ok(gcm_encrypt(data, key, iv))
+    }
+}
+
+// Decrypt a buffer with the given key and iv using the given cipher.
+pub fn decrypt(cipher: Cipher, encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Secret, EncryptionError> {
+    if key.len() != 32 || iv.len() != cipher.iv_length() {
+        return Err(EncryptionError::IntegrityCheckFailed("the data key or IV is not the expected length"));
+    }
+    match cipher {
+        Cipher::AesCbc => cbc_decrypt(encrypted_data, key, iv)
+            .map_err(|_| EncryptionError::IntegrityCheckFailed("the ciphertext is not correctly padded or is not a whole number of blocks")),
+        Cipher::AesGcm => gcm_decrypt(encrypted_data, key, iv)
+    }
+}
+
+// Encrypt a buffer with the given key and nonce using AES-256/GCM.
+// Returns the ciphertext followed by the 16-byte authentication tag.
+fn gcm_encrypt(data: &[u8], key: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut encryptor = AesGcm::new(aes::KeySize::KeySize256, key, nonce, &[]);
+    let mut result = vec![0; data.len() + GCM_TAG_LENGTH];
+    {
+        let (ciphertext, tag) = result.split_at_mut(data.len());
+        encryptor.encrypt(data, ciphertext, tag);
+    }
+    result
+}
+
+// Verify the authentication tag and decrypt a buffer produced by gcm_encrypt.
+fn gcm_decrypt(encrypted_data: &[u8], key: &[u8], nonce: &[u8]) -> Result<Secret, EncryptionError> {
+    if encrypted_data.len() < GCM_TAG_LENGTH {
+        return Err(EncryptionError::IntegrityCheckFailed("the ciphertext is too short"));
+    }
+    let (ciphertext, tag) = encrypted_data.split_at(encrypted_data.len() - GCM_TAG_LENGTH);
+    let mut decryptor = AesGcm::new(aes::KeySize::KeySize256, key, nonce, &[]);
+    // the plaintext is written straight into a Secret, so it is zeroed even if authentication fails
+    let mut plaintext = Secret::new(vec![0; ciphertext.len()]);
+    if decryptor.decrypt(ciphertext, plaintext.as_mut_bytes(), tag) {
+        Ok(plaintext)
+    } else {
+        Err(EncryptionError::AuthenticationFailed)
+    }
+}
+
+// Encrypt a secret with a data key as part of envelope encryption.
+// The first half of the data key is used for encryption, the second half for an HMAC
+// over the IV and ciphertext. Returns the ciphertext and the HMAC.
+pub fn seal(cipher: Cipher, data: &[u8], data_key: &[u8], iv: &[u8]) -> Result<(Vec<u8>, Vec<u8>), EncryptionError> {
+    if data_key.len() != DATA_KEY_LENGTH {
+        return Err(EncryptionError::InvalidLength);
+    }
+    let (encryption_key, hmac_key) = data_key.split_at(DATA_KEY_LENGTH / 2);
+    let ciphertext = encrypt(cipher, data, encryption_key, iv)?;
+    let hmac = hmac_sha256(hmac_key, &[iv, ciphertext.as_slice()]);
+    Ok((ciphertext, hmac))
+}
+
+// Verify the HMAC and decrypt a secret that was encrypted using seal.
+// Items written before we started storing an HMAC have a 32-byte data key
+// that is used entirely for encryption. Anything else must carry a valid HMAC.
+pub fn open(cipher: Cipher, encrypted_data: &[u8], data_key: &[u8], iv: &[u8], hmac: Option<&[u8]>) -> Result<Secret, EncryptionError> {
+    let encryption_key = match (data_key.len(), hmac) {
+        (DATA_KEY_LENGTH, Some(hmac)) => {
+            let (encryption_key, hmac_key) = data_key.split_at(DATA_KEY_LENGTH / 2);
+            if !verify_hmac_sha256(hmac_key, &[iv, encrypted_data], hmac) {
+                return Err(EncryptionError::IntegrityCheckFailed("the HMAC does not match the stored ciphertext"));
+            }
+            encryption_key
+        },
+        (DATA_KEY_LENGTH, None) => return Err(EncryptionError::IntegrityCheckFailed("the item has no HMAC")),
+        (_, Some(_)) => return Err(EncryptionError::IntegrityCheckFailed("the data key is not the expected length")),
+        (_, None) => data_key
+    };
+    decrypt(cipher, encrypted_data, encryption_key, iv)
+}
+
+// Compute an HMAC-SHA256 over the given pieces of data, in order.
+pub fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    for piece in data {
+        hmac.input(piece);
+    }
+    hmac.result().code().to_vec()
+}
+
+// Check an HMAC-SHA256 in constant time.
+pub fn verify_hmac_sha256(key: &[u8], data: &[&[u8]], expected: &[u8]) -> bool {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    for piece in data {
+        hmac.input(piece);
+    }
+    // MacResult's equality check runs in constant time
+    hmac.result() == MacResult::new(expected)
+}
 
 // Based on (i.e. copy-pasted from) 
 // https://github.com/DaGenix/rust-crypto/blob/master/examples/symmetriccipher.rs
 
 // Encrypt a buffer with the given key and iv using AES-256/CBC/Pkcs encryption.
-pub fn encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
+fn cbc_encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
 
     // Create an encryptor instance of the best performing
     // type available for the platform.
@@ -65,29 +254,37 @@ pub fn encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, symmetricc
     Ok(final_result)
 }
 
-// Decrypts a buffer with the given key and iv using AES-256/CBC/Pkcs encryption
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
+// Decrypts a buffer with the given key and iv using AES-256/CBC/Pkcs encryption.
+// The plaintext is never longer than the ciphertext, so reserving that much up front means the result
+// is never reallocated, which would leave a copy of the plaintext behind in freed memory.
+fn cbc_decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Secret, symmetriccipher::SymmetricCipherError> {
     let mut decryptor = aes::cbc_decryptor(
             aes::KeySize::KeySize256,
             key,
             iv,
             blockmodes::PkcsPadding);
 
-    let mut final_result = Vec::<u8>::new();
+    let mut final_result = Secret::new(Vec::with_capacity(encrypted_data.len()));
     let mut read_buffer = buffer::RefReadBuffer::new(encrypted_data);
     let mut buffer = [0; 4096];
-    let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
-
-    loop {
-        let result = try!(decryptor.decrypt(&mut read_buffer, &mut write_buffer, true));
-        final_result.extend(write_buffer.take_read_buffer().take_remaining().iter().map(|&i| i));
-        match result {
-            BufferResult::BufferUnderflow => break,
-            BufferResult::BufferOverflow => { }
+    let outcome = {
+        let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
+        loop {
+            match decryptor.decrypt(&mut read_buffer, &mut write_buffer, true) {
+                Ok(result) => {
+                    final_result.extend_from_slice(write_buffer.take_read_buffer().take_remaining());
+                    match result {
+                        BufferResult::BufferUnderflow => break Ok(()),
+                        BufferResult::BufferOverflow => { }
+                    }
+                },
+                Err(e) => break Err(e)
+            }
         }
-    }
+    };
+    wipe(&mut buffer);
 
-    Ok(final_result)
+    outcome.map(|_| final_result)
 }
 
 #[cfg(test)]
@@ -97,20 +294,92 @@ mod tests {
     use super::*;
     use self::rand::{ Rng, OsRng };
 
+    fn random_key_and_iv(cipher: Cipher) -> (Vec<u8>, Vec<u8>) {
+        let mut rng = OsRng::new().ok().unwrap();
+
+        let mut key = vec![0; 32];
+        let mut iv = vec![0; cipher.iv_length()];
+        rng.fill_bytes(&mut key);
+        rng.fill_bytes(&mut iv);
+        (key, iv)
+    }
+
     #[test]
     fn encryption_round_trip() {
         let message = "hey this is very top secret information";
-        
+
+        for cipher in vec![Cipher::AesCbc, Cipher::AesGcm] {
+            let (key, iv) = random_key_and_iv(cipher);
+
+            let encrypted_data = encrypt(cipher, message.as_bytes(), &key, &iv).ok().unwrap();
+            let decrypted_data = decrypt(cipher, &encrypted_data[..], &key, &iv).ok().unwrap();
+
+            assert_eq!(message, decrypted_data.as_str().unwrap());
+        }
+    }
+
+    #[test]
+    fn cbc_round_trip_larger_than_buffer() {
+        let message = vec![7; 10000];
+        let (key, iv) = random_key_and_iv(Cipher::AesCbc);
+
+        let encrypted_data = encrypt(Cipher::AesCbc, &message, &key, &iv).ok().unwrap();
+        let decrypted_data = decrypt(Cipher::AesCbc, &encrypted_data[..], &key, &iv).ok().unwrap();
+
+        assert_eq!(&message[..], decrypted_data.as_bytes());
+    }
+
+    #[test]
+    fn gcm_detects_tampering() {
+        let message = "hey this is very top secret information";
+        let (key, iv) = random_key_and_iv(Cipher::AesGcm);
+
+        let mut encrypted_data = encrypt(Cipher::AesGcm, message.as_bytes(), &key, &iv).ok().unwrap();
+        encrypted_data[0] ^= 1;
+
+        assert_eq!(Err(EncryptionError::AuthenticationFailed), decrypt(Cipher::AesGcm, &encrypted_data[..], &key, &iv));
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_errors_are_classified_apart() {
+        let (key, iv) = random_key_and_iv(Cipher::AesGcm);
+        let bad_key = encrypt(Cipher::AesGcm, b"message", &key[..16], &iv).err().unwrap();
+        assert_eq!(ErrorKind::Backend, MoroccoError::from(bad_key).kind);
+
+        let truncated = decrypt(Cipher::AesGcm, &[0; 4], &key, &iv).err().unwrap();
+        assert_eq!(ErrorKind::IntegrityFailure, MoroccoError::from(truncated).kind);
+        let (key, iv) = random_key_and_iv(Cipher::AesCbc);
+        let partial_block = decrypt(Cipher::AesCbc, &[0; 15], &key, &iv).err().unwrap();
+        assert_eq!(ErrorKind::IntegrityFailure, MoroccoError::from(partial_block).kind);
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let message = "hey this is very top secret information";
         let mut rng = OsRng::new().ok().unwrap();
+        let mut data_key = vec![0; DATA_KEY_LENGTH];
+        rng.fill_bytes(&mut data_key);
+        let (_, iv) = random_key_and_iv(Cipher::AesGcm);
 
-        let mut key: [u8; 32] = [0; 32];
-        let mut iv: [u8; 16] = [0; 16];
-        rng.fill_bytes(&mut key);
-        rng.fill_bytes(&mut iv);
+        let (ciphertext, hmac) = seal(Cipher::AesGcm, message.as_bytes(), &data_key, &iv).ok().unwrap();
+        let plaintext = open(Cipher::AesGcm, &ciphertext, &data_key, &iv, Some(&hmac)).ok().unwrap();
+        assert_eq!(message.as_bytes(), plaintext.as_bytes());
 
-        let encrypted_data = encrypt(message.as_bytes(), &key, &iv).ok().unwrap();
-        let decrypted_data = decrypt(&encrypted_data[..], &key, &iv).ok().unwrap();
+        let mut tampered_hmac = hmac.clone();
+        tampered_hmac[0] ^= 1;
+        assert_eq!(Err(EncryptionError::IntegrityCheckFailed("the HMAC does not match the stored ciphertext")),
+                   open(Cipher::AesGcm, &ciphertext, &data_key, &iv, Some(&tampered_hmac)));
+        assert_eq!(Err(EncryptionError::IntegrityCheckFailed("the item has no HMAC")),
+                   open(Cipher::AesGcm, &ciphertext, &data_key, &iv, None));
+    }
+
+    #[test]
+    fn hmac_round_trip() {
+        let key = b"an hmac key";
+        let hmac = hmac_sha256(key, &[b"iv", b"ciphertext"]);
 
-        assert_eq!(message, String::from_utf8(decrypted_data).unwrap());
+        assert!(verify_hmac_sha256(key, &[b"iv", b"ciphertext"], &hmac));
+        assert!(!verify_hmac_sha256(key, &[b"iv", b"ciphertexT"], &hmac));
+        assert!(!verify_hmac_sha256(b"another key", &[b"iv", b"ciphertext"], &hmac));
     }
 }
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::process::Command;
+
+use morocco::{MoroccoError, ErrorKind, Secret};
+use formats::env_var_name;
+
+// How secret IDs are turned into environment variable names
+pub struct EnvNaming {
+    // removed from the start of each ID before it is converted
+    pub strip_prefix: Option<String>,
+    // added to the start of each converted name
+    pub name_prefix: String,
+    // explicit names for particular IDs, used as they are
+    pub overrides: HashMap<String, String>
+}
+
+impl EnvNaming {
+
+    pub fn name_for(&self, id: &str) -> String {
+        match self.overrides.get(id) {
+            Some(name) => name.clone(),
+            None => format!("{}{}", self.name_prefix, env_var_name(id, self.strip_prefix.as_ref().map(|p| p.as_str())))
+        }
+    }
+
+}
+
+// Map each secret to an environment variable, making sure no two secrets end up with the same name
+pub fn env_vars(secrets: BTreeMap<String, Secret>, naming: &EnvNaming) -> Result<BTreeMap<String, Secret>, MoroccoError> {
+    let mut ids_by_name: HashMap<String, String> = HashMap::new();
+    let mut vars = BTreeMap::new();
+    for (id, value) in secrets {
+        let name = naming.name_for(&id);
+        if let Some(other_id) = ids_by_name.insert(name.clone(), id.clone()) {
+            return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("The secrets {} and {} would both be set as {}. Please use --map to rename one of them.", other_id, id, name)));
+        }
+        vars.insert(name, value);
+    }
+    Ok(vars)
+}
+
+// Run the command with the given extra environment variables, in place of this process.
+// The secrets are only ever held in memory and passed to the child's environment.
+// Only returns if the command could not be run.
+pub fn exec(command: &[&str], vars: BTreeMap<String, Secret>) -> MoroccoError {
+    let mut child = Command::new(command[0]);
+    child.args(&command[1..]);
+    for (name, value) in vars {
+        match to_os_string(value.to_vec()) {
+            Ok(value) => { child.env(name, value); },
+            Err(_) => return MoroccoError::new(
+                ErrorKind::Config,
+                format!("The value for {} is not valid text, so it cannot be passed as an environment variable.", name))
+        }
+    }
+    let err = run(&mut child);
+    MoroccoError::new(ErrorKind::Io, format!("Could not run {}: {}", command[0], err))
+}
+
+#[cfg(unix)]
+fn to_os_string(value: Vec<u8>) -> Result<OsString, ()> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(value))
+}
+
+#[cfg(not(unix))]
+fn to_os_string(value: Vec<u8>) -> Result<OsString, ()> {
+    String::from_utf8(value).map(OsString::from).map_err(|_| ())
+}
+
+#[cfg(unix)]
+fn run(child: &mut Command) -> ::std::io::Error {
+    use std::os::unix::process::CommandExt;
+    child.exec()
+}
+
+// Without exec, run the command as a child process and pass on its exit code
+#[cfg(not(unix))]
+fn run(child: &mut Command) -> ::std::io::Error {
+    match child.status() {
+        Ok(status) => ::std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming() -> EnvNaming {
+        let mut overrides = HashMap::new();
+        overrides.insert("app.prod.legacy-key".to_string(), "SECRET_KEY_BASE".to_string());
+        EnvNaming {
+            strip_prefix: Some("app.prod.".to_string()),
+            name_prefix: "APP_".to_string(),
+            overrides: overrides
+        }
+    }
+
+    #[test]
+    fn names() {
+        let naming = naming();
+        assert_eq!("APP_DB_PASSWORD", naming.name_for("app.prod.db.password"));
+        assert_eq!("SECRET_KEY_BASE", naming.name_for("app.prod.legacy-key"));
+    }
+
+    #[test]
+    fn clashing_names() {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("app.prod.db.password".to_string(), Secret::from("one"));
+        secrets.insert("app.prod.db-password".to_string(), Secret::from("two"));
+        assert_eq!(ErrorKind::Config, env_vars(secrets, &naming()).err().unwrap().kind);
+    }
+}
@@ -0,0 +1,196 @@
+extern crate chrono;
+extern crate serde_json;
+
+use self::chrono::{DateTime, Duration, NaiveDate, Utc};
+
+use morocco::{MoroccoError, ErrorKind, Description, Metadata};
+
+// The longest duration accepted for --expires-in and report --within, about 100 years.
+// Anything longer is almost certainly a typo, and would overflow the date arithmetic.
+pub const MAX_DAYS: i64 = 36500;
+
+// A secret that has expired or will expire soon, as shown by the report command
+#[derive(Serialize)]
+pub struct ReportEntry {
+    pub id: String,
+    pub version: u64,
+    pub expires_at: String,
+    pub expired: bool,
+    // negative once the secret has expired
+    pub days_left: i64
+}
+
+// The expiry date for a new version, from --expires-in (e.g. 90d) or --expires-at (e.g. 2018-01-31)
+pub fn expiry_date(expires_in: Option<&str>, expires_at: Option<&str>, now: DateTime<Utc>) -> Result<Option<String>, MoroccoError> {
+    match (expires_in, expires_at) {
+        (Some(duration), _) => match now.checked_add_signed(parse_duration(duration)?) {
+            Some(date) => Ok(Some(date.to_rfc3339())),
+            None => Err(MoroccoError::new(ErrorKind::Config, format!("The duration '{}' is too long.", duration)))
+        },
+        (None, Some(date)) => Ok(Some(parse_date(date)?.to_rfc3339())),
+        (None, None) => Ok(None)
+    }
+}
+
+// Parse a duration such as 90d, 12w or 36h, of at most MAX_DAYS
+fn parse_duration(text: &str) -> Result<Duration, MoroccoError> {
+    let invalid = || MoroccoError::new(
+        ErrorKind::Config,
+        format!("Invalid duration '{}'. Expected a number of hours, days or weeks up to {} days, e.g. 90d.", text, MAX_DAYS));
+    let (number, unit) = match text.char_indices().last() {
+        Some((i, _)) if i > 0 => text.split_at(i),
+        _ => return Err(invalid())
+    };
+    // parse would accept a sign
+    if !number.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours_per_unit = match unit {
+        "h" => 1,
+        "d" => 24,
+        "w" => 24 * 7,
+        _ => return Err(invalid())
+    };
+    match number.parse::<i64>().ok().and_then(|n| n.checked_mul(hours_per_unit)) {
+        Some(hours) if hours <= MAX_DAYS * 24 => Ok(Duration::hours(hours)),
+        _ => Err(invalid())
+    }
+}
+
+// Parse a date such as 2018-01-31 (meaning midnight UTC), or a full RFC 3339 timestamp
+fn parse_date(text: &str) -> Result<DateTime<Utc>, MoroccoError> {
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+    DateTime::parse_from_rfc3339(text)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| MoroccoError::new(
+                ErrorKind::Config,
+                format!("Invalid date '{}'. Expected a date such as 2018-01-31 or an RFC 3339 timestamp.", text)))
+}
+
+// Warn if a version of a secret has expired, going by the metadata it was read with, or fail if strict is true.
+pub fn check(id: &str, metadata: &Metadata, strict: bool, now: DateTime<Utc>) -> Result<(), MoroccoError> {
+    let expires_at = match metadata.expires_at.as_ref() {
+        Some(expires_at) => expires_at,
+        None => return Ok(())
+    };
+    match DateTime::parse_from_rfc3339(expires_at) {
+        Ok(date) if date.with_timezone(&Utc) <= now => {
+            let message = format!("The secret {} expired at {}. Please rotate it.", id, expires_at);
+            if strict {
+                Err(MoroccoError::new(ErrorKind::Expired, message))
+            } else {
+                eprintln!("Warning: {}", message);
+                Ok(())
+            }
+        },
+        _ => Ok(())
+    }
+}
+
+// The secrets that have expired or will expire within the given number of days, soonest first.
+// Anything beyond MAX_DAYS counts as MAX_DAYS.
+pub fn report(descriptions: Vec<Description>, within_days: i64, now: DateTime<Utc>) -> Vec<ReportEntry> {
+    let until = now.checked_add_signed(Duration::days(within_days.min(MAX_DAYS)));
+    let mut entries: Vec<(DateTime<Utc>, ReportEntry)> = descriptions.into_iter()
+        .flat_map(|d| {
+            let date = d.metadata.expires_at.as_ref()
+                .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+                .map(|date| date.with_timezone(&Utc));
+            date.map(|date| (date, ReportEntry {
+                id: d.id,
+                version: d.version,
+                expires_at: d.metadata.expires_at.unwrap(),
+                expired: date <= now,
+                days_left: (date - now).num_days()
+            }))
+        })
+        .filter(|&(date, _)| until.map_or(true, |until| date <= until))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+// One line per secret, with the ID, version, expiry date and status separated by tabs
+pub fn render_human(entries: &[ReportEntry]) -> String {
+    entries.iter()
+        .map(|e| {
+            let status = if e.expired {
+                match -e.days_left {
+                    0 => "expired today".to_string(),
+                    1 => "expired 1 day ago".to_string(),
+                    days => format!("expired {} days ago", days)
+                }
+            } else {
+                match e.days_left {
+                    0 => "expires today".to_string(),
+                    1 => "expires in 1 day".to_string(),
+                    days => format!("expires in {} days", days)
+                }
+            };
+            format!("{}\t{}\t{}\t{}\n", e.id, e.version, e.expires_at, status)
+        })
+        .collect()
+}
+
+pub fn render_json(entries: &[ReportEntry]) -> Result<String, MoroccoError> {
+    Ok(format!("{}\n", serde_json::to_string_pretty(entries)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2017-07-01T12:00:00+00:00").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn expiry_dates() {
+        assert_eq!(Some("2017-09-29T12:00:00+00:00".to_string()), expiry_date(Some("90d"), None, now()).ok().unwrap());
+        assert_eq!(Some("2017-07-03T00:00:00+00:00".to_string()), expiry_date(Some("36h"), None, now()).ok().unwrap());
+        assert_eq!(Some("2018-01-31T00:00:00+00:00".to_string()), expiry_date(None, Some("2018-01-31"), now()).ok().unwrap());
+        assert_eq!(None, expiry_date(None, None, now()).ok().unwrap());
+        assert_eq!(ErrorKind::Config, expiry_date(Some("90"), None, now()).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, expiry_date(None, Some("31/01/2018"), now()).err().unwrap().kind);
+    }
+
+    #[test]
+    fn invalid_durations() {
+        assert_eq!(Some("2117-06-07T12:00:00+00:00".to_string()), expiry_date(Some("36500d"), None, now()).ok().unwrap());
+        for duration in &["36501d", "9999999999999999w", "99999999999999999999h", "-5d", "+5d", "5 d", "d", "", "5é", "é", "90"] {
+            assert_eq!(ErrorKind::Config, expiry_date(Some(duration), None, now()).err().unwrap().kind, "{}", duration);
+        }
+    }
+
+    #[test]
+    fn strict_check_fails_once_expired() {
+        let expiring = |expires_at: &str| Metadata { expires_at: Some(expires_at.to_string()), ..Default::default() };
+        assert_eq!(ErrorKind::Expired, check("db.password", &expiring("2017-07-01T11:59:59+00:00"), true, now()).err().unwrap().kind);
+        assert!(check("db.password", &expiring("2017-07-01T11:59:59+00:00"), false, now()).is_ok());
+        assert!(check("db.password", &expiring("2017-07-02T00:00:00+00:00"), true, now()).is_ok());
+        assert!(check("db.password", &Metadata::default(), true, now()).is_ok());
+    }
+
+    #[test]
+    fn report_lists_expired_and_due_secrets() {
+        let description = |id: &str, expires_at: Option<&str>| Description {
+            id: id.to_string(),
+            version: 1,
+            created_at: "2017-01-01T00:00:00+00:00".to_string(),
+            updated_at: "2017-01-01T00:00:00+00:00".to_string(),
+            metadata: Metadata { expires_at: expires_at.map(|e| e.to_string()), ..Default::default() }
+        };
+        let descriptions = vec![
+            description("later", Some("2017-12-01T00:00:00+00:00")),
+            description("soon", Some("2017-07-11T12:00:00+00:00")),
+            description("never", None),
+            description("expired", Some("2017-06-01T12:00:00+00:00"))
+        ];
+        let entries = report(descriptions, 30, now());
+        assert_eq!(vec!["expired", "soon"], entries.iter().map(|e| e.id.as_str()).collect::<Vec<&str>>());
+        assert_eq!("expired\t1\t2017-06-01T12:00:00+00:00\texpired 30 days ago\nsoon\t1\t2017-07-11T12:00:00+00:00\texpires in 10 days\n",
+                   render_human(&entries));
+    }
+}
@@ -0,0 +1,35 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use morocco::MoroccoError;
+
+// Create (or truncate) a file that only the current user can read
+#[cfg(unix)]
+pub fn create_private_file(path: &Path) -> Result<File, MoroccoError> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // the mode only applies to newly created files, so tighten the permissions of existing ones too
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub fn create_private_file(path: &Path) -> Result<File, MoroccoError> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    Ok(file)
+}
+
+// Create a file that only the current user can read, failing with AlreadyExists rather than
+// replacing a file that another process has created in the meantime
+#[cfg(unix)]
+pub fn create_new_private_file(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create_new_private_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
@@ -0,0 +1,259 @@
+extern crate serde_json;
+extern crate serde_yaml;
+
+use std::collections::BTreeMap;
+
+use morocco::{MoroccoError, ErrorKind, Secret};
+
+// The formats that getall can export secrets in, and putall can import them from (apart from shell)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Dotenv,
+    Shell
+}
+
+impl Format {
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "yaml" => Some(Format::Yaml),
+            "dotenv" => Some(Format::Dotenv),
+            "shell" => Some(Format::Shell),
+            _ => None
+        }
+    }
+
+    // Guess the format of a file from its extension
+    pub fn from_path(path: &str) -> Option<Format> {
+        let path = path.to_lowercase();
+        if path.ends_with(".json") {
+            Some(Format::Json)
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Some(Format::Yaml)
+        } else if path.ends_with(".env") {
+            Some(Format::Dotenv)
+        } else {
+            None
+        }
+    }
+
+}
+
+// Render secrets, keyed by ID, in the given format.
+// JSON and YAML keep the IDs as they are. In dotenv and shell format each ID is turned into
+// an environment variable name, after removing the prefix (if any) that the secrets were filtered by.
+pub fn render(format: Format, secrets: &BTreeMap<String, Secret>, prefix: Option<&str>) -> Result<String, MoroccoError> {
+    let mut values = BTreeMap::new();
+    for (id, value) in secrets {
+        match value.as_str() {
+            Ok(text) => { values.insert(id.as_str(), text); },
+            Err(_) => return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("The secret {} is not valid UTF-8, so it cannot be exported. Please use get --base64 instead.", id)))
+        }
+    }
+
+    match format {
+        Format::Json => Ok(format!("{}\n", serde_json::to_string_pretty(&values)?)),
+        Format::Yaml => Ok(format!("{}\n", serde_yaml::to_string(&values).map_err(|e| MoroccoError::with_source(ErrorKind::Backend, e))?)),
+        Format::Dotenv => render_variables(&values, prefix, |name, value| format!("{}={}\n", name, dotenv_quote(value))),
+        Format::Shell => render_variables(&values, prefix, |name, value| format!("export {}={}\n", name, shell_quote(value)))
+    }
+}
+
+fn render_variables<F>(values: &BTreeMap<&str, &str>, prefix: Option<&str>, line: F) -> Result<String, MoroccoError>
+    where F: Fn(&str, &str) -> String {
+    let mut ids_by_name: BTreeMap<String, &str> = BTreeMap::new();
+    let mut output = String::new();
+    for (id, value) in values {
+        let name = env_var_name(id, prefix);
+        if let Some(other_id) = ids_by_name.insert(name.clone(), id) {
+            return Err(MoroccoError::new(
+                    ErrorKind::Config,
+                    format!("The secrets {} and {} would both be exported as {}.", other_id, id, name)));
+        }
+        output.push_str(&line(&name, value));
+    }
+    Ok(output)
+}
+
+// Turn a secret ID into an environment variable name,
+// e.g. "db.password" or "app.prod.db.password" with the prefix "app.prod." both become "DB_PASSWORD".
+pub fn env_var_name(id: &str, prefix: Option<&str>) -> String {
+    let id = match prefix {
+        Some(p) if id.starts_with(p) => &id[p.len()..],
+        _ => id
+    };
+    let name: String = id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    // names must not start with a digit
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+// Parse a map of secret IDs to values, as written by render.
+// Values in JSON and YAML files may be strings, numbers or booleans.
+pub fn parse(format: Format, text: &str) -> Result<BTreeMap<String, Vec<u8>>, MoroccoError> {
+    let invalid = |e: String| MoroccoError::new(ErrorKind::Config, format!("Invalid {:?} file: {}", format, e));
+    let values: BTreeMap<String, String> = match format {
+        Format::Json => {
+            let map: BTreeMap<String, serde_json::Value> = serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?;
+            let mut values = BTreeMap::new();
+            for (id, value) in map {
+                let value = match value {
+                    serde_json::Value::String(s) => s,
+                    serde_json::Value::Number(n) => n.to_string(),
+                    serde_json::Value::Bool(b) => b.to_string(),
+                    _ => return Err(invalid(format!("the value of {} is not a string", id)))
+                };
+                values.insert(id, value);
+            }
+            values
+        },
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| invalid(e.to_string()))?,
+        Format::Dotenv => parse_dotenv(text).map_err(&invalid)?,
+        Format::Shell => return Err(MoroccoError::new(ErrorKind::Config, "Secrets cannot be read from shell scripts.".to_string()))
+    };
+    Ok(values.into_iter().map(|(id, value)| (id, value.into_bytes())).collect())
+}
+
+// Parse KEY=VALUE lines, ignoring blank lines, comments and a leading "export".
+// Values can be unquoted, single-quoted (taken literally) or double-quoted (with backslash escapes).
+fn parse_dotenv(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut values = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = if line.starts_with("export ") { line["export ".len()..].trim_left() } else { line };
+        let (key, value) = match line.find('=') {
+            Some(eq) if eq > 0 => (line[..eq].trim(), line[eq + 1..].trim()),
+            _ => return Err(format!("line {} is not of the form KEY=VALUE", i + 1))
+        };
+        let value = if value.starts_with('\'') {
+            match value[1..].find('\'') {
+                Some(end) => value[1..end + 1].to_string(),
+                None => return Err(format!("line {} has an unterminated single quote", i + 1))
+            }
+        } else if value.starts_with('"') {
+            unescape_double_quoted(&value[1..]).ok_or(format!("line {} has an unterminated double quote", i + 1))?
+        } else {
+            // an unquoted value ends at a comment
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_right().to_string(),
+                None => value.to_string()
+            }
+        };
+        values.insert(key.to_string(), value);
+    }
+    Ok(values)
+}
+
+// Read a double-quoted value up to its closing quote
+fn unescape_double_quoted(quoted: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next() {
+            Some('"') => return Some(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => return None
+            },
+            Some(c) => value.push(c),
+            None => return None
+        }
+    }
+}
+
+// Single quotes keep the value literal in every dotenv implementation,
+// but they cannot contain single quotes or newlines, so fall back to escaped double quotes.
+// Some implementations expand variables and commands inside double quotes, so $ and ` are escaped too.
+fn dotenv_quote(value: &str) -> String {
+    if !value.contains('\'') && !value.contains('\n') && !value.contains('\r') {
+        format!("'{}'", value)
+    } else {
+        let escaped = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+            .replace('`', "\\`")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\"{}\"", escaped)
+    }
+}
+
+// Quote a value so that a POSIX shell reads it back unchanged
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> BTreeMap<String, Secret> {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("app.prod.db.password".to_string(), Secret::from("it's a \"secret\""));
+        secrets.insert("app.prod.api-key".to_string(), Secret::from("abc$123"));
+        secrets
+    }
+
+    #[test]
+    fn env_var_names() {
+        assert_eq!("DB_PASSWORD", env_var_name("app.prod.db.password", Some("app.prod.")));
+        assert_eq!("APP_PROD_API_KEY", env_var_name("app.prod.api-key", None));
+        assert_eq!("_1PASSWORD", env_var_name("1password", None));
+    }
+
+    #[test]
+    fn dotenv_and_shell_quoting() {
+        assert_eq!("API_KEY='abc$123'\nDB_PASSWORD=\"it's a \\\"secret\\\"\"\n",
+                   render(Format::Dotenv, &secrets(), Some("app.prod.")).ok().unwrap());
+        assert_eq!("export API_KEY='abc$123'\nexport DB_PASSWORD='it'\\''s a \"secret\"'\n",
+                   render(Format::Shell, &secrets(), Some("app.prod.")).ok().unwrap());
+
+        let mut secrets = BTreeMap::new();
+        secrets.insert("token".to_string(), Secret::from("it's $HOME `id`"));
+        assert_eq!("TOKEN=\"it's \\$HOME \\`id\\`\"\n", render(Format::Dotenv, &secrets, None).ok().unwrap());
+    }
+
+    #[test]
+    fn dotenv_round_trip() {
+        let mut secrets = secrets();
+        secrets.insert("app.prod.multi-line".to_string(), Secret::from("one\ntwo \\ three"));
+        secrets.insert("app.prod.token".to_string(), Secret::from("it's $HOME `id`"));
+        let rendered = render(Format::Dotenv, &secrets, Some("app.prod.")).ok().unwrap();
+        let parsed = parse(Format::Dotenv, &format!("# a comment\n\n{}export PLAIN=value # a comment\n", rendered)).ok().unwrap();
+        assert_eq!(Some(&b"it's a \"secret\"".to_vec()), parsed.get("DB_PASSWORD"));
+        assert_eq!(Some(&b"abc$123".to_vec()), parsed.get("API_KEY"));
+        assert_eq!(Some(&b"one\ntwo \\ three".to_vec()), parsed.get("MULTI_LINE"));
+        assert_eq!(Some(&b"it's $HOME `id`".to_vec()), parsed.get("TOKEN"));
+        assert_eq!(Some(&b"value".to_vec()), parsed.get("PLAIN"));
+    }
+
+    #[test]
+    fn yaml_values() {
+        let parsed = parse(Format::Yaml, "db.password: hunter2\ndb.port: 5432\n").ok().unwrap();
+        assert_eq!(Some(&b"hunter2".to_vec()), parsed.get("db.password"));
+        assert_eq!(Some(&b"5432".to_vec()), parsed.get("db.port"));
+    }
+
+    #[test]
+    fn json_keeps_ids() {
+        let json: BTreeMap<String, String> = serde_json::from_str(&render(Format::Json, &secrets(), None).ok().unwrap()).unwrap();
+        assert_eq!(Some(&"abc$123".to_string()), json.get("app.prod.api-key"));
+    }
+}
@@ -0,0 +1,278 @@
+extern crate base64;
+extern crate chrono;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+
+use morocco::{MoroccoError, ErrorKind, PutResult, DeletionResult, VersionInfo, Metadata};
+use encryption::Cipher;
+use gcp::Item;
+use gcp::http::HttpClient;
+
+use self::base64::{encode, decode};
+use self::chrono::Utc;
+use self::serde_json::Value;
+
+// Each version of a secret is stored as a separate entity, with a key name of "{id}@{version}".
+// Looking up the latest version requires a composite index on (id, version descending).
+pub struct DatastoreOps {
+    // {endpoint}/v1/projects/{project}
+    project_url: String,
+    project: String,
+    kind: String,
+    http_client: Box<HttpClient>
+}
+
+impl DatastoreOps {
+
+    pub fn new(endpoint: String, project: String, kind: String, http_client: Box<HttpClient>) -> DatastoreOps {
+        DatastoreOps {
+            project_url: format!("{}/v1/projects/{}", endpoint, project),
+            project: project,
+            kind: kind,
+            http_client: http_client
+        }
+    }
+
+    pub fn list_ids(&self) -> Result<Vec<String>, MoroccoError> {
+        let mut ids = Vec::new();
+        self.scan_ids(None, &mut |id| ids.push(id))?;
+        Ok(ids)
+    }
+
+    // Pass each secret ID to the callback as soon as its batch of query results has been read
+    pub fn scan_ids(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        let mut query = json!({
+            "kind": [ { "name": self.kind } ],
+            "projection": [ { "property": { "name": "id" } } ],
+            "distinctOn": [ { "name": "id" } ]
+        });
+        if let Some(l) = limit {
+            query["limit"] = json!(l);
+        }
+        let mut found = 0;
+        self.run_query(query, &mut |entity| {
+            if limit.map_or(true, |l| found < l) {
+                if let Some(id) = entity["properties"]["id"]["stringValue"].as_str() {
+                    found += 1;
+                    callback(id.to_string());
+                }
+            }
+        })
+    }
+
+    // Get the latest version of the secret with the given ID, along with its metadata
+    pub fn get_latest_item(&self, id: String) -> Result<(Item, Metadata), MoroccoError> {
+        match self.query_latest(id)? {
+            Some(entity) => Ok((entity_to_item(&entity)?, entity_to_version_info(&entity)?.metadata)),
+            None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+        }
+    }
+
+    pub fn get_item(&self, id: String, version: u64) -> Result<(Item, Metadata), MoroccoError> {
+        let request = json!({ "keys": [ self.entity_key(&id, version) ] });
+        let response = self.http_client.post(&format!("{}:lookup", self.project_url), &request)?.into_result()?;
+        match response["found"].as_array().and_then(|found| found.first()) {
+            Some(result) => Ok((entity_to_item(&result["entity"])?, entity_to_version_info(&result["entity"])?.metadata)),
+            None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID and version.".to_string()))
+        }
+    }
+
+    // List all versions of the secret with the given ID, oldest first
+    pub fn list_versions(&self, id: String) -> Result<Vec<VersionInfo>, MoroccoError> {
+        let query = json!({
+            "kind": [ { "name": self.kind } ],
+            "filter": id_filter(&id),
+            "order": [ { "property": { "name": "version" }, "direction": "ASCENDING" } ]
+        });
+        let mut entities = Vec::new();
+        self.run_query(query, &mut |entity| entities.push(entity))?;
+
+        let mut versions = Vec::new();
+        for entity in entities {
+            versions.push(entity_to_version_info(&entity)?);
+        }
+        Ok(versions)
+    }
+
+    // The latest version of the secret with the given ID, if there is one
+    pub fn latest_version(&self, id: String) -> Result<Option<VersionInfo>, MoroccoError> {
+        match self.query_latest(id)? {
+            Some(entity) => Ok(Some(entity_to_version_info(&entity)?)),
+            None => Ok(None)
+        }
+    }
+
+    // Store the given version of a secret. Uses an insert mutation,
+    // so this will not overwrite an existing entity with the same ID and version.
+    pub fn put_item(&self, id: String, version: u64, item: Item, metadata: &Metadata) -> Result<PutResult, MoroccoError> {
+        let mut properties = json!({
+            "id": { "stringValue": id },
+            "version": { "integerValue": version.to_string() },
+            "created_at": { "stringValue": Utc::now().to_rfc3339(), "excludeFromIndexes": true },
+            "encrypted_data_key": { "blobValue": encode(&item.encrypted_data_key), "excludeFromIndexes": true },
+            "encrypted_data": { "blobValue": encode(&item.encrypted_data), "excludeFromIndexes": true },
+            "iv": { "blobValue": encode(&item.iv), "excludeFromIndexes": true },
+            "cipher": { "stringValue": item.cipher.name(), "excludeFromIndexes": true }
+        });
+        if let Some(ref hmac) = item.hmac {
+            properties["hmac"] = json!({ "blobValue": encode(hmac), "excludeFromIndexes": true });
+        }
+        if let Some(ref description) = metadata.description {
+            properties["description"] = json!({ "stringValue": description, "excludeFromIndexes": true });
+        }
+        if !metadata.tags.is_empty() {
+            let tags: BTreeMap<&String, Value> = metadata.tags.iter()
+                .map(|(key, value)| (key, json!({ "stringValue": value })))
+                .collect();
+            properties["tags"] = json!({ "entityValue": { "properties": tags }, "excludeFromIndexes": true });
+        }
+        if let Some(ref author) = metadata.author {
+            properties["author"] = json!({ "stringValue": author, "excludeFromIndexes": true });
+        }
+        if let Some(ref expires_at) = metadata.expires_at {
+            properties["expires_at"] = json!({ "stringValue": expires_at, "excludeFromIndexes": true });
+        }
+        let entity = json!({
+            "key": self.entity_key(&id, version),
+            "properties": properties
+        });
+        let request = json!({
+            "mode": "NON_TRANSACTIONAL",
+            "mutations": [ { "insert": entity } ]
+        });
+        let response = self.http_client.post(&format!("{}:commit", self.project_url), &request)?;
+        if response.status == 409 {
+            Ok(PutResult::DidNotOverwrite)
+        } else {
+            response.into_result()?;
+            Ok(PutResult::Stored)
+        }
+    }
+
+    // Delete all versions of the secret with the given ID
+    pub fn delete_item(&self, id: String) -> Result<DeletionResult, MoroccoError> {
+        let versions = self.list_versions(id.clone())?;
+        if versions.is_empty() {
+            return Ok(DeletionResult::NotFound);
+        }
+        let mutations: Vec<Value> = versions.iter()
+            .map(|v| json!({ "delete": self.entity_key(&id, v.version) }))
+            .collect();
+        // a single commit can contain at most 500 mutations
+        for batch in mutations.chunks(500) {
+            let request = json!({
+                "mode": "NON_TRANSACTIONAL",
+                "mutations": batch
+            });
+            self.http_client.post(&format!("{}:commit", self.project_url), &request)?.into_result()?;
+        }
+        Ok(DeletionResult::Deleted)
+    }
+
+    fn query_latest(&self, id: String) -> Result<Option<Value>, MoroccoError> {
+        let query = json!({
+            "kind": [ { "name": self.kind } ],
+            "filter": id_filter(&id),
+            "order": [ { "property": { "name": "version" }, "direction": "DESCENDING" } ],
+            "limit": 1
+        });
+        let mut latest = None;
+        self.run_query(query, &mut |entity| latest = Some(entity))?;
+        Ok(latest)
+    }
+
+    // Run a query, following the cursor until there are no more results
+    fn run_query(&self, query: Value, callback: &mut FnMut(Value)) -> Result<(), MoroccoError> {
+        let mut query = query;
+        loop {
+            let request = json!({
+                "partitionId": { "projectId": self.project },
+                "query": query.clone()
+            });
+            let response = self.http_client.post(&format!("{}:runQuery", self.project_url), &request)?.into_result()?;
+            let batch = &response["batch"];
+            if let Some(results) = batch["entityResults"].as_array() {
+                for result in results {
+                    callback(result["entity"].clone());
+                }
+            }
+            match (batch["moreResults"].as_str(), batch["endCursor"].as_str()) {
+                (Some("NOT_FINISHED"), Some(cursor)) => query["startCursor"] = json!(cursor),
+                _ => return Ok(())
+            }
+        }
+    }
+
+    fn entity_key(&self, id: &str, version: u64) -> Value {
+        json!({
+            "partitionId": { "projectId": self.project },
+            "path": [ { "kind": self.kind, "name": format!("{}@{}", id, version) } ]
+        })
+    }
+
+}
+
+fn id_filter(id: &str) -> Value {
+    json!({
+        "propertyFilter": {
+            "property": { "name": "id" },
+            "op": "EQUAL",
+            "value": { "stringValue": id }
+        }
+    })
+}
+
+fn entity_to_version(entity: &Value) -> Result<u64, MoroccoError> {
+    match entity["properties"]["version"]["integerValue"].as_str() {
+        Some(version) => Ok(version.parse::<u64>()?),
+        None => Err(MoroccoError::new(ErrorKind::Backend, "Entity did not contain a version".to_string()))
+    }
+}
+
+fn entity_to_version_info(entity: &Value) -> Result<VersionInfo, MoroccoError> {
+    let properties = &entity["properties"];
+    let string = |name: &str| properties[name]["stringValue"].as_str().map(|s| s.to_string());
+    let mut tags = BTreeMap::new();
+    if let Some(tag_properties) = properties["tags"]["entityValue"]["properties"].as_object() {
+        for (key, value) in tag_properties {
+            if let Some(value) = value["stringValue"].as_str() {
+                tags.insert(key.clone(), value.to_string());
+            }
+        }
+    }
+    Ok(VersionInfo {
+        version: entity_to_version(entity)?,
+        created_at: string("created_at").unwrap_or("unknown".to_string()),
+        metadata: Metadata {
+            description: string("description"),
+            tags: tags,
+            author: string("author"),
+            expires_at: string("expires_at")
+        }
+    })
+}
+
+fn entity_to_item(entity: &Value) -> Result<Item, MoroccoError> {
+    let properties = &entity["properties"];
+    let blob = |name: &str| properties[name]["blobValue"].as_str().map(|b| b.to_string());
+
+    let cipher_name = properties["cipher"]["stringValue"].as_str().unwrap_or("").to_string();
+    match (blob("encrypted_data_key"), blob("encrypted_data"), blob("iv"), Cipher::from_name(&cipher_name)) {
+        (Some(encrypted_key_base64), Some(encrypted_data_base64), Some(iv_base64), Some(cipher)) => {
+            let hmac = match blob("hmac") {
+                Some(hmac_base64) => Some(decode(&hmac_base64)?),
+                None => None
+            };
+            Ok(Item {
+                encrypted_data_key: decode(&encrypted_key_base64)?,
+                encrypted_data: decode(&encrypted_data_base64)?,
+                iv: decode(&iv_base64)?,
+                cipher: cipher,
+                hmac: hmac
+            })
+        },
+        (_, _, _, None) => Err(MoroccoError::new(ErrorKind::Backend, format!("Entity was encrypted using an unsupported cipher: {}", cipher_name))),
+        _ => Err(MoroccoError::new(ErrorKind::Backend, "Entity did not contain the expected properties".to_string()))
+    }
+}
@@ -0,0 +1,105 @@
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate serde_json;
+
+use std::io::Read;
+
+use self::hyper::Client;
+use self::hyper::client::RequestBuilder;
+use self::hyper::header::{Authorization, Bearer, ContentType};
+use self::hyper::net::HttpsConnector;
+use self::hyper_native_tls::NativeTlsClient;
+use self::serde_json::Value;
+
+use morocco::{MoroccoError, ErrorKind};
+
+pub struct Response {
+    pub status: u16,
+    pub body: Value
+}
+
+impl Response {
+
+    // Turn an error response from a Google API into a MoroccoError
+    pub fn into_result(self) -> Result<Value, MoroccoError> {
+        if self.status >= 200 && self.status < 300 {
+            Ok(self.body)
+        } else {
+            let message = self.body.pointer("/error/message")
+                .and_then(|m| m.as_str())
+                .map(|m| m.to_string())
+                .unwrap_or(format!("Request failed with HTTP status {}", self.status));
+            Err(MoroccoError::new(status_error_kind(self.status), message))
+        }
+    }
+
+}
+
+fn status_error_kind(status: u16) -> ErrorKind {
+    match status {
+        401 | 403 => ErrorKind::AccessDenied,
+        // missing secrets are reported in the response body, so this means a missing project, key ring or key
+        404 => ErrorKind::Config,
+        409 => ErrorKind::Conflict,
+        429 => ErrorKind::Throttled,
+        500...599 => ErrorKind::Unavailable,
+        _ => ErrorKind::Backend
+    }
+}
+
+// The minimal HTTP client needed to talk to the Google Cloud JSON APIs.
+// This is a trait so that the APIs can be replaced with fakes in tests.
+pub trait HttpClient {
+
+    fn get(&self, url: &str) -> Result<Response, MoroccoError>;
+
+    fn post(&self, url: &str, body: &Value) -> Result<Response, MoroccoError>;
+
+}
+
+pub struct HyperClient {
+    client: Client,
+    // not needed when talking to an emulator
+    access_token: Option<String>
+}
+
+impl HyperClient {
+
+    pub fn new(access_token: Option<String>) -> Result<HyperClient, MoroccoError> {
+        let tls = NativeTlsClient::new()?;
+        Ok(HyperClient {
+            client: Client::with_connector(HttpsConnector::new(tls)),
+            access_token: access_token
+        })
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Response, MoroccoError> {
+        let request = match self.access_token {
+            Some(ref token) => request.header(Authorization(Bearer { token: token.clone() })),
+            None => request
+        };
+        // failing to get any response at all means the API could not be reached
+        let mut response = request.send().map_err(|e| MoroccoError::with_source(ErrorKind::Unavailable, e))?;
+        let mut body = String::new();
+        response.read_to_string(&mut body)?;
+        let json = if body.trim().is_empty() { Value::Null } else { serde_json::from_str(&body)? };
+        Ok(Response {
+            status: response.status.to_u16(),
+            body: json
+        })
+    }
+
+}
+
+impl HttpClient for HyperClient {
+
+    fn get(&self, url: &str) -> Result<Response, MoroccoError> {
+        self.send(self.client.get(url))
+    }
+
+    fn post(&self, url: &str, body: &Value) -> Result<Response, MoroccoError> {
+        let body = body.to_string();
+        self.send(self.client.post(url).header(ContentType::json()).body(body.as_str()))
+    }
+
+}
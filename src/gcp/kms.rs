@@ -0,0 +1,109 @@
+extern crate base64;
+
+use morocco::{MoroccoError, ErrorKind, Secret};
+use gcp::http::HttpClient;
+
+use self::base64::{encode, decode};
+
+pub struct CloudKmsOps {
+    endpoint: String,
+    // projects/{project}/locations/{location}
+    location_name: String,
+    key_ring: String,
+    key: String,
+    http_client: Box<HttpClient>
+}
+
+impl CloudKmsOps {
+
+    pub fn new(endpoint: String,
+               project: String,
+               location: String,
+               key_ring: String,
+               key: String,
+               http_client: Box<HttpClient>) -> CloudKmsOps {
+        CloudKmsOps {
+            endpoint: endpoint,
+            location_name: format!("projects/{}/locations/{}", project, location),
+            key_ring: key_ring,
+            key: key,
+            http_client: http_client
+        }
+    }
+
+    pub fn create_key_ring_if_does_not_exist(&self) -> Result<&str, MoroccoError> {
+        let key_ring_url = format!("{}/v1/{}", self.endpoint, self.key_ring_name());
+        if self.does_resource_exist(&key_ring_url)? {
+            Ok("Key ring already existed.")
+        } else {
+            let create_url = format!("{}/v1/{}/keyRings?keyRingId={}", self.endpoint, self.location_name, self.key_ring);
+            self.http_client.post(&create_url, &json!({}))?.into_result()?;
+            Ok("Created key ring.")
+        }
+    }
+
+    pub fn create_key_if_does_not_exist(&self) -> Result<&str, MoroccoError> {
+        let key_url = format!("{}/v1/{}", self.endpoint, self.key_name());
+        if self.does_resource_exist(&key_url)? {
+            Ok("Crypto key already existed.")
+        } else {
+            let create_url = format!("{}/v1/{}/cryptoKeys?cryptoKeyId={}", self.endpoint, self.key_ring_name(), self.key);
+            self.http_client.post(&create_url, &json!({ "purpose": "ENCRYPT_DECRYPT" }))?.into_result()?;
+            Ok("Created crypto key.")
+        }
+    }
+
+    // Encrypt a locally generated data key. The additional authenticated data
+    // must be passed again, unchanged, in order to decrypt it.
+    pub fn encrypt_data_key(&self, plaintext_key: &[u8], aad: &[u8]) -> Result<Vec<u8>, MoroccoError> {
+        let url = format!("{}/v1/{}:encrypt", self.endpoint, self.key_name());
+        let request = json!({
+            "plaintext": encode(plaintext_key),
+            "additionalAuthenticatedData": encode(aad)
+        });
+        let response = self.http_client.post(&url, &request)?.into_result()?;
+        match response["ciphertext"].as_str() {
+            Some(ciphertext) => Ok(decode(ciphertext)?),
+            None => Err(MoroccoError::new(ErrorKind::Backend, "Failed to encrypt the data key".to_string()))
+        }
+    }
+
+    pub fn decrypt_data_key(&self, encrypted_key: &[u8], aad: &[u8]) -> Result<Secret, MoroccoError> {
+        let url = format!("{}/v1/{}:decrypt", self.endpoint, self.key_name());
+        let request = json!({
+            "ciphertext": encode(encrypted_key),
+            "additionalAuthenticatedData": encode(aad)
+        });
+        let response = self.http_client.post(&url, &request)?;
+        // Cloud KMS rejects a ciphertext that was modified or is being decrypted with different AAD
+        if response.status == 400 {
+            return Err(MoroccoError::new(
+                ErrorKind::IntegrityFailure,
+                "Failed to decrypt the data key. It may have been tampered with or copied from another secret.".to_string()));
+        }
+        let response = response.into_result()?;
+        match response["plaintext"].as_str() {
+            Some(plaintext) => Ok(Secret::new(decode(plaintext)?)),
+            None => Err(MoroccoError::new(ErrorKind::Backend, "Failed to decrypt the data key".to_string()))
+        }
+    }
+
+    fn does_resource_exist(&self, url: &str) -> Result<bool, MoroccoError> {
+        let response = self.http_client.get(url)?;
+        if response.status == 404 {
+            Ok(false)
+        } else {
+            response.into_result()?;
+            Ok(true)
+        }
+    }
+
+    fn key_ring_name(&self) -> String {
+        format!("{}/keyRings/{}", self.location_name, self.key_ring)
+    }
+
+    fn key_name(&self) -> String {
+        format!("{}/cryptoKeys/{}", self.key_ring_name(), self.key)
+    }
+
+}
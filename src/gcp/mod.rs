@@ -0,0 +1,454 @@
+extern crate rand;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::process::Command;
+
+use self::rand::{Rng, OsRng};
+
+use morocco::*;
+use encryption::*;
+
+mod http;
+mod kms;
+mod datastore;
+
+use gcp::http::{HttpClient, HyperClient};
+use gcp::kms::CloudKmsOps;
+use gcp::datastore::DatastoreOps;
+
+const DEFAULT_KMS_ENDPOINT: &str = "https://cloudkms.googleapis.com";
+const DEFAULT_DATASTORE_ENDPOINT: &str = "https://datastore.googleapis.com";
+const TOKEN_INFO_ENDPOINT: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+pub struct Item {
+    encrypted_data_key: Vec<u8>,
+    encrypted_data: Vec<u8>,
+    iv: Vec<u8>,
+    cipher: Cipher,
+    hmac: Option<Vec<u8>>
+}
+
+pub struct GCP {
+    collection: String,
+    // the gcloud account, recorded as the author of new versions
+    author: Option<String>,
+    // None when only talking to emulators and fakes
+    access_token: Option<String>,
+    // asks Google who the access token belongs to
+    token_info_client: Box<HttpClient>,
+    kms_ops: CloudKmsOps,
+    datastore_ops: DatastoreOps
+}
+
+impl Morocco for GCP {
+
+    fn setup(&self) -> Result<String, MoroccoError> {
+        let create_key_ring_result = self.kms_ops.create_key_ring_if_does_not_exist()?;
+        let create_key_result = self.kms_ops.create_key_if_does_not_exist()?;
+        Ok(format!("{} {}", create_key_ring_result, create_key_result))
+    }
+
+    fn list(&self) -> Result<Vec<String>, MoroccoError> {
+        let mut result = self.datastore_ops.list_ids()?;
+        result.sort();
+        Ok(result)
+    }
+
+    fn list_streaming(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        self.datastore_ops.scan_ids(limit, callback)
+    }
+
+    fn get(&self, id: &str) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, None).map(|(value, _)| value)
+    }
+
+    fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, Some(version)).map(|(value, _)| value)
+    }
+
+    fn get_with_metadata(&self, id: &str, version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+        let (item, metadata) = match version {
+            Some(version) => self.datastore_ops.get_item(id.to_string(), version)?,
+            None => self.datastore_ops.get_latest_item(id.to_string())?
+        };
+        Ok((self.decrypt_item(id, item)?, metadata))
+    }
+
+    fn put(&self, id: &str, value: &[u8], overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError> {
+        match self.datastore_ops.latest_version(id.to_string())? {
+            Some(_) if !overwrite => Ok(PutResult::DidNotOverwrite),
+            latest => {
+                let item = self.encrypt_value(id, value)?;
+                let previous = latest.as_ref().map(|v| v.metadata.clone()).unwrap_or_default();
+                let metadata = previous.updated(metadata, self.author.clone());
+                self.put_new_version(id.to_string(), latest.map(|v| v.version), item, metadata)
+            }
+        }
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<VersionInfo>, MoroccoError> {
+        self.datastore_ops.list_versions(id.to_string())
+    }
+
+    fn rollback(&self, id: &str, version: u64) -> Result<RollbackResult, MoroccoError> {
+        let versions = self.datastore_ops.list_versions(id.to_string())?;
+        if !versions.iter().any(|v| v.version == version) {
+            return Ok(RollbackResult::NotFound);
+        }
+        // The data key is bound to the secret ID, not the version, so the old entity can be copied as is
+        let (item, restored) = self.datastore_ops.get_item(id.to_string(), version)?;
+        let latest = versions.last().map(|v| v.version);
+        // the metadata describes the secret rather than the value, so keep the latest, apart from the expiry date of the value
+        let update = MetadataUpdate { expires_at: restored.expires_at, ..Default::default() };
+        let metadata = versions.last().map(|v| v.metadata.clone()).unwrap_or_default()
+            .updated(&update, self.author.clone());
+        self.put_new_version(id.to_string(), latest, item, metadata)?;
+        Ok(RollbackResult::RolledBack(latest.unwrap_or(0) + 1))
+    }
+
+    fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError> {
+        self.datastore_ops.delete_item(id.to_string())
+    }
+
+    // The account that the access token was issued to, which may not be the gcloud account
+    // if the token came from GOOGLE_OAUTH_ACCESS_TOKEN
+    fn caller_identity(&self) -> Result<Option<String>, MoroccoError> {
+        let token = match self.access_token {
+            Some(ref token) => token,
+            None => return Ok(None)
+        };
+        let info = self.token_info_client.get(&format!("{}?access_token={}", TOKEN_INFO_ENDPOINT, token))?.into_result()?;
+        // the email is only given if the token has the email scope, but the account's unique ID always is
+        match info["email"].as_str().or(info["sub"].as_str()) {
+            Some(account) => Ok(Some(account.to_string())),
+            None => Err(MoroccoError::new(ErrorKind::Backend, "Google did not say who the access token belongs to.".to_string()))
+        }
+    }
+
+}
+
+impl GCP {
+
+    // Endpoints are only needed when talking to emulators or fakes.
+    // If no Datastore endpoint is given, the DATASTORE_EMULATOR_HOST environment variable is honoured.
+    pub fn new(project: String,
+               location: String,
+               key_ring: String,
+               key: String,
+               collection: String,
+               kms_endpoint: Option<String>,
+               datastore_endpoint: Option<String>) -> Result<GCP, MoroccoError> {
+        let datastore_endpoint = datastore_endpoint.or(
+            env::var("DATASTORE_EMULATOR_HOST").ok().map(|host| format!("http://{}", host)));
+
+        // Emulators and fakes don't need an access token
+        let token = if kms_endpoint.is_none() || datastore_endpoint.is_none() {
+            Some(GCP::access_token()?)
+        } else {
+            None
+        };
+
+        let kms_client = HyperClient::new(if kms_endpoint.is_none() { token.clone() } else { None })?;
+        let kms_ops = CloudKmsOps::new(kms_endpoint.unwrap_or(DEFAULT_KMS_ENDPOINT.to_string()),
+                                       project.clone(), location, key_ring, key, Box::new(kms_client));
+
+        let datastore_client = HyperClient::new(if datastore_endpoint.is_none() { token.clone() } else { None })?;
+        let datastore_ops = DatastoreOps::new(datastore_endpoint.unwrap_or(DEFAULT_DATASTORE_ENDPOINT.to_string()),
+                                              project, collection.clone(), Box::new(datastore_client));
+
+        let author = if token.is_some() { GCP::account() } else { None };
+
+        Ok(GCP {
+            collection: collection,
+            author: author,
+            access_token: token,
+            token_info_client: Box::new(HyperClient::new(None)?),
+            kms_ops: kms_ops,
+            datastore_ops: datastore_ops
+        })
+    }
+
+    // Use the access token from the environment if there is one, otherwise ask gcloud
+    fn access_token() -> Result<String, MoroccoError> {
+        if let Ok(token) = env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+            return Ok(token);
+        }
+        match Command::new("gcloud").args(&["auth", "print-access-token"]).output() {
+            Ok(ref output) if output.status.success() =>
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+            _ => Err(MoroccoError::new(
+                ErrorKind::AccessDenied,
+                "Failed to get a Google Cloud access token. Please set GOOGLE_OAUTH_ACCESS_TOKEN or log in with gcloud.".to_string()))
+        }
+    }
+
+    // The account gcloud is logged in as, if any. Only used for the metadata, so failures are ignored.
+    fn account() -> Option<String> {
+        match Command::new("gcloud").args(&["config", "get-value", "account"]).output() {
+            Ok(ref output) if output.status.success() => {
+                let account = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if account.is_empty() { None } else { Some(account) }
+            },
+            _ => None
+        }
+    }
+
+    fn put_new_version(&self, id: String, latest_version: Option<u64>, item: Item, metadata: Metadata) -> Result<PutResult, MoroccoError> {
+        let version = latest_version.unwrap_or(0) + 1;
+        match self.datastore_ops.put_item(id, version, item, &metadata)? {
+            PutResult::Stored => Ok(PutResult::Stored),
+            // Somebody else stored the same version between us reading the latest version and writing
+            PutResult::DidNotOverwrite if latest_version.is_some() =>
+                Err(MoroccoError::new(ErrorKind::Conflict, "Another version of the secret was stored concurrently. Please try again.".to_string())),
+            PutResult::DidNotOverwrite => Ok(PutResult::DidNotOverwrite)
+        }
+    }
+
+    // The additional authenticated data that binds a data key to its secret,
+    // so that an encrypted data key copied from one entity to another will fail to decrypt.
+    fn additional_authenticated_data(&self, id: &str) -> Result<Vec<u8>, MoroccoError> {
+        let mut aad = BTreeMap::new();
+        aad.insert("collection", self.collection.as_str());
+        aad.insert("id", id);
+        Ok(serde_json::to_vec(&aad)?)
+    }
+
+    fn encrypt_value(&self, id: &str, value: &[u8]) -> Result<Item, MoroccoError> {
+        let cipher = Cipher::AesGcm;
+        let mut rng = OsRng::new()?;
+        let mut iv = vec![0; cipher.iv_length()];
+        let mut data_key = Secret::new(vec![0; DATA_KEY_LENGTH]);
+        rng.fill_bytes(&mut iv);
+        rng.fill_bytes(data_key.as_mut_bytes());
+
+        let (ciphertext, hmac) = seal(cipher, value, data_key.as_bytes(), iv.as_slice())?;
+        let encrypted_data_key = self.kms_ops.encrypt_data_key(data_key.as_bytes(), &self.additional_authenticated_data(id)?)?;
+        Ok(Item {
+            encrypted_data_key: encrypted_data_key,
+            encrypted_data: ciphertext,
+            iv: iv,
+            cipher: cipher,
+            hmac: Some(hmac)
+        })
+    }
+
+    fn decrypt_item(&self, id: &str, item: Item) -> Result<Secret, MoroccoError> {
+        let plaintext_key = self.kms_ops.decrypt_data_key(&item.encrypted_data_key, &self.additional_authenticated_data(id)?)?;
+        let plaintext = open(item.cipher,
+                             item.encrypted_data.as_slice(),
+                             plaintext_key.as_bytes(),
+                             item.iv.as_slice(),
+                             item.hmac.as_ref().map(|h| h.as_slice()))?;
+        Ok(plaintext)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate base64;
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use self::serde_json::Value;
+    use gcp::http::{HttpClient, Response};
+
+    // A fake of the Cloud KMS API. The "ciphertext" is just the plaintext and AAD wrapped in JSON.
+    struct FakeKms {
+        resources: RefCell<Vec<String>>
+    }
+
+    impl HttpClient for FakeKms {
+        fn get(&self, url: &str) -> Result<Response, MoroccoError> {
+            let status = if self.resources.borrow().iter().any(|r| r == url) { 200 } else { 404 };
+            Ok(Response { status: status, body: json!({}) })
+        }
+
+        fn post(&self, url: &str, body: &Value) -> Result<Response, MoroccoError> {
+            if url.ends_with(":encrypt") {
+                let wrapped = serde_json::to_vec(body).unwrap();
+                Ok(Response { status: 200, body: json!({ "ciphertext": base64::encode(&wrapped) }) })
+            } else if url.ends_with(":decrypt") {
+                let wrapped: Value = serde_json::from_slice(&base64::decode(body["ciphertext"].as_str().unwrap()).unwrap()).unwrap();
+                if wrapped["additionalAuthenticatedData"] == body["additionalAuthenticatedData"] {
+                    Ok(Response { status: 200, body: json!({ "plaintext": wrapped["plaintext"] }) })
+                } else {
+                    Ok(Response { status: 400, body: json!({ "error": { "message": "Decryption failed" } }) })
+                }
+            } else {
+                // e.g. {parent}/keyRings?keyRingId=morocco creates {parent}/keyRings/morocco
+                let (collection, id) = url.split_at(url.find('?').unwrap());
+                let (_, id) = id.split_at(id.find('=').unwrap() + 1);
+                self.resources.borrow_mut().push(format!("{}/{}", collection, id));
+                Ok(Response { status: 200, body: json!({}) })
+            }
+        }
+    }
+
+    // A fake of the OAuth token info endpoint, which knows about a single access token
+    struct FakeTokenInfo;
+
+    impl HttpClient for FakeTokenInfo {
+        fn get(&self, url: &str) -> Result<Response, MoroccoError> {
+            if url.ends_with("?access_token=ya29.chris") {
+                Ok(Response { status: 200, body: json!({ "sub": "1234567890", "email": "chris@example.com", "expires_in": "3599" }) })
+            } else {
+                Ok(Response { status: 400, body: json!({ "error": "invalid_token" }) })
+            }
+        }
+
+        fn post(&self, _url: &str, _body: &Value) -> Result<Response, MoroccoError> {
+            Ok(Response { status: 404, body: json!({}) })
+        }
+    }
+
+    // A fake of the Datastore API, supporting just the queries that DatastoreOps makes
+    struct FakeDatastore {
+        entities: RefCell<BTreeMap<String, Value>>
+    }
+
+    impl HttpClient for FakeDatastore {
+        fn get(&self, _url: &str) -> Result<Response, MoroccoError> {
+            Ok(Response { status: 404, body: json!({}) })
+        }
+
+        fn post(&self, url: &str, body: &Value) -> Result<Response, MoroccoError> {
+            let mut entities = self.entities.borrow_mut();
+            let name = |key: &Value| key["path"][0]["name"].as_str().unwrap().to_string();
+            if url.ends_with(":lookup") {
+                let found: Vec<Value> = body["keys"].as_array().unwrap().iter()
+                    .flat_map(|key| entities.get(&name(key)))
+                    .map(|entity| json!({ "entity": entity }))
+                    .collect();
+                Ok(Response { status: 200, body: json!({ "found": found }) })
+            } else if url.ends_with(":commit") {
+                for mutation in body["mutations"].as_array().unwrap() {
+                    if mutation["insert"].is_object() {
+                        let entity = mutation["insert"].clone();
+                        if entities.contains_key(&name(&entity["key"])) {
+                            return Ok(Response { status: 409, body: json!({}) });
+                        }
+                        entities.insert(name(&entity["key"]), entity);
+                    } else {
+                        entities.remove(&name(&mutation["delete"]));
+                    }
+                }
+                Ok(Response { status: 200, body: json!({}) })
+            } else {
+                let query = &body["query"];
+                let id_filter = query["filter"]["propertyFilter"]["value"]["stringValue"].as_str();
+                let version = |e: &Value| e["properties"]["version"]["integerValue"].as_str().unwrap().parse::<u64>().unwrap();
+                let mut results: Vec<Value> = entities.values()
+                    .filter(|e| id_filter.map_or(true, |id| e["properties"]["id"]["stringValue"] == json!(id)))
+                    .cloned()
+                    .collect();
+                results.sort_by_key(|e| version(e));
+                if query["order"][0]["direction"] == json!("DESCENDING") {
+                    results.reverse();
+                }
+                if query["distinctOn"].is_array() {
+                    results.dedup_by_key(|e| e["properties"]["id"].clone());
+                }
+                if let Some(limit) = query["limit"].as_u64() {
+                    results.truncate(limit as usize);
+                }
+                let entity_results: Vec<Value> = results.into_iter().map(|e| json!({ "entity": e })).collect();
+                Ok(Response { status: 200, body: json!({ "batch": { "entityResults": entity_results, "moreResults": "NO_MORE_RESULTS" } }) })
+            }
+        }
+    }
+
+    fn fake_gcp() -> GCP {
+        let kms = FakeKms { resources: RefCell::new(Vec::new()) };
+        let datastore = FakeDatastore { entities: RefCell::new(BTreeMap::new()) };
+        GCP {
+            collection: "morocco".to_string(),
+            author: Some("chris@example.com".to_string()),
+            access_token: Some("ya29.chris".to_string()),
+            token_info_client: Box::new(FakeTokenInfo),
+            kms_ops: CloudKmsOps::new("http://kms".to_string(), "project".to_string(), "global".to_string(),
+                                      "morocco".to_string(), "morocco".to_string(), Box::new(kms)),
+            datastore_ops: DatastoreOps::new("http://datastore".to_string(), "project".to_string(),
+                                             "morocco".to_string(), Box::new(datastore))
+        }
+    }
+
+    #[test]
+    fn setup_put_get_delete() {
+        let gcp = fake_gcp();
+        assert_eq!("Created key ring. Created crypto key.", gcp.setup().ok().unwrap());
+        assert_eq!("Key ring already existed. Crypto key already existed.", gcp.setup().ok().unwrap());
+
+        assert_eq!(PutResult::Stored, gcp.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(PutResult::DidNotOverwrite, gcp.put("db.password", b"other", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(PutResult::Stored, gcp.put("db.password", b"newer", true, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(b"newer", gcp.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(b"secret", gcp.get_version("db.password", 1).ok().unwrap().as_bytes());
+        assert_eq!(vec!["db.password".to_string()], gcp.list().ok().unwrap());
+
+        assert_eq!(RollbackResult::RolledBack(3), gcp.rollback("db.password", 1).ok().unwrap());
+        assert_eq!(b"secret", gcp.get("db.password").ok().unwrap().as_bytes());
+
+        assert_eq!(DeletionResult::Deleted, gcp.delete("db.password").ok().unwrap());
+        assert_eq!(DeletionResult::NotFound, gcp.delete("db.password").ok().unwrap());
+        assert_eq!(ErrorKind::NotFound, gcp.get("db.password").err().unwrap().kind);
+    }
+
+    #[test]
+    fn metadata_is_kept_across_versions() {
+        let gcp = fake_gcp();
+        let mut tags = BTreeMap::new();
+        tags.insert("owner".to_string(), "payments".to_string());
+        let update = MetadataUpdate {
+            description: Some("Payments DB".to_string()),
+            tags: Some(tags.clone()),
+            expires_at: Some("2017-09-01T00:00:00+00:00".to_string())
+        };
+        gcp.put("db.password", b"one", false, &update).ok().unwrap();
+        gcp.put("db.password", b"two", true, &MetadataUpdate::default()).ok().unwrap();
+
+        let description = gcp.describe("db.password").ok().unwrap();
+        assert_eq!(2, description.version);
+        assert_eq!(Some("Payments DB".to_string()), description.metadata.description);
+        assert_eq!(tags, description.metadata.tags);
+        assert_eq!(Some("chris@example.com".to_string()), description.metadata.author);
+        // the new value has not been given an expiry date
+        assert_eq!(None, description.metadata.expires_at);
+        let first = &gcp.history("db.password").ok().unwrap()[0];
+        assert_eq!(Some("2017-09-01T00:00:00+00:00".to_string()), first.metadata.expires_at);
+        let (value, metadata) = gcp.get_with_metadata("db.password", Some(1)).ok().unwrap();
+        assert_eq!(b"one", value.as_bytes());
+        assert_eq!(first.metadata, metadata);
+        assert_eq!(description.metadata, gcp.get_with_metadata("db.password", None).ok().unwrap().1);
+        assert_eq!(1, gcp.describe_all().ok().unwrap().len());
+        assert_eq!(ErrorKind::NotFound, gcp.describe("other").err().unwrap().kind);
+
+        // rolling back restores the expiry date of the old value
+        gcp.rollback("db.password", 1).ok().unwrap();
+        let rolled_back = gcp.describe("db.password").ok().unwrap();
+        assert_eq!(Some("Payments DB".to_string()), rolled_back.metadata.description);
+        assert_eq!(Some("2017-09-01T00:00:00+00:00".to_string()), rolled_back.metadata.expires_at);
+    }
+
+    #[test]
+    fn caller_identity_is_the_owner_of_the_access_token() {
+        let mut gcp = fake_gcp();
+        assert_eq!(Some("chris@example.com".to_string()), gcp.caller_identity().ok().unwrap());
+        gcp.access_token = Some("ya29.expired".to_string());
+        assert!(gcp.caller_identity().is_err());
+        // emulators don't check who is calling
+        gcp.access_token = None;
+        assert_eq!(None, gcp.caller_identity().ok().unwrap());
+    }
+
+    #[test]
+    fn data_key_is_bound_to_secret_id() {
+        let gcp = fake_gcp();
+        gcp.put("a", b"secret", false, &MetadataUpdate::default()).ok().unwrap();
+        let (item, _) = gcp.datastore_ops.get_latest_item("a".to_string()).ok().unwrap();
+        assert_eq!(ErrorKind::IntegrityFailure, gcp.decrypt_item("b", item).err().unwrap().kind);
+    }
+}
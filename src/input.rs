@@ -0,0 +1,54 @@
+extern crate rpassword;
+
+use std::fs::File;
+use std::io::stdin;
+
+use clap::ArgMatches;
+
+use morocco::{MoroccoError, ErrorKind, Secret};
+
+// Read the value of a secret from wherever the put command was told to get it:
+// the VALUE argument, stdin (if VALUE is "-"), a file, or an interactive prompt.
+pub fn read_value(matches: &ArgMatches) -> Result<Secret, MoroccoError> {
+    if let Some(path) = matches.value_of("file") {
+        read_file(path)
+    } else if matches.is_present("prompt") {
+        prompt_for_value()
+    } else {
+        match matches.value_of("VALUE") {
+            Some("-") => Ok(Secret::read_from(&mut stdin(), 0)?),
+            Some(value) => Ok(Secret::from(value)),
+            None => Err(MoroccoError::new(ErrorKind::Config, "No value given.".to_string()))
+        }
+    }
+}
+
+// Read a whole file as raw bytes, into a buffer of the right size if the file's length is known
+pub fn read_file(path: &str) -> Result<Secret, MoroccoError> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let size_hint = file.metadata().map(|m| m.len() as usize).unwrap_or(0);
+            Ok(Secret::read_from(&mut file, size_hint)?)
+        },
+        Err(e) => Err(MoroccoError::new(ErrorKind::Io, format!("Could not open {}: {}", path, e)))
+    }
+}
+
+// Ask for the current code of an MFA device, for assuming a role that requires one
+pub fn prompt_for_mfa_code(serial: &str) -> Result<String, MoroccoError> {
+    Ok(rpassword::prompt_password_stderr(&format!("MFA code for {}: ", serial))?)
+}
+
+// Ask for the value twice without echoing it to the terminal
+fn prompt_for_value() -> Result<Secret, MoroccoError> {
+    // taken over by Secrets straight away, so that both strings are zeroed when they are dropped
+    let value = Secret::new(rpassword::prompt_password_stderr("Secret value: ")?.into_bytes());
+    let confirmation = Secret::new(rpassword::prompt_password_stderr("Confirm secret value: ")?.into_bytes());
+    if value != confirmation {
+        Err(MoroccoError::new(ErrorKind::Config, "The values did not match.".to_string()))
+    } else if value.is_empty() {
+        Err(MoroccoError::new(ErrorKind::Config, "No value given.".to_string()))
+    } else {
+        Ok(value)
+    }
+}
@@ -0,0 +1,30 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+// The morocco library: the Morocco trait, the AWS, Google Cloud and local backends, a cache to put in front of them,
+// and the types they take and return. The morocco command line tool is a thin client of this.
+//
+// let morocco = morocco::AWS::builder().region("eu-west-1").build()?;
+// morocco.put("db.password", b"hunter2", false, &morocco::MetadataUpdate::default())?;
+// let password = morocco.get("db.password")?;
+
+mod morocco;
+mod secret;
+mod encryption;
+mod aws;
+mod gcp;
+mod local;
+mod cache;
+// used by the command line tool, but not part of the library's API
+#[doc(hidden)]
+pub mod files;
+
+pub use morocco::{Morocco, MoroccoError, ErrorKind, PutResult, DeletionResult, RollbackResult, ReencryptResult, SyncResult,
+                  VersionInfo, Metadata, MetadataUpdate, Description, Secret};
+pub use aws::{AWS, AWSBuilder};
+pub use gcp::GCP;
+pub use local::Local;
+pub use cache::CachedMorocco;
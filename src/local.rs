@@ -0,0 +1,396 @@
+extern crate crypto;
+extern crate rand;
+extern crate base64;
+extern crate chrono;
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use self::crypto::scrypt::{scrypt, ScryptParams};
+use self::rand::{Rng, OsRng};
+use self::base64::{encode, decode};
+use self::chrono::Utc;
+
+use morocco::*;
+use encryption::*;
+use files::create_private_file;
+
+// Version of the on-disk file format
+const FILE_FORMAT: u32 = 1;
+
+// scrypt parameters used when creating a new secrets file (N = 2^15, r = 8, p = 1)
+const DEFAULT_KDF_PARAMS: KdfParams = KdfParams { log_n: 15, r: 8, p: 1 };
+
+const SALT_LENGTH: usize = 16;
+
+// The secrets file as stored on disk. The KDF parameters and salt are in the clear,
+// everything else is encrypted using AES-256-GCM with a key derived from the passphrase.
+#[derive(Serialize, Deserialize)]
+struct SecretsFile {
+    format: u32,
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Secrets {
+    secrets: BTreeMap<String, Vec<StoredVersion>>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredVersion {
+    version: u64,
+    created_at: String,
+    value: String,
+    // files written before metadata was supported don't have these
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    expires_at: Option<String>
+}
+
+impl StoredVersion {
+
+    fn new(version: u64, value: String, metadata: Metadata) -> StoredVersion {
+        StoredVersion {
+            version: version,
+            created_at: Utc::now().to_rfc3339(),
+            value: value,
+            description: metadata.description,
+            tags: metadata.tags,
+            author: metadata.author,
+            expires_at: metadata.expires_at
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            description: self.description.clone(),
+            tags: self.tags.clone(),
+            author: self.author.clone(),
+            expires_at: self.expires_at.clone()
+        }
+    }
+
+}
+
+// A store kept in a single passphrase-encrypted file on local disk.
+// Useful for offline development and testing. There is no locking,
+// so concurrent writers may lose each other's updates.
+pub struct Local {
+    path: PathBuf,
+    passphrase: String,
+    kdf_params: KdfParams,
+    // the most recently derived key, along with the salt it was derived from
+    derived_key: RefCell<Option<(Vec<u8>, Secret)>>
+}
+
+impl Morocco for Local {
+
+    fn setup(&self) -> Result<String, MoroccoError> {
+        if self.path.exists() {
+            // make sure the passphrase is correct
+            self.load()?;
+            Ok("Secrets file already existed.".to_string())
+        } else {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut salt = vec![0; SALT_LENGTH];
+            OsRng::new()?.fill_bytes(&mut salt);
+            self.save(&Secrets::default(), &salt, self.kdf_params)?;
+            Ok(format!("Created secrets file {}.", self.path.display()))
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, MoroccoError> {
+        let (secrets, _, _) = self.load()?;
+        Ok(secrets.secrets.keys().cloned().collect())
+    }
+
+    fn get(&self, id: &str) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, None).map(|(value, _)| value)
+    }
+
+    fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError> {
+        self.get_with_metadata(id, Some(version)).map(|(value, _)| value)
+    }
+
+    fn get_with_metadata(&self, id: &str, version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+        let (secrets, _, _) = self.load()?;
+        let versions = secrets.secrets.get(id);
+        let found = match version {
+            Some(version) => versions.and_then(|versions| versions.iter().find(|v| v.version == version)),
+            None => versions.and_then(|versions| versions.last())
+        };
+        match (found, version) {
+            (Some(stored), _) => Ok((Secret::new(decode(&stored.value)?), stored.metadata())),
+            (None, Some(_)) => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID and version.".to_string())),
+            (None, None) => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+        }
+    }
+
+    fn get_all(&self, prefix: Option<&str>) -> Result<BTreeMap<String, Secret>, MoroccoError> {
+        let (secrets, _, _) = self.load()?;
+        let mut values = BTreeMap::new();
+        for (id, versions) in secrets.secrets {
+            if let Some(latest) = versions.last() {
+                if prefix.map_or(true, |p| id.starts_with(p)) {
+                    values.insert(id.clone(), Secret::new(decode(&latest.value)?));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    fn put(&self, id: &str, value: &[u8], overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError> {
+        let (mut secrets, salt, kdf_params) = self.load()?;
+        {
+            let versions = secrets.secrets.entry(id.to_string()).or_insert(Vec::new());
+            if !versions.is_empty() && !overwrite {
+                return Ok(PutResult::DidNotOverwrite);
+            }
+            let version = versions.last().map(|v| v.version).unwrap_or(0) + 1;
+            let previous = versions.last().map(|v| v.metadata()).unwrap_or_default();
+            versions.push(StoredVersion::new(version, encode(value), previous.updated(metadata, author())));
+        }
+        self.save(&secrets, &salt, kdf_params)?;
+        Ok(PutResult::Stored)
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<VersionInfo>, MoroccoError> {
+        let (secrets, _, _) = self.load()?;
+        let versions = secrets.secrets.get(id).cloned().unwrap_or(Vec::new());
+        Ok(versions.into_iter()
+           .map(|v| VersionInfo { version: v.version, metadata: v.metadata(), created_at: v.created_at })
+           .collect())
+    }
+
+    fn rollback(&self, id: &str, version: u64) -> Result<RollbackResult, MoroccoError> {
+        let (mut secrets, salt, kdf_params) = self.load()?;
+        let new_version = match secrets.secrets.get_mut(id) {
+            Some(versions) => {
+                match versions.iter().find(|v| v.version == version).cloned() {
+                    Some(old) => {
+                        let new_version = versions.last().map(|v| v.version).unwrap_or(0) + 1;
+                        // the metadata describes the secret rather than the value, so keep the latest, apart from the expiry date of the value
+                        let update = MetadataUpdate { expires_at: old.metadata().expires_at, ..Default::default() };
+                        let metadata = versions.last().map(|v| v.metadata()).unwrap_or_default()
+                            .updated(&update, author());
+                        versions.push(StoredVersion::new(new_version, old.value, metadata));
+                        new_version
+                    },
+                    None => return Ok(RollbackResult::NotFound)
+                }
+            },
+            None => return Ok(RollbackResult::NotFound)
+        };
+        self.save(&secrets, &salt, kdf_params)?;
+        Ok(RollbackResult::RolledBack(new_version))
+    }
+
+    fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError> {
+        let (mut secrets, salt, kdf_params) = self.load()?;
+        match secrets.secrets.remove(id) {
+            Some(_) => {
+                self.save(&secrets, &salt, kdf_params)?;
+                Ok(DeletionResult::Deleted)
+            },
+            None => Ok(DeletionResult::NotFound)
+        }
+    }
+
+}
+
+impl Local {
+
+    pub fn new(path: PathBuf, passphrase: String) -> Local {
+        Local {
+            path: path,
+            passphrase: passphrase,
+            kdf_params: DEFAULT_KDF_PARAMS,
+            derived_key: RefCell::new(None)
+        }
+    }
+
+    fn load(&self) -> Result<(Secrets, Vec<u8>, KdfParams), MoroccoError> {
+        let mut json = String::new();
+        match File::open(&self.path) {
+            Ok(mut file) => { file.read_to_string(&mut json)?; },
+            Err(_) => return Err(MoroccoError::new(
+                ErrorKind::Config,
+                format!("Could not open secrets file {}. Have you run setup?", self.path.display())))
+        }
+        let file: SecretsFile = serde_json::from_str(&json)?;
+        if file.format != FILE_FORMAT {
+            return Err(MoroccoError::new(ErrorKind::Config, format!("Unsupported secrets file format: {}", file.format)));
+        }
+        let salt = decode(&file.salt)?;
+        let nonce = decode(&file.nonce)?;
+        let ciphertext = decode(&file.ciphertext)?;
+
+        let key = self.derive_key(&salt, file.kdf);
+        match decrypt(Cipher::AesGcm, &ciphertext, key.as_bytes(), &nonce) {
+            Ok(plaintext) => Ok((serde_json::from_slice(plaintext.as_bytes())?, salt, file.kdf)),
+            Err(_) => Err(MoroccoError::new(ErrorKind::AccessDenied, "Failed to decrypt secrets file. Either the passphrase is wrong or the file is corrupt.".to_string()))
+        }
+    }
+
+    fn save(&self, secrets: &Secrets, salt: &[u8], kdf_params: KdfParams) -> Result<(), MoroccoError> {
+        let plaintext = Secret::new(serde_json::to_vec(secrets)?);
+        let mut nonce = vec![0; Cipher::AesGcm.iv_length()];
+        OsRng::new()?.fill_bytes(&mut nonce);
+
+        let key = self.derive_key(salt, kdf_params);
+        let ciphertext = match encrypt(Cipher::AesGcm, plaintext.as_bytes(), key.as_bytes(), &nonce) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err(MoroccoError::new(ErrorKind::Backend, "Failed to encrypt secrets file.".to_string()))
+        };
+        let file = SecretsFile {
+            format: FILE_FORMAT,
+            kdf: kdf_params,
+            salt: encode(salt),
+            nonce: encode(&nonce),
+            ciphertext: encode(&ciphertext)
+        };
+
+        // Write to a temporary file and rename it into place, so a failed write can't corrupt the store
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        {
+            let mut tmp_file = create_private_file(&PathBuf::from(&tmp_path))?;
+            tmp_file.write_all(serde_json::to_string_pretty(&file)?.as_bytes())?;
+            tmp_file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    // Deriving a key is deliberately slow, so remember the last one
+    fn derive_key(&self, salt: &[u8], kdf_params: KdfParams) -> Secret {
+        if let Some((ref cached_salt, ref key)) = *self.derived_key.borrow() {
+            if cached_salt.as_slice() == salt && kdf_params == self.kdf_params {
+                return key.clone();
+            }
+        }
+        let mut key = Secret::new(vec![0; 32]);
+        let params = ScryptParams::new(kdf_params.log_n, kdf_params.r, kdf_params.p);
+        scrypt(self.passphrase.as_bytes(), salt, &params, key.as_mut_bytes());
+        if kdf_params == self.kdf_params {
+            *self.derived_key.borrow_mut() = Some((salt.to_vec(), key.clone()));
+        }
+        key
+    }
+
+}
+
+// There is no identity to speak of, so record the local user
+fn author() -> Option<String> {
+    env::var("USER").or(env::var("USERNAME")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_store(name: &str, passphrase: &str) -> Local {
+        let path = env::temp_dir().join(format!("morocco-test-{}-{}", name, OsRng::new().unwrap().next_u64()));
+        let mut local = Local::new(path, passphrase.to_string());
+        // keep the tests fast
+        local.kdf_params = KdfParams { log_n: 4, r: 8, p: 1 };
+        local
+    }
+
+    #[test]
+    fn put_get_list_delete() {
+        let local = local_store("crud", "passphrase");
+        local.setup().ok().unwrap();
+
+        assert_eq!(PutResult::Stored, local.put("db.password", b"secret", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(PutResult::DidNotOverwrite, local.put("db.password", b"other", false, &MetadataUpdate::default()).ok().unwrap());
+        assert_eq!(b"secret", local.get("db.password").ok().unwrap().as_bytes());
+        assert_eq!(vec!["db.password"], local.list().ok().unwrap());
+
+        assert_eq!(DeletionResult::Deleted, local.delete("db.password").ok().unwrap());
+        assert_eq!(DeletionResult::NotFound, local.delete("db.password").ok().unwrap());
+        assert_eq!(ErrorKind::NotFound, local.get("db.password").err().unwrap().kind);
+
+        fs::remove_file(&local.path).unwrap();
+    }
+
+    #[test]
+    fn versions_and_rollback() {
+        let local = local_store("versions", "passphrase");
+        local.setup().ok().unwrap();
+
+        local.put("api.key", b"one", false, &MetadataUpdate::default()).ok().unwrap();
+        local.put("api.key", b"two", true, &MetadataUpdate::default()).ok().unwrap();
+        assert_eq!(b"two", local.get("api.key").ok().unwrap().as_bytes());
+        assert_eq!(b"one", local.get_version("api.key", 1).ok().unwrap().as_bytes());
+
+        assert_eq!(RollbackResult::RolledBack(3), local.rollback("api.key", 1).ok().unwrap());
+        assert_eq!(b"one", local.get("api.key").ok().unwrap().as_bytes());
+        let versions: Vec<u64> = local.history("api.key").ok().unwrap().iter().map(|v| v.version).collect();
+        assert_eq!(vec![1, 2, 3], versions);
+
+        fs::remove_file(&local.path).unwrap();
+    }
+
+    #[test]
+    fn metadata() {
+        let local = local_store("metadata", "passphrase");
+        local.setup().ok().unwrap();
+
+        let mut tags = BTreeMap::new();
+        tags.insert("owner".to_string(), "payments".to_string());
+        let expires_at = Some("2017-09-01T00:00:00+00:00".to_string());
+        let update = MetadataUpdate { description: Some("API key".to_string()), tags: Some(tags.clone()), expires_at: expires_at.clone() };
+        local.put("api.key", b"one", false, &update).ok().unwrap();
+        let update = MetadataUpdate { description: Some("Rotated API key".to_string()), tags: None, expires_at: None };
+        local.put("api.key", b"two", true, &update).ok().unwrap();
+        local.rollback("api.key", 1).ok().unwrap();
+
+        let description = local.describe("api.key").ok().unwrap();
+        assert_eq!(3, description.version);
+        assert_eq!(Some("Rotated API key".to_string()), description.metadata.description);
+        assert_eq!(tags, description.metadata.tags);
+        // but the expiry date goes with the value that was rolled back to
+        assert_eq!(expires_at, description.metadata.expires_at);
+        let (value, metadata) = local.get_with_metadata("api.key", Some(2)).ok().unwrap();
+        assert_eq!(b"two", value.as_bytes());
+        assert_eq!(Some("Rotated API key".to_string()), metadata.description);
+
+        fs::remove_file(&local.path).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let local = local_store("passphrase", "right");
+        local.setup().ok().unwrap();
+
+        let mut wrong = Local::new(local.path.clone(), "wrong".to_string());
+        wrong.kdf_params = local.kdf_params;
+        assert_eq!(ErrorKind::AccessDenied, wrong.list().err().unwrap().kind);
+
+        fs::remove_file(&local.path).unwrap();
+    }
+}
@@ -1,99 +1,319 @@
+extern crate morocco;
 extern crate clap;
+extern crate chrono;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
-use clap::{Arg, App, SubCommand, ArgMatches};
+use clap::ArgMatches;
+use chrono::Utc;
 
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
 use std::io::stderr;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
 
-mod morocco;
-mod aws;
-mod encryption;
+mod cli;
+mod input;
+mod output;
+mod formats;
+mod exec;
+mod template;
+mod config;
+mod expiry;
 
 use morocco::*;
-use aws::AWS;
+use formats::Format;
+use exec::EnvNaming;
+use config::Config;
 
 // Examples of valid commands:
 // morocco aws setup
 // morocco aws list
+// morocco aws list --stream --limit 100
+// morocco aws list --long --tag owner=payments
 // morocco aws get my.secret
+// morocco aws get --version 2 my.secret
+// morocco aws get --output ./keystore.jks my.keystore
+// morocco aws get --base64 my.keystore
+// morocco aws getall --prefix app.prod. --format dotenv
+// morocco aws exec --prefix app.prod. -- ./server --port 8080
+// morocco aws render --output ./config.yml ./config.yml.tmpl
 // morocco aws put my.secret "oh my god"
 // morocco aws put --overwrite my.secret "oh my god"
+// echo -n "oh my god" | morocco aws put my.secret -
+// morocco aws put --file ./keystore.jks my.keystore
+// morocco aws put --prompt my.secret
+// morocco aws put --description "Payments DB password" --tag owner=payments my.secret "oh my god"
+// morocco aws put --context env=prod my.secret "oh my god"
+// morocco aws get --context env=prod my.secret
+// morocco aws putall --prefix app.prod. ./app.env
+// morocco aws put --overwrite --expires-in 90d my.secret "oh my god"
+// morocco aws get --strict my.secret
+// morocco aws report --within 14 --format json
+// morocco aws --key-alias new-key reencrypt --prefix app.prod. --dry-run
+// morocco aws describe my.secret
+// morocco aws history my.secret
+// morocco aws rollback my.secret 2
 // morocco aws delete my.secret
 // morocco aws --profile foo --region eu-west-1 --table my-custom-table list
-// TODO similar commands for GCP
+// morocco --env prod aws list
+// morocco aws --role-arn arn:aws:iam::123456789012:role/secrets --mfa-serial arn:aws:iam::123456789012:mfa/me list
+// morocco aws --dynamodb-endpoint http://localhost:8000 --kms-endpoint http://localhost:8080 list
+// morocco aws --region eu-west-1 --replicate-to us-east-1,ap-southeast-2 put my.secret "oh my god"
+// morocco aws --region eu-west-1 --replicate-to us-east-1,ap-southeast-2 sync-regions --dry-run
+// morocco aws --table morocco-v2 migrate --from morocco
+// morocco aws --cache-ttl 300 exec --prefix app.prod. -- ./server
+// morocco aws --cache-ttl 300 --no-cache get my.secret
+// morocco cache clear
+// morocco local setup
+// morocco local --file ./secrets put my.secret "oh my god"
+// morocco gcp --project my-project setup
+// morocco gcp --project my-project --collection my-secrets get my.secret
 
 
 fn main() {
-    let matches = App::new("morocco")
-        .version("0.1.0")
-        .about("Secure secret management in the cloud")
-        .subcommand(SubCommand::with_name("aws")
-                    .about("Use Amazon's Key Management Service for encryption and DynamoDB for storage")
-                    .arg(Arg::with_name("profile")
-                         .long("profile")
-                         .short("p")
-                         .takes_value(true)
-                         .help("use custom IAM profile"))
-                    .arg(Arg::with_name("region")
-                         .long("region")
-                         .short("r")
-                         .default_value("eu-west-1")
-                         .help("use custom AWS region"))
-                    .arg(Arg::with_name("table")
-                         .long("table")
-                         .short("t")
-                         .default_value("morocco")
-                         .help("use custom DynamoDB table"))
-                    .arg(Arg::with_name("key-alias")
-                         .long("key-alias")
-                         .short("k")
-                         .default_value("morocco")
-                         .help("use custom KMS customer master key"))
-                    .subcommand(SubCommand::with_name("setup"))
-                    .subcommand(SubCommand::with_name("list"))
-                    .subcommand(SubCommand::with_name("get")
-                                .arg(Arg::with_name("ID")
-                                     .required(true)
-                                     .index(1)))
-                    .subcommand(SubCommand::with_name("put")
-                                .arg(Arg::with_name("overwrite")
-                                     .long("overwrite")
-                                     .short("o")
-                                     .takes_value(false)
-                                     .help("overwrite the record if it already exists"))
-                                .arg(Arg::with_name("ID")
-                                     .required(true)
-                                     .index(1))
-                                .arg(Arg::with_name("VALUE")
-                                     .required(true)
-                                     .index(2)))
-                    .subcommand(SubCommand::with_name("delete")
-                                .arg(Arg::with_name("ID")
-                                     .required(true)
-                                     .index(1)))
-         )
-        .get_matches();
+    let matches = match cli::build_cli().get_matches_safe() {
+        Ok(matches) => matches,
+        // clap prints the help or version and exits with 0
+        Err(ref err) if err.kind == clap::ErrorKind::HelpDisplayed || err.kind == clap::ErrorKind::VersionDisplayed => err.exit(),
+        // but it would exit with 1 for usage errors, which scripts would mistake for a backend error
+        Err(err) => bail(ErrorKind::Config, err.message),
+    };
+
+    let config = match Config::load(matches.value_of("env")) {
+        Ok(config) => config,
+        Err(err) => bail(err.kind, format!("Failed to load config. Error: {}", err.message)),
+    };
 
     if let Some(aws_matches) = matches.subcommand_matches("aws") {
-        match construct_aws(aws_matches) {
-            Ok(aws) => run_subcommand(aws, aws_matches),
+        match construct_aws(aws_matches, &config) {
+            Ok(aws) => match aws_matches.subcommand() {
+                ("sync-regions", Some(sync_matches)) => sync_regions(&aws, sync_matches),
+                ("migrate", Some(migrate_matches)) => migrate(&aws, migrate_matches),
+                _ => run_with_cache(aws, "aws", aws_matches, &config)
+            },
             Err(err) => {
-                bail(format!("Failed to initialise AWS client. Error: {}\n{}", err.message, matches.usage()));
+                bail(err.kind, format!("Failed to initialise AWS client. Error: {}\n{}", err.message, matches.usage()));
             }
         }
+    } else if let Some(gcp_matches) = matches.subcommand_matches("gcp") {
+        match construct_gcp(gcp_matches, &config) {
+            Ok(gcp) => run_with_cache(gcp, "gcp", gcp_matches, &config),
+            Err(err) => {
+                bail(err.kind, format!("Failed to initialise Google Cloud client. Error: {}\n{}", err.message, matches.usage()));
+            }
+        }
+    } else if let Some(local_matches) = matches.subcommand_matches("local") {
+        match construct_local(local_matches, &config) {
+            Ok(local) => run_subcommand(local, local_matches),
+            Err(err) => {
+                bail(err.kind, format!("Failed to open local secrets file. Error: {}\n{}", err.message, matches.usage()));
+            }
+        }
+    } else if let Some(cache_matches) = matches.subcommand_matches("cache") {
+        match cache_matches.subcommand_name() {
+            Some("clear") => clear_cache(),
+            _ => bail(ErrorKind::Config, cache_matches.usage().to_string())
+        }
     } else {
-        bail(matches.usage().to_string());
+        bail(ErrorKind::Config, matches.usage().to_string());
     }
     
 }
 
-fn construct_aws(matches: &ArgMatches) -> Result<AWS, MoroccoError> {
-    let profile = matches.value_of("profile").map(|s| s.to_string());
-    let region = matches.value_of("region").unwrap().to_string();
-    let table = matches.value_of("table").unwrap().to_string();
-    let key_alias = matches.value_of("key-alias").unwrap().to_string();
-    aws::AWS::new(profile, region, table, key_alias)
+fn construct_aws(matches: &ArgMatches, config: &Config) -> Result<AWS, MoroccoError> {
+    let setting = |name| config.value_of("aws", name, matches.value_of(name));
+    let context = match matches.subcommand() {
+        (_, Some(sub_matches)) => parse_context(sub_matches)?,
+        _ => HashMap::new()
+    };
+    let mut builder = AWS::builder()
+        .encryption_context(context)
+        .mfa_code_callback(input::prompt_for_mfa_code)
+        .warning_callback(warn);
+    let options: [(&str, fn(AWSBuilder, &str) -> AWSBuilder); 9] = [
+        ("profile", AWSBuilder::profile),
+        ("region", AWSBuilder::region),
+        ("table", AWSBuilder::table),
+        ("key-alias", AWSBuilder::key_alias),
+        ("dynamodb-endpoint", AWSBuilder::dynamodb_endpoint),
+        ("kms-endpoint", AWSBuilder::kms_endpoint),
+        ("role-arn", AWSBuilder::role_arn),
+        ("external-id", AWSBuilder::external_id),
+        ("mfa-serial", AWSBuilder::mfa_serial)
+    ];
+    for &(name, set) in options.iter() {
+        if let Some(value) = setting(name) {
+            builder = set(builder, &value);
+        }
+    }
+    if let Some(regions) = setting("replicate-to") {
+        for region in regions.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            builder = builder.replicate_to(region);
+        }
+    }
+    builder.build()
+}
+
+fn construct_gcp(matches: &ArgMatches, config: &Config) -> Result<GCP, MoroccoError> {
+    let setting = |name| config.value_of("gcp", name, matches.value_of(name));
+    let project = match setting("project").or(env::var("GOOGLE_CLOUD_PROJECT").ok()) {
+        Some(project) => project,
+        None => return Err(MoroccoError::new(ErrorKind::Config, "Please specify a project using --project, a config file or the GOOGLE_CLOUD_PROJECT environment variable.".to_string()))
+    };
+    let location = setting("location").unwrap_or("global".to_string());
+    let key_ring = setting("key-ring").unwrap_or("morocco".to_string());
+    let key = setting("key").unwrap_or("morocco".to_string());
+    let collection = setting("collection").unwrap_or("morocco".to_string());
+    let kms_endpoint = setting("kms-endpoint");
+    let datastore_endpoint = setting("datastore-endpoint");
+    GCP::new(project, location, key_ring, key, collection, kms_endpoint, datastore_endpoint)
+}
+
+fn construct_local(matches: &ArgMatches, config: &Config) -> Result<Local, MoroccoError> {
+    let setting = |name| config.value_of("local", name, matches.value_of(name));
+    let path = match setting("file") {
+        Some(file) => PathBuf::from(file),
+        None => match env::home_dir() {
+            Some(home) => home.join(".morocco").join("secrets"),
+            None => return Err(MoroccoError::new(ErrorKind::Config, "Could not find your home directory. Please use the --file option.".to_string()))
+        }
+    };
+    let passphrase_env = setting("passphrase-env").unwrap_or("MOROCCO_PASSPHRASE".to_string());
+    match env::var(&passphrase_env) {
+        Ok(ref passphrase) if !passphrase.is_empty() => Ok(Local::new(path, passphrase.clone())),
+        _ => Err(MoroccoError::new(ErrorKind::Config, format!("Please set the {} environment variable to the passphrase for the secrets file.", passphrase_env)))
+    }
+}
+
+fn parse_context(matches: &ArgMatches) -> Result<HashMap<String, String>, MoroccoError> {
+    let mut context = HashMap::new();
+    if let Some(pairs) = matches.values_of("context") {
+        for pair in pairs {
+            match pair.find('=') {
+                Some(i) if i > 0 => {
+                    let (key, value) = (&pair[..i], &pair[i + 1..]);
+                    if key == "id" || key == "table" {
+                        return Err(MoroccoError::new(ErrorKind::Config, format!("The encryption context key '{}' is reserved.", key)));
+                    }
+                    context.insert(key.to_string(), value.to_string());
+                },
+                _ => return Err(MoroccoError::new(ErrorKind::Config, format!("Invalid encryption context '{}'. Expected KEY=VALUE.", pair)))
+            }
+        }
+    }
+    Ok(context)
+}
+
+// The description, tags and expiry date given to put
+fn parse_metadata(matches: &ArgMatches) -> Result<MetadataUpdate, MoroccoError> {
+    let description = match matches.value_of("description") {
+        // DynamoDB rejects empty strings
+        Some("") => return Err(MoroccoError::new(ErrorKind::Config, "The description cannot be empty.".to_string())),
+        description => description.map(|d| d.to_string())
+    };
+    Ok(MetadataUpdate {
+        description: description,
+        tags: parse_tags(matches)?,
+        expires_at: expiry::expiry_date(matches.value_of("expires-in"), matches.value_of("expires-at"), Utc::now())?
+    })
+}
+
+// Parse the --tag KEY=VALUE options, if any were given
+fn parse_tags(matches: &ArgMatches) -> Result<Option<BTreeMap<String, String>>, MoroccoError> {
+    match matches.values_of("tag") {
+        Some(pairs) => {
+            let mut tags = BTreeMap::new();
+            for pair in pairs {
+                match pair.find('=') {
+                    Some(i) if i > 0 && i + 1 < pair.len() => { tags.insert(pair[..i].to_string(), pair[i + 1..].to_string()); },
+                    _ => return Err(MoroccoError::new(ErrorKind::Config, format!("Invalid tag '{}'. Expected KEY=VALUE with a non-empty value.", pair)))
+                }
+            }
+            Ok(Some(tags))
+        },
+        None => Ok(None)
+    }
+}
+
+fn format_tags(tags: &BTreeMap<String, String>) -> String {
+    if tags.is_empty() {
+        "-".to_string()
+    } else {
+        tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>().join(",")
+    }
+}
+
+// Put the cache in front of the backend if a TTL is set and --no-cache was not given
+fn run_with_cache<M: Morocco>(morocco: M, backend: &str, matches: &ArgMatches, config: &Config) {
+    let ttl = match config.value_of(backend, "cache-ttl", matches.value_of("cache-ttl")) {
+        Some(ref ttl) if !matches.is_present("no-cache") => parse_ttl(ttl),
+        _ => return run_subcommand(morocco, matches)
+    };
+    let (dir, key_path) = match (cache_dir(), cache_key_path()) {
+        (Some(dir), Some(key_path)) => (dir, key_path),
+        _ => return run_subcommand(morocco, matches)
+    };
+    // Somebody else sharing the home directory mustn't be given secrets that they can't read themselves
+    let identity = match morocco.caller_identity() {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn(&format!("Not using the cache, as who you are could not be checked: {}", e));
+            return run_subcommand(morocco, matches);
+        }
+    };
+    let namespace = cache_namespace(backend, identity, matches, config);
+    run_subcommand(CachedMorocco::on_disk(morocco, ttl, dir, key_path, &namespace).warning_callback(warn), matches)
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".morocco").join("cache").join("secrets"))
+}
+
+// Kept outside the cache directory, so that copying or backing up the cache doesn't give the secrets away
+fn cache_key_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".morocco").join("cache-key"))
+}
+
+// Identifies where secrets are read from and who by, so that a cached value is never returned for
+// a different table, key, project, region, encryption context or caller than it was read with
+fn cache_namespace(backend: &str, identity: Option<String>, matches: &ArgMatches, config: &Config) -> String {
+    let mut parts = vec![backend.to_string()];
+    if let Some(identity) = identity {
+        parts.push(format!("identity={}", identity));
+    }
+    for name in config::settings(backend).iter().filter(|name| **name != "cache-ttl") {
+        if let Some(value) = config.value_of(backend, name, matches.value_of(name)) {
+            parts.push(format!("{}={}", name, value));
+        }
+    }
+    // used when the region, profile or project isn't given as an option
+    for name in &["AWS_REGION", "AWS_DEFAULT_REGION", "AWS_PROFILE", "AWS_DEFAULT_PROFILE", "GOOGLE_CLOUD_PROJECT"] {
+        if let Ok(value) = env::var(name) {
+            parts.push(format!("{}={}", name, value));
+        }
+    }
+    if let (_, Some(sub_matches)) = matches.subcommand() {
+        let context = parse_context(sub_matches).unwrap_or(HashMap::new()).into_iter().collect::<BTreeMap<String, String>>();
+        for (key, value) in context {
+            parts.push(format!("context {}={}", key, value));
+        }
+    }
+    parts.join("\n")
+}
+
+fn clear_cache() {
+    match cache_dir() {
+        Some(ref dir) if dir.exists() => match fs::remove_dir_all(dir) {
+            Ok(_) => println!("Cleared the cache."),
+            Err(e) => fail("Failed to clear the cache!", MoroccoError::from(e))
+        },
+        _ => println!("The cache was already empty.")
+    }
 }
 
 fn run_subcommand<M: Morocco>(morocco: M, matches: &ArgMatches) {
@@ -101,55 +321,492 @@ fn run_subcommand<M: Morocco>(morocco: M, matches: &ArgMatches) {
         ("setup", _) => {
             match morocco.setup() {
                 Ok(result) => println!("Set up complete. {}", result),
-                Err(e) => bail(format!("Failed to set up KMS customer master key and/or Dynamo table! {}", e.message))
+                Err(e) => fail("Failed to set up secret storage!", e)
+            }
+        },
+
+        ("list", Some(list_matches)) => {
+            let limit = list_matches.value_of("limit").map(|l| parse_limit(l));
+            if list_matches.is_present("long") || list_matches.is_present("tag") {
+                let tags = match parse_tags(list_matches) {
+                    Ok(tags) => tags.unwrap_or(BTreeMap::new()),
+                    Err(e) => fail("Failed to list secrets!", e),
+                };
+                match morocco.describe_all() {
+                    Ok(descriptions) => {
+                        let matching = descriptions.into_iter()
+                            .filter(|d| tags.iter().all(|(key, value)| d.metadata.tags.get(key) == Some(value)))
+                            .take(limit.unwrap_or(usize::max_value()));
+                        for d in matching {
+                            if list_matches.is_present("long") {
+                                println!("{}\t{}\t{}\t{}\t{}\t{}",
+                                         d.id,
+                                         d.version,
+                                         d.updated_at,
+                                         d.metadata.author.as_ref().map(|a| a.as_str()).unwrap_or("-"),
+                                         format_tags(&d.metadata.tags),
+                                         d.metadata.description.as_ref().map(|a| a.as_str()).unwrap_or("-"));
+                            } else {
+                                println!("{}", d.id);
+                            }
+                        }
+                    },
+                    Err(e) => fail("Failed to list secrets!", e)
+                }
+            } else if list_matches.is_present("stream") {
+                if let Err(e) = morocco.list_streaming(limit, &mut |id| println!("{}", id)) {
+                    fail("Failed to list secrets!", e)
+                }
+            } else {
+                match morocco.list() {
+                    Ok(ids) => {
+                        for id in ids.into_iter().take(limit.unwrap_or(usize::max_value())) {
+                            println!("{}", id);
+                        }
+                    },
+                    Err(e) => fail("Failed to list secrets!", e)
+                }
             }
         },
 
-        ("list", _) => {
-            match morocco.list() {
-                Ok(ids) => {
-                    for id in ids {
-                        println!("{}", id);
+        ("get", Some(get_matches)) => {
+            let id = get_matches.value_of("ID").unwrap();
+            let version = get_matches.value_of("version").map(|v| parse_version(v));
+            // the expiry date is read along with the value, so checking it costs nothing extra
+            let result = morocco.get_with_metadata(id, version).and_then(|(value, metadata)| {
+                expiry::check(id, &metadata, get_matches.is_present("strict"), Utc::now()).map(|_| value)
+            });
+            match result {
+                Ok(value) => {
+                    if let Err(e) = output::write_value(get_matches, &value) {
+                        fail("Failed to write secret!", e)
                     }
                 },
-                Err(e) => bail(format!("Failed to list secrets! {}", e.message))
+                Err(e) => fail("Failed to retrieve secret!", e)
             }
         },
 
-        ("get", Some(get_matches)) => {
-            let id = get_matches.value_of("ID").unwrap().to_string();
-            match morocco.get(id) {
-                Ok(value) => println!("{}", String::from_utf8(value).unwrap()),
-                Err(e) => bail(format!("Failed to retrieve secret! {}", e.message))
+        ("getall", Some(getall_matches)) => {
+            let prefix = getall_matches.value_of("prefix");
+            let format = Format::from_name(getall_matches.value_of("format").unwrap()).unwrap();
+            let result = morocco.get_all(prefix)
+                .and_then(|secrets| formats::render(format, &secrets, prefix))
+                .and_then(|text| output::write_output(getall_matches, text.as_bytes()));
+            if let Err(e) = result {
+                fail("Failed to retrieve secrets!", e)
+            }
+        },
+
+        ("exec", Some(exec_matches)) => {
+            let prefix = exec_matches.value_of("prefix");
+            let naming = EnvNaming {
+                strip_prefix: if exec_matches.is_present("keep-prefix") { None } else { prefix.map(|p| p.to_string()) },
+                name_prefix: exec_matches.value_of("env-prefix").unwrap_or("").to_string(),
+                overrides: parse_env_mappings(exec_matches)
+            };
+            let command: Vec<&str> = exec_matches.values_of("COMMAND").unwrap().collect();
+            match morocco.get_all(prefix) {
+                Ok(secrets) => {
+                    let err = match exec::env_vars(secrets, &naming) {
+                        Ok(vars) => exec::exec(&command, vars),
+                        Err(e) => e
+                    };
+                    fail("Failed to run command!", err)
+                },
+                Err(e) => fail("Failed to retrieve secrets!", e)
+            }
+        },
+
+        ("render", Some(render_matches)) => {
+            let path = render_matches.value_of("TEMPLATE").unwrap();
+            let result = input::read_file(path)
+                .and_then(|contents| contents.as_str().map(|text| text.to_string())
+                          .map_err(|_| MoroccoError::new(ErrorKind::Config, format!("{} is not a text file.", path))))
+                .and_then(|text| template::render(&text, &morocco))
+                .and_then(|rendered| output::write_output(render_matches, &rendered));
+            if let Err(e) = result {
+                fail("Failed to render template!", e)
             }
         },
 
         ("put", Some(put_matches)) => {
-            let id = put_matches.value_of("ID").unwrap().to_string();
-            let value = put_matches.value_of("VALUE").unwrap().as_bytes().to_vec();
+            let id = put_matches.value_of("ID").unwrap();
+            let value = match input::read_value(put_matches) {
+                Ok(value) => value,
+                Err(e) => fail("Failed to read secret value!", e),
+            };
             let overwrite = put_matches.is_present("overwrite");
-            match morocco.put(id, value, overwrite) {
+            let metadata = match parse_metadata(put_matches) {
+                Ok(metadata) => metadata,
+                Err(e) => fail("Failed to store secret!", e),
+            };
+            match morocco.put(id, value.as_bytes(), overwrite, &metadata) {
                 Ok(PutResult::Stored) => println!("Stored secret."),
-                Ok(PutResult::DidNotOverwrite) => bail(format!("Failed to store secret! It was already present. If you want to overwrite the existing value, please use the --overwrite option.")),
-                Err(e) => bail(format!("Failed to store secret! {}", e.message))
+                Ok(PutResult::DidNotOverwrite) => bail(ErrorKind::AlreadyExists, format!("Failed to store secret! It was already present. If you want to overwrite the existing value, please use the --overwrite option.")),
+                Err(e) => fail("Failed to store secret!", e)
+            }
+        },
+
+        ("putall", Some(putall_matches)) => {
+            let path = putall_matches.value_of("FILE").unwrap();
+            let secrets = match read_secrets_file(path, putall_matches.value_of("format")) {
+                Ok(secrets) => secrets,
+                Err(e) => fail("Failed to read secrets file!", e),
+            };
+            let prefix = putall_matches.value_of("prefix").unwrap_or("");
+            let overwrite = putall_matches.is_present("overwrite");
+            let (mut stored, mut skipped) = (0, 0);
+            let mut first_failure = None;
+            for (id, value) in secrets {
+                let id = format!("{}{}", prefix, id);
+                match morocco.put(&id, &value, overwrite, &MetadataUpdate::default()) {
+                    Ok(PutResult::Stored) => {
+                        stored += 1;
+                        println!("{}\tstored", id);
+                    },
+                    Ok(PutResult::DidNotOverwrite) => {
+                        skipped += 1;
+                        println!("{}\tskipped, already exists", id);
+                    },
+                    Err(e) => {
+                        println!("{}\tfailed: {}", id, e.message);
+                        first_failure = first_failure.or(Some(e));
+                    }
+                }
+            }
+            if let Some(e) = first_failure {
+                fail(&format!("Stored {} secrets and skipped {}, but some secrets could not be stored! First error:", stored, skipped), e);
+            } else {
+                println!("Stored {} secrets and skipped {}.", stored, skipped);
+            }
+        },
+
+        ("describe", Some(describe_matches)) => {
+            let id = describe_matches.value_of("ID").unwrap();
+            match morocco.describe(id) {
+                Ok(d) => {
+                    println!("id:          {}", d.id);
+                    println!("version:     {}", d.version);
+                    println!("created:     {}", d.created_at);
+                    println!("updated:     {}", d.updated_at);
+                    println!("author:      {}", d.metadata.author.unwrap_or("unknown".to_string()));
+                    println!("expires:     {}", d.metadata.expires_at.unwrap_or("never".to_string()));
+                    println!("tags:        {}", format_tags(&d.metadata.tags));
+                    println!("description: {}", d.metadata.description.unwrap_or("-".to_string()));
+                },
+                Err(e) => fail("Failed to describe secret!", e)
+            }
+        },
+
+        ("reencrypt", Some(reencrypt_matches)) => {
+            let prefix = reencrypt_matches.value_of("prefix").unwrap_or("");
+            let force = reencrypt_matches.is_present("force");
+            let dry_run = reencrypt_matches.is_present("dry-run");
+            let ids = match morocco.list() {
+                Ok(ids) => ids.into_iter().filter(|id| id.starts_with(prefix)).collect::<Vec<String>>(),
+                Err(e) => fail("Failed to list secrets!", e),
+            };
+            let (mut changed, mut current) = (0, 0);
+            let mut first_failure = None;
+            for id in ids {
+                let versions = match morocco.history(&id) {
+                    Ok(versions) => versions,
+                    Err(e) => {
+                        println!("{}\tfailed: {}", id, e.message);
+                        first_failure = first_failure.or(Some(e));
+                        continue;
+                    }
+                };
+                for v in versions {
+                    match morocco.reencrypt(&id, v.version, force, dry_run) {
+                        Ok(ReencryptResult::Reencrypted) => {
+                            changed += 1;
+                            println!("{}\t{}\tre-encrypted", id, v.version);
+                        },
+                        Ok(ReencryptResult::WouldReencrypt) => {
+                            changed += 1;
+                            println!("{}\t{}\twould re-encrypt", id, v.version);
+                        },
+                        Ok(ReencryptResult::AlreadyCurrent) => {
+                            current += 1;
+                            println!("{}\t{}\tskipped, already under the current master key", id, v.version);
+                        },
+                        // e.g. an unsupported backend or a missing key, which would affect every version
+                        Err(ref e) if e.kind == ErrorKind::Config => bail(ErrorKind::Config, format!("Failed to re-encrypt secrets! {}", e)),
+                        Err(e) => {
+                            println!("{}\t{}\tfailed: {}", id, v.version, e.message);
+                            first_failure = first_failure.or(Some(e));
+                        }
+                    }
+                }
+            }
+            let summary = if dry_run {
+                format!("Would re-encrypt {} versions, {} are already under the current master key.", changed, current)
+            } else {
+                format!("Re-encrypted {} versions and skipped {} already under the current master key.", changed, current)
+            };
+            if let Some(e) = first_failure {
+                fail(&format!("{} Some versions could not be re-encrypted! Run the command again to retry them. First error:", summary), e);
+            } else {
+                println!("{}", summary);
+            }
+        },
+
+        ("report", Some(report_matches)) => {
+            let within = report_matches.value_of("within").map(|w| parse_days(w)).unwrap_or(30);
+            let now = Utc::now();
+            let result = morocco.describe_all()
+                .map(|descriptions| expiry::report(descriptions, within, now))
+                .and_then(|entries| match report_matches.value_of("format") {
+                    Some("json") => expiry::render_json(&entries),
+                    _ => Ok(expiry::render_human(&entries))
+                });
+            match result {
+                Ok(report) => print!("{}", report),
+                Err(e) => fail("Failed to report on expiring secrets!", e)
+            }
+        },
+
+        ("history", Some(history_matches)) => {
+            let id = history_matches.value_of("ID").unwrap();
+            match morocco.history(id) {
+                Ok(ref versions) if versions.is_empty() => bail(ErrorKind::NotFound, format!("Failed to retrieve history! No secret found with that ID.")),
+                Ok(versions) => {
+                    for v in versions {
+                        println!("{}\t{}", v.version, v.created_at);
+                    }
+                },
+                Err(e) => fail("Failed to retrieve history!", e)
+            }
+        },
+
+        ("rollback", Some(rollback_matches)) => {
+            let id = rollback_matches.value_of("ID").unwrap();
+            let version = parse_version(rollback_matches.value_of("VERSION").unwrap());
+            match morocco.rollback(id, version) {
+                Ok(RollbackResult::RolledBack(new_version)) => println!("Rolled back. Version {} is now stored as version {}.", version, new_version),
+                Ok(RollbackResult::NotFound) => bail(ErrorKind::NotFound, format!("Failed to roll back secret! No secret found with that ID and version.")),
+                Err(e) => fail("Failed to roll back secret!", e)
             }
         },
 
         ("delete", Some(get_matches)) => {
-            let id = get_matches.value_of("ID").unwrap().to_string();
+            let id = get_matches.value_of("ID").unwrap();
             match morocco.delete(id) {
                 Ok(DeletionResult::Deleted) => println!("{}", "Deleted secret."),
-                Ok(DeletionResult::NotFound) => bail(format!("Failed to delete secret! No secret found with that ID.")),
-                Err(e) => bail(format!("Failed to delete secret! {}", e.message))
+                Ok(DeletionResult::NotFound) => bail(ErrorKind::NotFound, format!("Failed to delete secret! No secret found with that ID.")),
+                Err(e) => fail("Failed to delete secret!", e)
             }
         },
 
         
-        _ => bail(matches.usage().to_string())
+        _ => bail(ErrorKind::Config, matches.usage().to_string())
+    }
+}
+
+// Only the AWS backend can replicate secrets to other regions
+fn sync_regions(aws: &AWS, matches: &ArgMatches) {
+    let prefix = matches.value_of("prefix");
+    let dry_run = matches.is_present("dry-run");
+    let delete_extra = matches.is_present("delete-extra");
+    let (mut changed, mut in_sync, mut extra) = (0, 0, 0);
+    let mut first_failure = None;
+    let result = aws.sync_regions(prefix, dry_run, delete_extra, &mut |region, id, result| {
+        let action = match result {
+            Ok(SyncResult::InSync) => {
+                in_sync += 1;
+                "in sync".to_string()
+            },
+            Ok(SyncResult::Copied(versions)) => {
+                changed += 1;
+                format!("{} {} missing versions", if dry_run { "would copy" } else { "copied" }, versions)
+            },
+            Ok(SyncResult::Replaced) => {
+                changed += 1;
+                format!("{} all versions, as they differed", if dry_run { "would replace" } else { "replaced" })
+            },
+            Ok(SyncResult::Deleted) => {
+                changed += 1;
+                format!("{}, as it no longer exists in the main region", if dry_run { "would delete" } else { "deleted" })
+            },
+            Ok(SyncResult::OnlyInReplica) => {
+                extra += 1;
+                "only exists in this region".to_string()
+            },
+            Err(e) => {
+                let action = format!("failed: {}", e.message);
+                if first_failure.is_none() {
+                    first_failure = Some(e);
+                }
+                action
+            }
+        };
+        println!("{}\t{}\t{}", region, id, action);
+    });
+    if let Err(e) = result {
+        fail("Failed to sync regions!", e);
+    }
+    let mut summary = if dry_run {
+        format!("Would sync {} secrets, {} are already in sync.", changed, in_sync)
+    } else {
+        format!("Synced {} secrets and skipped {} already in sync.", changed, in_sync)
+    };
+    if extra > 0 {
+        summary.push_str(&format!(" Left {} secrets that only exist in the replica regions, use --delete-extra to delete them.", extra));
+    }
+    if let Some(e) = first_failure {
+        fail(&format!("{} Some secrets could not be synced! Run the command again to retry them. First error:", summary), e);
+    } else {
+        println!("{}", summary);
     }
 }
 
-fn bail(message: String) {
+fn migrate(aws: &AWS, matches: &ArgMatches) {
+    let from = matches.value_of("from").unwrap();
+    let (mut migrated, mut skipped) = (0, 0);
+    let result = aws.migrate_from(from, &mut |id, result| {
+        let action = match result {
+            PutResult::Stored => {
+                migrated += 1;
+                "migrated"
+            },
+            PutResult::DidNotOverwrite => {
+                skipped += 1;
+                "already migrated"
+            }
+        };
+        println!("{}\t{}", id, action);
+    });
+    match result {
+        Ok(_) => println!("Migrated {} secrets and skipped {} already migrated.", migrated, skipped),
+        Err(e) => fail(&format!("Failed to migrate the secrets after migrating {}! Run the command again to carry on. Error:", migrated), e)
+    }
+}
+
+fn parse_env_mappings(matches: &ArgMatches) -> HashMap<String, String> {
+    let mut mappings = HashMap::new();
+    if let Some(pairs) = matches.values_of("map") {
+        for pair in pairs {
+            match pair.find('=') {
+                Some(i) if i > 0 && i < pair.len() - 1 => { mappings.insert(pair[..i].to_string(), pair[i + 1..].to_string()); },
+                _ => bail(ErrorKind::Config, format!("Invalid mapping '{}'. Expected ID=NAME.", pair))
+            }
+        }
+    }
+    mappings
+}
+
+fn read_secrets_file(path: &str, format: Option<&str>) -> Result<BTreeMap<String, Vec<u8>>, MoroccoError> {
+    let format = match format.and_then(Format::from_name).or(Format::from_path(path)) {
+        Some(format) => format,
+        None => return Err(MoroccoError::new(ErrorKind::Config, format!("Could not tell the format of {}. Please use the --format option.", path)))
+    };
+    let contents = input::read_file(path)?;
+    match contents.as_str() {
+        Ok(text) => formats::parse(format, text),
+        Err(_) => Err(MoroccoError::new(ErrorKind::Config, format!("{} is not a text file.", path)))
+    }
+}
+
+fn parse_version(version: &str) -> u64 {
+    match version.parse::<u64>() {
+        Ok(v) if v > 0 => v,
+        _ => bail(ErrorKind::Config, format!("Invalid version '{}'. Versions are positive integers.", version)),
+    }
+}
+
+fn parse_days(days: &str) -> i64 {
+    match days.parse::<i64>() {
+        Ok(d @ 0...expiry::MAX_DAYS) => d,
+        _ => bail(ErrorKind::Config, format!("Invalid number of days '{}'. It must be an integer from 0 to {}.", days, expiry::MAX_DAYS)),
+    }
+}
+
+fn parse_ttl(ttl: &str) -> u64 {
+    match ttl.parse::<u64>() {
+        Ok(t) => t,
+        Err(_) => bail(ErrorKind::Config, format!("Invalid cache TTL '{}'. It must be a non-negative number of seconds.", ttl)),
+    }
+}
+
+fn parse_limit(limit: &str) -> usize {
+    match limit.parse::<usize>() {
+        Ok(l) => l,
+        Err(_) => bail(ErrorKind::Config, format!("Invalid limit '{}'. The limit must be a non-negative integer.", limit)),
+    }
+}
+
+// Report a problem that doesn't stop the command
+fn warn(message: &str) {
+    eprintln!("Warning: {}", message);
+}
+
+// Report a failed operation. Set MOROCCO_DEBUG to also see the underlying error.
+fn fail(context: &str, err: MoroccoError) -> ! {
+    let mut message = format!("{} {}", context, err.message);
+    if let (Some(source), Ok(_)) = (err.source(), env::var("MOROCCO_DEBUG")) {
+        message.push_str(&format!("\nCaused by: {:?}", source));
+    }
+    bail(err.kind, message)
+}
+
+// Exit with the code for the given kind of error. See ErrorKind::exit_code for the mapping.
+fn bail(kind: ErrorKind, message: String) -> ! {
     writeln!(stderr(), "{}", message).unwrap();
-    exit(1)
+    exit(kind.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<HashMap<String, String>, MoroccoError> {
+        let mut argv = vec!["morocco", "aws", "get", "db-password"];
+        argv.extend_from_slice(args);
+        let matches = cli::build_cli().get_matches_from(argv);
+        let get_matches = matches.subcommand_matches("aws").unwrap().subcommand_matches("get").unwrap();
+        parse_context(get_matches)
+    }
+
+    #[test]
+    fn context_pairs() {
+        let context = parse(&["-c", "env=prod", "--context", "url=https://x/?a=b"]).ok().unwrap();
+        assert_eq!(2, context.len());
+        assert_eq!("prod", context["env"]);
+        // only the first = separates the key from the value
+        assert_eq!("https://x/?a=b", context["url"]);
+        assert_eq!("", parse(&["-c", "empty="]).ok().unwrap()["empty"]);
+        assert!(parse(&[]).ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_or_reserved_context() {
+        assert_eq!(ErrorKind::Config, parse(&["-c", "env"]).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, parse(&["-c", "=prod"]).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, parse(&["-c", "id=other"]).err().unwrap().kind);
+        assert_eq!(ErrorKind::Config, parse(&["-c", "env=prod", "-c", "table=other"]).err().unwrap().kind);
+    }
+
+    #[test]
+    fn empty_description_or_tag() {
+        let put = |args: &[&str]| {
+            let mut argv = vec!["morocco", "aws", "put"];
+            argv.extend_from_slice(args);
+            argv.extend_from_slice(&["db-password", "so-very-secret"]);
+            let matches = cli::build_cli().get_matches_from(argv);
+            parse_metadata(matches.subcommand_matches("aws").unwrap().subcommand_matches("put").unwrap())
+        };
+        assert_eq!(Some("Main database".to_string()), put(&["--description", "Main database"]).ok().unwrap().description);
+        assert_eq!(ErrorKind::Config, put(&["--description", ""]).err().unwrap().kind);
+        assert_eq!("chris", put(&["--tag", "owner=chris"]).ok().unwrap().tags.unwrap()["owner"]);
+        assert_eq!(ErrorKind::Config, put(&["--tag", "owner="]).err().unwrap().kind);
+    }
+
+    #[test]
+    fn environment_and_context_together() {
+        let matches = cli::build_cli().get_matches_from(vec!["morocco", "--env", "prod", "aws", "get", "--context", "env=staging", "db-password"]);
+        assert_eq!(Some("prod"), matches.value_of("env"));
+        let get_matches = matches.subcommand_matches("aws").unwrap().subcommand_matches("get").unwrap();
+        assert_eq!("staging", parse_context(get_matches).ok().unwrap()["env"]);
+    }
 }
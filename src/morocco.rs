@@ -1,39 +1,360 @@
+extern crate base64;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::convert::From;
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+
+pub use secret::Secret;
+
+// What went wrong, broadly speaking. Each kind maps to a distinct process exit code
+// so that scripts can tell a missing secret from an unreachable backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // The secret, or the requested version of it, does not exist
+    NotFound,
+    // The secret already exists and --overwrite was not given
+    AlreadyExists,
+    // Missing or invalid credentials, or not allowed to use the key or table
+    AccessDenied,
+    // The backend rejected the request because of rate limits
+    Throttled,
+    // Decryption or an integrity check failed, e.g. because the stored data was tampered with
+    IntegrityFailure,
+    // Invalid options or configuration, or the backend has not been set up
+    Config,
+    // The backend could not be reached or reported an internal error
+    Unavailable,
+    // Another write to the same secret happened at the same time
+    Conflict,
+    // Reading or writing a local file failed
+    Io,
+    // The secret has expired and --strict was given
+    Expired,
+    // Any other error reported by the backend
+    Backend
+}
+
+impl ErrorKind {
 
+    // The exit codes are part of the CLI's interface and are documented in the README,
+    // so existing codes must never be changed.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ErrorKind::Backend => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::NotFound => 3,
+            ErrorKind::AlreadyExists => 4,
+            ErrorKind::AccessDenied => 5,
+            ErrorKind::Throttled => 6,
+            ErrorKind::IntegrityFailure => 7,
+            ErrorKind::Unavailable => 8,
+            ErrorKind::Conflict => 9,
+            ErrorKind::Io => 10,
+            ErrorKind::Expired => 11
+        }
+    }
+
+}
+
+#[derive(Debug)]
 pub struct MoroccoError {
-    pub message: String
+    pub kind: ErrorKind,
+    pub message: String,
+    source: Option<Box<Error + Send + Sync>>
 }
 
-impl <A: Error> From<A> for MoroccoError {
-    fn from(err: A) -> MoroccoError { 
-        let message = String::from(err.description());
-        MoroccoError { 
-            message
+impl MoroccoError {
+
+    pub fn new(kind: ErrorKind, message: String) -> MoroccoError {
+        MoroccoError {
+            kind,
+            message,
+            source: None
         }
     }
+
+    // Wrap an underlying error, keeping it around as the source
+    pub fn with_source<E: Error + Send + Sync + 'static>(kind: ErrorKind, err: E) -> MoroccoError {
+        MoroccoError {
+            kind,
+            message: err.to_string(),
+            source: Some(Box::new(err))
+        }
+    }
+
+    // The underlying error that caused this one, if any
+    pub fn source(&self) -> Option<&(Error + Send + Sync + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref())
+    }
+
 }
 
+impl fmt::Display for MoroccoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// I/O errors are always about local files or streams
+impl From<io::Error> for MoroccoError {
+    fn from(err: io::Error) -> MoroccoError {
+        MoroccoError::with_source(ErrorKind::Io, err)
+    }
+}
+
+// Stored data or responses that can't be parsed are backend errors
+impl From<ParseIntError> for MoroccoError {
+    fn from(err: ParseIntError) -> MoroccoError {
+        MoroccoError::with_source(ErrorKind::Backend, err)
+    }
+}
+
+impl From<base64::DecodeError> for MoroccoError {
+    fn from(err: base64::DecodeError) -> MoroccoError {
+        MoroccoError::with_source(ErrorKind::Backend, err)
+    }
+}
+
+impl From<serde_json::Error> for MoroccoError {
+    fn from(err: serde_json::Error) -> MoroccoError {
+        MoroccoError::with_source(ErrorKind::Backend, err)
+    }
+}
+
+impl Error for MoroccoError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+
+    fn source(&self) -> Option<&(Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as &(Error + 'static))
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum PutResult {
     Stored,
     DidNotOverwrite
 }
 
+#[derive(Debug, PartialEq)]
 pub enum DeletionResult {
     Deleted,
     NotFound
 }
 
+#[derive(Debug, PartialEq)]
+pub enum RollbackResult {
+    // contains the new version number under which the old value was re-stored
+    RolledBack(u64),
+    NotFound
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReencryptResult {
+    Reencrypted,
+    // the data key was already encrypted under the current master key
+    AlreadyCurrent,
+    // a dry run found that the version needs re-encrypting
+    WouldReencrypt
+}
+
+// What syncing a secret from the main region did to its copy in a replica region.
+// A dry run reports what it would have done.
+#[derive(Debug, PartialEq)]
+pub enum SyncResult {
+    // the replica already had every version
+    InSync,
+    // contains the number of versions that were missing from the replica and have been copied to it
+    Copied(usize),
+    // the replica had versions that the main region does not, so all its versions were replaced
+    Replaced,
+    // the secret no longer exists in the main region, so it was deleted from the replica
+    Deleted,
+    // the secret only exists in the replica, and was left alone as deleting extra secrets was not asked for
+    OnlyInReplica
+}
+
+pub struct VersionInfo {
+    pub version: u64,
+    pub created_at: String,
+    pub metadata: Metadata
+}
+
+// Information about a version of a secret that is stored in the clear alongside it,
+// so that it can be shown and searched without decrypting anything
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub description: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    // who stored the version, e.g. their IAM ARN. Recorded automatically where the backend can tell.
+    pub author: Option<String>,
+    // when the value should have been rotated by, as an RFC 3339 timestamp
+    pub expires_at: Option<String>
+}
+
+impl Metadata {
+
+    // The metadata for a new version of a secret: whatever the update sets,
+    // with the rest carried over from the previous version.
+    // The expiry date is not carried over, as it belongs to the value that is being replaced.
+    pub fn updated(&self, update: &MetadataUpdate, author: Option<String>) -> Metadata {
+        Metadata {
+            description: update.description.clone().or(self.description.clone()),
+            tags: update.tags.clone().unwrap_or(self.tags.clone()),
+            author: author,
+            expires_at: update.expires_at.clone()
+        }
+    }
+
+}
+
+// The metadata given when storing a secret. Anything left as None is kept from the previous version.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataUpdate {
+    pub description: Option<String>,
+    pub tags: Option<BTreeMap<String, String>>,
+    pub expires_at: Option<String>
+}
+
+// A summary of a secret, as shown by describe
+pub struct Description {
+    pub id: String,
+    // the latest version
+    pub version: u64,
+    // when the first version was stored
+    pub created_at: String,
+    // when the latest version was stored
+    pub updated_at: String,
+    // the metadata of the latest version
+    pub metadata: Metadata
+}
+
+impl Description {
+
+    // Summarise a secret from its versions, oldest first. Returns None if there are no versions.
+    pub fn from_versions(id: String, versions: Vec<VersionInfo>) -> Option<Description> {
+        let created_at = match versions.first() {
+            Some(first) => first.created_at.clone(),
+            None => return None
+        };
+        versions.into_iter().last().map(|latest| Description {
+            id: id,
+            version: latest.version,
+            created_at: created_at,
+            updated_at: latest.created_at,
+            metadata: latest.metadata
+        })
+    }
+
+}
+
 pub trait Morocco {
 
     fn setup(&self) -> Result<String, MoroccoError>;
 
     fn list(&self) -> Result<Vec<String>, MoroccoError>;
 
-    fn get(&self, id: String) -> Result<Vec<u8>, MoroccoError>;
+    // Pass each secret ID to the callback as soon as it is found, in no particular order,
+    // stopping after `limit` IDs if a limit is given.
+    // Backends that can page through their IDs should override this so that huge stores
+    // are listed progressively.
+    fn list_streaming(&self, limit: Option<usize>, callback: &mut FnMut(String)) -> Result<(), MoroccoError> {
+        let ids = self.list()?;
+        for id in ids.into_iter().take(limit.unwrap_or(usize::max_value())) {
+            callback(id);
+        }
+        Ok(())
+    }
+
+    // Get the latest version of a secret
+    fn get(&self, id: &str) -> Result<Secret, MoroccoError>;
 
-    fn put(&self, id: String, value: Vec<u8>, overwrite: bool) -> Result<PutResult, MoroccoError>;
+    fn get_version(&self, id: &str, version: u64) -> Result<Secret, MoroccoError>;
 
-    fn delete(&self, id: String) -> Result<DeletionResult, MoroccoError>;
+    // Get the given version of a secret, or the latest, along with its metadata, e.g. to check its expiry date.
+    // Backends that store the metadata with the value should override this to read both at once.
+    fn get_with_metadata(&self, id: &str, version: Option<u64>) -> Result<(Secret, Metadata), MoroccoError> {
+        let value = match version {
+            Some(version) => self.get_version(id, version)?,
+            None => self.get(id)?
+        };
+        let versions = self.history(id)?;
+        let found = match version {
+            Some(version) => versions.into_iter().find(|info| info.version == version),
+            None => versions.into_iter().last()
+        };
+        Ok((value, found.map(|info| info.metadata).unwrap_or_default()))
+    }
+
+    // Get the latest version of every secret, or of every secret whose ID starts with the prefix.
+    // Backends that can read many secrets at once should override this.
+    fn get_all(&self, prefix: Option<&str>) -> Result<BTreeMap<String, Secret>, MoroccoError> {
+        let mut secrets = BTreeMap::new();
+        for id in self.list()? {
+            if prefix.map_or(true, |p| id.starts_with(p)) {
+                match self.get(&id) {
+                    Ok(value) => { secrets.insert(id, value); },
+                    // deleted since we listed it
+                    Err(ref e) if e.kind == ErrorKind::NotFound => (),
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    // Store a secret. If the secret already exists and overwrite is true, it is stored as a new version.
+    fn put(&self, id: &str, value: &[u8], overwrite: bool, metadata: &MetadataUpdate) -> Result<PutResult, MoroccoError>;
+
+    // List the versions of a secret, oldest first
+    fn history(&self, id: &str) -> Result<Vec<VersionInfo>, MoroccoError>;
+
+    // Summarise a secret and its metadata without decrypting it
+    fn describe(&self, id: &str) -> Result<Description, MoroccoError> {
+        match Description::from_versions(id.to_string(), self.history(id)?) {
+            Some(description) => Ok(description),
+            None => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+        }
+    }
+
+    // Summarise every secret, sorted by ID.
+    // Backends that can read the metadata of many secrets at once should override this.
+    fn describe_all(&self) -> Result<Vec<Description>, MoroccoError> {
+        let mut descriptions = Vec::new();
+        for id in self.list()? {
+            match self.describe(&id) {
+                Ok(description) => descriptions.push(description),
+                // deleted since we listed it
+                Err(ref e) if e.kind == ErrorKind::NotFound => (),
+                Err(e) => return Err(e)
+            }
+        }
+        descriptions.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(descriptions)
+    }
+
+    // Store the value of an old version of a secret as its latest version
+    fn rollback(&self, id: &str, version: u64) -> Result<RollbackResult, MoroccoError>;
+
+    // Delete all versions of a secret
+    fn delete(&self, id: &str) -> Result<DeletionResult, MoroccoError>;
+
+    // Re-encrypt a version of a secret in place under a fresh data key from the current master key,
+    // e.g. after the key alias has been pointed at a new key. The metadata is left as it is.
+    // Versions that are already encrypted under the current master key are skipped unless force is true.
+    fn reencrypt(&self, _id: &str, _version: u64, _force: bool, _dry_run: bool) -> Result<ReencryptResult, MoroccoError> {
+        Err(MoroccoError::new(ErrorKind::Config, "This backend does not support re-encrypting secrets.".to_string()))
+    }
+
+    // Who the backend reads secrets as, e.g. an IAM ARN, or None if access doesn't depend on who you are.
+    // Anything kept from what one identity has read, such as a cache, mustn't be given to another.
+    fn caller_identity(&self) -> Result<Option<String>, MoroccoError> {
+        Ok(None)
+    }
 
 }
@@ -0,0 +1,56 @@
+extern crate base64;
+
+use std::io::{stdout, Write};
+use std::path::Path;
+
+use clap::ArgMatches;
+
+use morocco::{MoroccoError, Secret};
+use morocco::files::create_private_file;
+
+// Write a secret value to wherever the get command was told to put it.
+// Text is printed followed by a newline. Values that are not valid UTF-8 (or all values,
+// if --raw is given) are written to stdout as raw bytes, with nothing added.
+pub fn write_value(matches: &ArgMatches, value: &Secret) -> Result<(), MoroccoError> {
+    let encoded;
+    let (bytes, is_text) = if matches.is_present("base64") {
+        encoded = base64::encode(value.as_bytes());
+        (encoded.as_bytes(), true)
+    } else {
+        (value.as_bytes(), !matches.is_present("raw") && value.as_str().is_ok())
+    };
+
+    match matches.value_of("output") {
+        Some(path) => {
+            let mut file = create_private_file(Path::new(path))?;
+            file.write_all(bytes)?;
+        },
+        None => {
+            let out = stdout();
+            let mut out = out.lock();
+            out.write_all(bytes)?;
+            if is_text && !matches.is_present("no-newline") {
+                out.write_all(b"\n")?;
+            }
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Write output to the file given by --output, or to stdout
+pub fn write_output(matches: &ArgMatches, output: &[u8]) -> Result<(), MoroccoError> {
+    match matches.value_of("output") {
+        Some(path) => {
+            let mut file = create_private_file(Path::new(path))?;
+            file.write_all(output)?;
+        },
+        None => {
+            let out = stdout();
+            let mut out = out.lock();
+            out.write_all(output)?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
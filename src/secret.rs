@@ -0,0 +1,163 @@
+extern crate crypto;
+#[cfg(feature = "mlock")]
+extern crate libc;
+
+use std::fmt;
+use std::io::{self, Read};
+use std::str::{self, Utf8Error};
+
+use self::crypto::util::secure_memset;
+
+// A decrypted secret value or plaintext data key.
+// The memory is zeroed when it is dropped, and the Debug output is redacted,
+// so that values don't linger in memory or end up in logs by accident.
+// With the mlock feature, the memory is also locked so that it is never swapped to disk.
+#[derive(PartialEq)]
+pub struct Secret {
+    value: Vec<u8>
+}
+
+impl Secret {
+
+    pub fn new(value: Vec<u8>) -> Secret {
+        let secret = Secret { value: value };
+        secret.lock();
+        secret
+    }
+
+    // Read everything from e.g. stdin or a file, starting with room for size_hint bytes.
+    // Reading into a Vec would leave copies of the value behind in the buffers it outgrows.
+    pub fn read_from<R: Read>(reader: &mut R, size_hint: usize) -> io::Result<Secret> {
+        let mut secret = Secret::new(Vec::with_capacity(size_hint));
+        let mut chunk = Secret::new(vec![0; 4096]);
+        loop {
+            match reader.read(chunk.as_mut_bytes()) {
+                Ok(0) => return Ok(secret),
+                Ok(n) => secret.extend_from_slice(&chunk.as_bytes()[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    // The value as text, if it is valid UTF-8
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(&self.value)
+    }
+
+    // A copy of the value that will not be zeroed, e.g. to hand over to a library that needs a Vec
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    // For decrypting in place
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.value
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, bytes: &[u8]) {
+        if self.value.len() + bytes.len() > self.value.capacity() {
+            // move into a bigger buffer ourselves, so that the old one is zeroed rather than just freed
+            let mut grown = Vec::with_capacity((self.value.len() + bytes.len()) * 2);
+            grown.extend_from_slice(&self.value);
+            *self = Secret::new(grown);
+        }
+        self.value.extend_from_slice(bytes);
+    }
+
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret::new(self.value.clone())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // zero the spare capacity as well, in case it holds leftover plaintext. This never reallocates.
+        let capacity = self.value.capacity();
+        self.value.resize(capacity, 0);
+        secure_memset(&mut self.value, 0);
+        self.unlock();
+    }
+}
+
+impl From<Vec<u8>> for Secret {
+    fn from(value: Vec<u8>) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Secret {
+        Secret::new(value.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<{} bytes redacted>)", self.value.len())
+    }
+}
+
+// Locking can fail, e.g. because of RLIMIT_MEMLOCK, in which case the value is still zeroed on drop
+#[cfg(feature = "mlock")]
+impl Secret {
+
+    fn lock(&self) {
+        if self.value.capacity() > 0 {
+            unsafe { libc::mlock(self.value.as_ptr() as *const libc::c_void, self.value.capacity()); }
+        }
+    }
+
+    fn unlock(&self) {
+        if self.value.capacity() > 0 {
+            unsafe { libc::munlock(self.value.as_ptr() as *const libc::c_void, self.value.capacity()); }
+        }
+    }
+
+}
+
+#[cfg(not(feature = "mlock"))]
+impl Secret {
+    fn lock(&self) {}
+    fn unlock(&self) {}
+}
+
+// Zero a buffer that held plaintext, such as the scratch space used while decrypting
+pub fn wipe(buffer: &mut [u8]) {
+    secure_memset(buffer, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let secret = Secret::from("hunter2");
+        assert_eq!("Secret(<7 bytes redacted>)", format!("{:?}", secret));
+        assert_eq!(b"hunter2", secret.clone().as_bytes());
+    }
+
+    #[test]
+    fn read_from_a_reader() {
+        let value: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(&value[..], Secret::read_from(&mut &value[..], 0).unwrap().as_bytes());
+        assert_eq!(&value[..], Secret::read_from(&mut &value[..], value.len()).unwrap().as_bytes());
+        assert!(Secret::read_from(&mut &b""[..], 16).unwrap().is_empty());
+    }
+}
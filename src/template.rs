@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use morocco::{Morocco, MoroccoError, ErrorKind, Secret};
+
+// A piece of a parsed template
+enum Part {
+    Text(String),
+    Secret(String)
+}
+
+// Render a template, replacing every {{ secret "some.id" }} placeholder with the latest
+// value of that secret. Any other {{ ... }} is left as it is, so templates for other tools still work.
+// Fails without rendering anything if a referenced secret does not exist.
+pub fn render(template: &str, morocco: &Morocco) -> Result<Vec<u8>, MoroccoError> {
+    render_with(template, &mut |id| morocco.get(id))
+}
+
+fn render_with(template: &str, get: &mut FnMut(&str) -> Result<Secret, MoroccoError>) -> Result<Vec<u8>, MoroccoError> {
+    let parts = parse(template)?;
+
+    // look up each secret once, and report all the missing ones together
+    let mut values = BTreeMap::new();
+    let mut missing = Vec::new();
+    for part in &parts {
+        if let Part::Secret(ref id) = *part {
+            if values.contains_key(id) || missing.contains(id) {
+                continue;
+            }
+            match get(id) {
+                Ok(value) => { values.insert(id.clone(), value); },
+                Err(ref e) if e.kind == ErrorKind::NotFound => missing.push(id.clone()),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(MoroccoError::new(
+                ErrorKind::NotFound,
+                format!("The template refers to secrets that do not exist: {}", missing.join(", "))));
+    }
+
+    let mut output = Vec::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => output.extend_from_slice(text.as_bytes()),
+            Part::Secret(id) => output.extend_from_slice(values[&id].as_bytes())
+        }
+    }
+    Ok(output)
+}
+
+fn parse(template: &str) -> Result<Vec<Part>, MoroccoError> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end + 2,
+            None => break
+        };
+        let placeholder = rest[start + 2..end - 2].trim();
+        if placeholder.starts_with("secret ") || placeholder == "secret" {
+            parts.push(Part::Text(rest[..start].to_string()));
+            match parse_quoted(placeholder["secret".len()..].trim()) {
+                Some(id) => parts.push(Part::Secret(id)),
+                None => {
+                    let offset = template.len() - rest.len() + start;
+                    let line = template[..offset].matches('\n').count() + 1;
+                    return Err(MoroccoError::new(
+                            ErrorKind::Config,
+                            format!("Invalid placeholder on line {}: {{{{ {} }}}}. Expected {{{{ secret \"some.id\" }}}}.", line, placeholder)));
+                }
+            }
+        } else {
+            parts.push(Part::Text(rest[..end].to_string()));
+        }
+        rest = &rest[end..];
+    }
+    parts.push(Part::Text(rest.to_string()));
+    Ok(parts)
+}
+
+// Parse a double-quoted string, which must be the whole of the input, allowing \" and \\ escapes
+fn parse_quoted(quoted: &str) -> Option<String> {
+    if !quoted.starts_with('"') {
+        return None;
+    }
+    let mut value = String::new();
+    let mut chars = quoted[1..].chars();
+    loop {
+        match chars.next() {
+            Some('"') => return if chars.next().is_none() && !value.is_empty() { Some(value) } else { None },
+            Some('\\') => match chars.next() {
+                Some(c) => value.push(c),
+                None => return None
+            },
+            Some(c) => value.push(c),
+            None => return None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(id: &str) -> Result<Secret, MoroccoError> {
+        match id {
+            "db.password" => Ok(Secret::from("hunter2")),
+            "db.user" => Ok(Secret::from("admin")),
+            _ => Err(MoroccoError::new(ErrorKind::NotFound, "No secret found with that ID.".to_string()))
+        }
+    }
+
+    #[test]
+    fn substitutes_secrets() {
+        let template = "user: {{ secret \"db.user\" }}\npassword: {{secret \"db.password\"}}\nhost: {{ .Values.host }}\n";
+        let rendered = render_with(template, &mut get).ok().unwrap();
+        assert_eq!("user: admin\npassword: hunter2\nhost: {{ .Values.host }}\n", String::from_utf8(rendered).unwrap());
+    }
+
+    #[test]
+    fn fails_on_missing_secrets() {
+        let template = "{{ secret \"db.password\" }} {{ secret \"api.key\" }} {{ secret \"other\" }}";
+        let err = render_with(template, &mut get).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind);
+        assert!(err.message.ends_with("api.key, other"));
+    }
+
+    #[test]
+    fn fails_on_invalid_placeholders() {
+        let err = render_with("ok\n{{ secret db.password }}", &mut get).err().unwrap();
+        assert_eq!(ErrorKind::Config, err.kind);
+        assert!(err.message.contains("line 2"));
+    }
+}
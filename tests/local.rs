@@ -0,0 +1,99 @@
+// Runs the morocco binary against the local backend, so that the whole command line is exercised
+// without any cloud credentials.
+
+extern crate rand;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use rand::{Rng, OsRng};
+
+// A home directory of its own, so that no config files or secrets from the real one are used
+struct Sandbox {
+    home: PathBuf
+}
+
+impl Sandbox {
+
+    fn new() -> Sandbox {
+        let home = env::temp_dir().join(format!("morocco-cli-test-{}", OsRng::new().unwrap().next_u64()));
+        fs::create_dir_all(&home).unwrap();
+        Sandbox { home: home }
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_with_passphrase("correct-horse-battery-staple", args)
+    }
+
+    fn run_with_passphrase(&self, passphrase: &str, args: &[&str]) -> Output {
+        Command::new(morocco_binary())
+            .arg("local")
+            .args(args)
+            .env_clear()
+            .env("HOME", &self.home)
+            .env("MOROCCO_PASSPHRASE", passphrase)
+            .current_dir(&self.home)
+            .output()
+            .unwrap()
+    }
+
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+// Integration tests are built into target/<profile>/deps, next to the binary's directory
+fn morocco_binary() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("morocco{}", env::consts::EXE_SUFFIX))
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn setup_put_get_list_delete() {
+    let sandbox = Sandbox::new();
+    let setup = sandbox.run(&["setup"]);
+    assert!(setup.status.success());
+    assert!(sandbox.home.join(".morocco").join("secrets").is_file());
+
+    assert_eq!("Stored secret.\n", stdout(&sandbox.run(&["put", "db.password", "so-very-secret"])));
+    assert_eq!("Stored secret.\n", stdout(&sandbox.run(&["put", "api.key", "abc123"])));
+    assert_eq!("so-very-secret\n", stdout(&sandbox.run(&["get", "db.password"])));
+    assert_eq!("so-very-secret", stdout(&sandbox.run(&["get", "--no-newline", "db.password"])));
+    assert_eq!("api.key\ndb.password\n", stdout(&sandbox.run(&["list"])));
+
+    assert_eq!("Stored secret.\n", stdout(&sandbox.run(&["put", "--overwrite", "db.password", "rotated"])));
+    assert_eq!("rotated\n", stdout(&sandbox.run(&["get", "db.password"])));
+    assert_eq!("so-very-secret\n", stdout(&sandbox.run(&["get", "--version", "1", "db.password"])));
+
+    assert_eq!("Deleted secret.\n", stdout(&sandbox.run(&["delete", "db.password"])));
+    assert_eq!("api.key\n", stdout(&sandbox.run(&["list"])));
+}
+
+#[test]
+fn failures_exit_with_the_code_for_their_kind() {
+    let sandbox = Sandbox::new();
+    assert!(sandbox.run(&["setup"]).status.success());
+    sandbox.run(&["put", "db.password", "so-very-secret"]);
+
+    let not_found = sandbox.run(&["get", "other"]);
+    assert_eq!(Some(3), not_found.status.code());
+    assert!(not_found.stdout.is_empty());
+    assert_eq!(Some(3), sandbox.run(&["delete", "other"]).status.code());
+    assert_eq!(Some(4), sandbox.run(&["put", "db.password", "other"]).status.code());
+    assert_eq!(Some(5), sandbox.run_with_passphrase("wrong", &["get", "db.password"]).status.code());
+    assert_eq!(Some(2), sandbox.run(&["get"]).status.code());
+    assert_eq!(Some(2), sandbox.run(&["get", "--version", "0", "db.password"]).status.code());
+}